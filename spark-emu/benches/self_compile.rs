@@ -0,0 +1,66 @@
+//! Measures how long the shroom compiler takes to compile itself with
+//! different interpreter settings: decoding every instruction as it is
+//! executed, caching decoded instructions, running whole basic blocks, and
+//! running natively.
+//!
+//! Run with `cargo bench --bench self_compile -- <compiler.exe> [source]`,
+//! where source defaults to `../shroom.shr`.
+
+extern crate spark_emu;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+use spark_emu::{Exe, RunOutcome, Vm};
+
+/// Changes `Vm` settings before the compiler runs.
+type Configure = fn(&mut Vm);
+
+/// Runs compiler on source, returning its output and the time it took.
+fn compile(compiler: &Exe, source: &[u8], configure: Configure) -> (Vec<u8>, Duration) {
+    let mut input = source;
+    let mut output = Vec::new();
+    let start = Instant::now();
+    {
+        let mut vm = Vm::new(compiler.clone(), &mut input, &mut output, false).unwrap();
+        configure(&mut vm);
+        match vm.run() {
+            RunOutcome::Exited(0) => {}
+            other => panic!("compiler did not succeed: {:?}", other),
+        }
+    }
+    (output, start.elapsed())
+}
+
+fn main() -> io::Result<()> {
+    // cargo passes `--bench` to benchmarks without a harness
+    let args = env::args().skip(1).filter(|arg| arg != "--bench").collect::<Vec<_>>();
+    let compiler = match args.first() {
+        Some(path) => Exe::read_from_file(path).expect("failed to read compiler"),
+        None => {
+            eprintln!("usage: cargo bench --bench self_compile -- <compiler.exe> [source]");
+            return Ok(());
+        }
+    };
+    let source = fs::read(args.get(1).map(|path| path.as_str()).unwrap_or("../shroom.shr"))?;
+
+    let (plain_output, plain_time) = compile(&compiler, &source, |vm| {
+        vm.set_decode_cache(false);
+        vm.set_block_execution(false);
+    });
+    println!("{:<22}{:.3}s", "without decode cache:", plain_time.as_secs_f64());
+    let configs: [(&str, Configure); 3] = [
+        ("with decode cache", |vm| vm.set_block_execution(false)),
+        ("with basic blocks", |_| {}),
+        ("natively", |vm| vm.set_native_execution(true)),
+    ];
+    for &(name, configure) in &configs {
+        let (output, time) = compile(&compiler, &source, configure);
+        assert!(output == plain_output, "outputs differ {}", name);
+        let time = time.as_secs_f64();
+        let name = format!("{}:", name);
+        println!("{:<22}{:.3}s ({:.2}x)", name, time, plain_time.as_secs_f64() / time);
+    }
+    Ok(())
+}
@@ -0,0 +1,240 @@
+//! Disassembly annotated with instruction execution counts.
+//!
+//! Functions are reported from the most executed one, with a percentage of
+//! the function's executed instructions next to every instruction. Loops,
+//! found as backward jumps within a function, are marked with a bar to the
+//! left of the instructions in their body, one bar per nesting level.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use disasm::{DecodedInstr, Disassembly, LabelKind};
+use executable::CODE_START;
+use instruction::Instr;
+use symbols::Symbols;
+
+/// Executed instructions of a single function.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FunctionHits {
+    pub addr: u64,
+    /// Index range of the function's instructions in the disassembly.
+    pub start: usize,
+    pub end: usize,
+    pub hits: u64,
+}
+
+/// Splits disassembly into functions and sums their hit counts. Functions
+/// are sorted by hit count, largest first, and those that were never
+/// executed are left out.
+pub fn function_hits(disassembly: &Disassembly, hit_counts: &[u64]) -> Vec<FunctionHits> {
+    let mut functions = Vec::new();
+    for (index, decoded) in disassembly.instrs.iter().enumerate() {
+        let starts_function = disassembly
+            .labels
+            .get(&decoded.addr)
+            .is_some_and(|label| label.kind != LabelKind::Local);
+        if starts_function || functions.is_empty() {
+            functions.push(FunctionHits {
+                addr: decoded.addr,
+                start: index,
+                end: index,
+                hits: 0,
+            });
+        }
+        let function = functions.last_mut().unwrap();
+        function.end = index + 1;
+        function.hits += hits(hit_counts, decoded);
+    }
+    functions.retain(|function| function.hits > 0);
+    functions.sort_by(|a, b| b.hits.cmp(&a.hits).then(a.addr.cmp(&b.addr)));
+    functions
+}
+
+/// Writes annotated disassembly of at most `limit` most executed functions.
+pub fn write_report<W: Write>(
+    mut out: W,
+    disassembly: &Disassembly,
+    code: &[u8],
+    hit_counts: &[u64],
+    symbols: &Symbols,
+    limit: usize,
+) -> io::Result<()> {
+    let total = hit_counts.iter().sum::<u64>();
+    writeln!(out, "executed {} instructions", total)?;
+    for function in function_hits(disassembly, hit_counts).iter().take(limit) {
+        let instrs = &disassembly.instrs[function.start..function.end];
+        let name = match symbols.lookup(function.addr) {
+            Some((start, name)) if start == function.addr => name.to_string(),
+            _ => disassembly.label(function.addr).unwrap_or("?").to_string(),
+        };
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{}: {} instructions ({:.2}% of total)",
+            name,
+            function.hits,
+            percent(function.hits, total),
+        )?;
+
+        let loops = find_loops(instrs);
+        let max_depth = loops.iter().cloned().max().unwrap_or(0);
+        for (decoded, &depth) in instrs.iter().zip(&loops) {
+            let bars = "|".repeat(depth) + &" ".repeat(max_depth - depth);
+            if decoded.addr != function.addr {
+                if let Some(label) = disassembly.label(decoded.addr) {
+                    writeln!(out, "{:>25}{} {}:", "", bars, label)?;
+                }
+            }
+            let count = hits(hit_counts, decoded);
+            let percentage = if count == 0 {
+                String::new()
+            } else {
+                format!("{:.2}%", percent(count, function.hits))
+            };
+            write!(
+                out,
+                "{:>8} {:>14}  {}   {:#010x}:  {}",
+                percentage,
+                count,
+                bars,
+                decoded.addr,
+                disassembly.format_instr(decoded, code),
+            )?;
+            if loop_target(decoded, function.addr).is_some() {
+                write!(out, "   <- loop")?;
+            }
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+fn hits(hit_counts: &[u64], decoded: &DecodedInstr) -> u64 {
+    hit_counts.get((decoded.addr - CODE_START) as usize).cloned().unwrap_or(0)
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+/// Target of the instruction if it is a jump back to an earlier
+/// instruction of the same function.
+fn loop_target(decoded: &DecodedInstr, function_start: u64) -> Option<u64> {
+    let target = match decoded.instr {
+        Some(instr @ Instr::Jmp(_)) | Some(instr @ Instr::Jz(_)) | Some(instr @ Instr::Jnz(_)) => {
+            instr.branch_target(decoded.addr)?
+        }
+        _ => return None,
+    };
+    if target >= function_start && target <= decoded.addr {
+        Some(target)
+    } else {
+        None
+    }
+}
+
+/// Computes loop nesting depth of every instruction. A loop spans from the
+/// target of backward jumps to the last jump back to it, so that several
+/// jumps to the same place count as one loop.
+fn find_loops(instrs: &[DecodedInstr]) -> Vec<usize> {
+    let mut depths = vec![0; instrs.len()];
+    let start = match instrs.first() {
+        Some(decoded) => decoded.addr,
+        None => return depths,
+    };
+    let mut loops = BTreeMap::new();
+    for decoded in instrs {
+        if let Some(target) = loop_target(decoded, start) {
+            loops.insert(target, decoded.addr);
+        }
+    }
+    for (&loop_start, &loop_end) in &loops {
+        for (depth, decoded) in depths.iter_mut().zip(instrs) {
+            if decoded.addr >= loop_start && decoded.addr <= loop_end {
+                *depth += 1;
+            }
+        }
+    }
+    depths
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use asm::{assemble, assemble_with_symbols};
+    use disasm::Disassembly;
+    use vm::{RunOutcome, Vm};
+    use super::{find_loops, write_report};
+
+    #[test]
+    fn nested_loops_are_found_from_backward_jumps() {
+        let exe = assemble("
+            _start:
+                xor rax, rax
+            outer:
+                push rax
+            inner:
+                pop rax
+                test rax, rax
+                jnz inner
+                jz inner
+                jnz outer
+                jmp done
+            done:
+                xor rax, rax
+                syscall
+        ").unwrap();
+        let disassembly = Disassembly::new(&exe.code);
+        // both jumps to `inner` end the same loop, and the forward jump to
+        // `done` is not a loop
+        assert_eq!(find_loops(&disassembly.instrs), [0, 1, 2, 2, 2, 2, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn report_marks_loop_body_and_shows_counts() {
+        let (exe, symbols) = assemble_with_symbols("
+            _start:
+                mov rax, 2
+            loop:
+                push rax
+                mov rax, 1
+                push rax
+                pop rbx
+                pop rax
+                sub rax, rbx
+                test rax, rax
+                jnz loop
+                syscall
+        ").unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.set_hit_counting(true);
+        match vm.run() {
+            RunOutcome::Exited(1) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        let disassembly = Disassembly::new(vm.code());
+        let mut report = Vec::new();
+        write_report(&mut report, &disassembly, vm.code(), vm.hit_counts().unwrap(), &symbols, 10).unwrap();
+        let expected = "\
+executed 18 instructions
+
+_start: 18 instructions (100.00% of total)
+   5.56%              1      0x10000000:  mov rax, 2
+                         | .L0:
+  11.11%              2  |   0x1000000a:  push rax
+  11.11%              2  |   0x1000000b:  mov rax, 1
+  11.11%              2  |   0x10000015:  push rax
+  11.11%              2  |   0x10000016:  pop rbx
+  11.11%              2  |   0x10000017:  pop rax
+  11.11%              2  |   0x10000018:  sub rax, rbx
+  11.11%              2  |   0x1000001b:  test rax, rax
+  11.11%              2  |   0x1000001e:  jnz 0x1000000a <.L0>   <- loop
+   5.56%              1      0x10000024:  syscall
+";
+        assert_eq!(String::from_utf8(report).unwrap(), expected);
+    }
+}
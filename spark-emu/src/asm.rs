@@ -0,0 +1,678 @@
+//! Assembler for spark programs.
+//!
+//! Instructions are written the same way as `Instr` displays them, for
+//! example `mov rax, [rsp + 8]` or `push qword [rax + 16]`. Offsets can also
+//! be written with a minus sign, as in `lea rax, [rbp - 8]`. The exception
+//! are branches: `Instr` displays their operand as an offset relative to
+//! the end of the branch, but here it is a target address, so `jmp 5` jumps
+//! to address 5. Use a label or `disasm`, which shows target addresses.
+//!
+//! Program is made of two sections, selected with `.text` (the default) and
+//! `.data` directives. Execution starts at the first instruction of `.text`.
+//! A line can start with a label `name:`, which names the address of the
+//! following instruction or data. Labels can be used anywhere where a number
+//! is expected, optionally with added or subtracted constants. Branch
+//! targets (`call`, `jmp`, `jz`, `jnz`) are absolute addresses or labels, and
+//! are converted to relative offsets by the assembler.
+//!
+//! Data section accepts `.quad a, b, ...` to emit initialized quad words and
+//! `.zero n` to reserve `n` zeroed bytes, where `n` is a multiple of 8.
+//!
+//! Comments start with `;` and last until the end of line.
+
+use std::collections::HashMap;
+use std::fmt;
+use executable::{Exe, CODE_START, DATA_START};
+use instruction::Instr;
+use symbols::Symbols;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnexpectedChar(char),
+    InvalidNumber(String),
+    UnknownInstruction(String),
+    UnknownDirective(String),
+    BadOperands(String),
+    UndefinedSymbol(String),
+    DuplicateLabel(String),
+    ValueOutOfRange(u64),
+    BadZeroSize(u64),
+    InstructionInData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// One-based line number.
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match self.kind {
+            AsmErrorKind::UnexpectedChar(ch) => write!(f, "unexpected character {:?}", ch),
+            AsmErrorKind::InvalidNumber(ref text) => write!(f, "invalid number `{}`", text),
+            AsmErrorKind::UnknownInstruction(ref name) => write!(f, "unknown instruction `{}`", name),
+            AsmErrorKind::UnknownDirective(ref name) => write!(f, "unknown directive `{}`", name),
+            AsmErrorKind::BadOperands(ref name) => write!(f, "invalid operands for `{}`", name),
+            AsmErrorKind::UndefinedSymbol(ref name) => write!(f, "undefined symbol `{}`", name),
+            AsmErrorKind::DuplicateLabel(ref name) => write!(f, "label `{}` is defined multiple times", name),
+            AsmErrorKind::ValueOutOfRange(value) => write!(f, "value {:#x} does not fit in 32 bits", value),
+            AsmErrorKind::BadZeroSize(size) => write!(f, "`.zero` size must be a multiple of 8, but is {}", size),
+            AsmErrorKind::InstructionInData => write!(f, "instructions are not allowed in data section"),
+        }
+    }
+}
+
+type AsmResult<T> = Result<T, AsmErrorKind>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Ident(String),
+    Number(u64),
+    Comma,
+    Colon,
+    Plus,
+    Minus,
+    LeftBracket,
+    RightBracket,
+}
+
+fn is_ident_start(ch: char) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_' || ch == '.'
+}
+
+fn is_ident_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_' || ch == '.'
+}
+
+fn tokenize(line: &str) -> AsmResult<Vec<Token>> {
+    let line = match line.find(';') {
+        Some(pos) => &line[..pos],
+        None => line,
+    };
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        if ch.is_whitespace() {
+            chars.next();
+        } else if is_ident_start(ch) {
+            let mut name = String::new();
+            while let Some(&ch) = chars.peek() {
+                if !is_ident_char(ch) {
+                    break;
+                }
+                name.push(ch);
+                chars.next();
+            }
+            tokens.push(Token::Ident(name));
+        } else if ch.is_ascii_digit() {
+            let mut text = String::new();
+            while let Some(&ch) = chars.peek() {
+                if !ch.is_ascii_alphanumeric() && ch != '_' {
+                    break;
+                }
+                text.push(ch);
+                chars.next();
+            }
+            tokens.push(Token::Number(parse_number(&text)?));
+        } else {
+            chars.next();
+            tokens.push(match ch {
+                ',' => Token::Comma,
+                ':' => Token::Colon,
+                '+' => Token::Plus,
+                '-' => Token::Minus,
+                '[' => Token::LeftBracket,
+                ']' => Token::RightBracket,
+                _ => return Err(AsmErrorKind::UnexpectedChar(ch)),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> AsmResult<u64> {
+    let digits = text.replace('_', "");
+    let parsed = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse()
+    };
+    parsed.map_err(|_| AsmErrorKind::InvalidNumber(text.to_string()))
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(u64),
+    Symbol(String),
+}
+
+/// Sum of terms, each of them either added or subtracted.
+#[derive(Debug, Clone, Default)]
+struct Expr {
+    terms: Vec<(bool, Term)>,
+}
+
+impl Expr {
+    fn eval(&self, symbols: &HashMap<String, u64>) -> AsmResult<u64> {
+        let mut total = 0u64;
+        for &(negative, ref term) in &self.terms {
+            let value = match *term {
+                Term::Number(value) => value,
+                Term::Symbol(ref name) => match symbols.get(name) {
+                    Some(&value) => value,
+                    None => return Err(AsmErrorKind::UndefinedSymbol(name.clone())),
+                },
+            };
+            total = if negative {
+                total.wrapping_sub(value)
+            } else {
+                total.wrapping_add(value)
+            };
+        }
+        Ok(total)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Reg(String),
+    /// Registers and displacement inside brackets. `disp` is `None` if the
+    /// operand has no constant part, like in `[rsp]`.
+    Mem { regs: Vec<String>, disp: Option<Expr> },
+    Imm(Expr),
+}
+
+const REGISTERS: [&str; 6] = ["rax", "rbx", "rdx", "rsp", "rbp", "dl"];
+
+fn is_register(name: &str) -> bool {
+    REGISTERS.contains(&name)
+}
+
+fn parse_operand(tokens: &[Token]) -> Option<Operand> {
+    let tokens = match tokens.first() {
+        Some(Token::Ident(name)) if name == "qword" => &tokens[1..],
+        _ => tokens,
+    };
+    if tokens.len() == 1 {
+        if let Token::Ident(ref name) = tokens[0] {
+            if is_register(name) {
+                return Some(Operand::Reg(name.clone()));
+            }
+        }
+    }
+    if tokens.first() == Some(&Token::LeftBracket) {
+        if tokens.last() != Some(&Token::RightBracket) {
+            return None;
+        }
+        let mut regs = Vec::new();
+        let mut disp: Option<Expr> = None;
+        let mut negative = false;
+        let mut expect_term = true;
+        for token in &tokens[1..tokens.len() - 1] {
+            match (token, expect_term) {
+                (Token::Ident(name), true) if is_register(name) => {
+                    if negative {
+                        return None;
+                    }
+                    regs.push(name.clone());
+                }
+                (Token::Ident(name), true) => {
+                    let term = Term::Symbol(name.clone());
+                    disp.get_or_insert_with(Expr::default).terms.push((negative, term));
+                }
+                (&Token::Number(value), true) => {
+                    let term = Term::Number(value);
+                    disp.get_or_insert_with(Expr::default).terms.push((negative, term));
+                }
+                (&Token::Plus, false) => negative = false,
+                (&Token::Minus, false) => negative = true,
+                _ => return None,
+            }
+            expect_term = !expect_term;
+        }
+        if expect_term {
+            return None;
+        }
+        return Some(Operand::Mem { regs, disp });
+    }
+    parse_expr(tokens).map(Operand::Imm)
+}
+
+fn parse_expr(tokens: &[Token]) -> Option<Expr> {
+    let mut expr = Expr::default();
+    let mut negative = false;
+    let mut expect_term = true;
+    for (index, token) in tokens.iter().enumerate() {
+        match (token, expect_term) {
+            (&Token::Minus, true) if index == 0 => {
+                negative = true;
+                continue;
+            }
+            (Token::Ident(name), true) if !is_register(name) => {
+                expr.terms.push((negative, Term::Symbol(name.clone())));
+            }
+            (&Token::Number(value), true) => {
+                expr.terms.push((negative, Term::Number(value)));
+            }
+            (&Token::Plus, false) => negative = false,
+            (&Token::Minus, false) => negative = true,
+            _ => return None,
+        }
+        expect_term = !expect_term;
+    }
+    if expect_term {
+        None
+    } else {
+        Some(expr)
+    }
+}
+
+fn split_operands(tokens: &[Token]) -> Option<Vec<Operand>> {
+    if tokens.is_empty() {
+        return Some(Vec::new());
+    }
+    tokens.split(|token| *token == Token::Comma).map(parse_operand).collect()
+}
+
+/// How to build an instruction from the value of its operand.
+#[derive(Copy, Clone)]
+enum Form {
+    Fixed(Instr),
+    /// Offset or immediate that is encoded as is.
+    Value(fn(u64) -> Instr),
+    /// Branch with absolute target that has to be converted to an offset.
+    Branch(fn(u64) -> Instr),
+}
+
+fn is_reg(operand: &Operand, name: &str) -> bool {
+    match *operand {
+        Operand::Reg(ref reg) => reg == name,
+        _ => false,
+    }
+}
+
+fn is_mem(operand: &Operand, expected: &[&str]) -> bool {
+    match *operand {
+        Operand::Mem { ref regs, .. } => regs.iter().map(|reg| reg.as_str()).eq(expected.iter().cloned()),
+        _ => false,
+    }
+}
+
+fn mem_disp(operand: &Operand) -> Option<Expr> {
+    match *operand {
+        Operand::Mem { ref disp, .. } => disp.clone(),
+        _ => None,
+    }
+}
+
+fn select_form(mnemonic: &str, ops: &[Operand]) -> AsmResult<(Form, Option<Expr>)> {
+    let bad = || AsmErrorKind::BadOperands(mnemonic.to_string());
+    let fixed = |instr| Ok((Form::Fixed(instr), None));
+    let reg_op = |index: usize| match ops.get(index) {
+        Some(Operand::Reg(name)) => Some(name.as_str()),
+        _ => None,
+    };
+    let mem_with_disp = |operand: &Operand, make: fn(u64) -> Instr| {
+        Ok((Form::Value(make), Some(mem_disp(operand).unwrap_or_default())))
+    };
+    match (mnemonic, ops.len()) {
+        ("pop", 1) => match reg_op(0) {
+            Some("rax") => fixed(Instr::PopRax),
+            Some("rbx") => fixed(Instr::PopRbx),
+            Some("rbp") => fixed(Instr::PopRbp),
+            Some("rdx") => fixed(Instr::PopRdx),
+            _ => Err(bad()),
+        },
+        ("push", 1) => match reg_op(0) {
+            Some("rax") => fixed(Instr::PushRax),
+            Some("rbx") => fixed(Instr::PushRbx),
+            Some("rbp") => fixed(Instr::PushRbp),
+            Some("rdx") => fixed(Instr::PushRdx),
+            _ if is_mem(&ops[0], &["rax"]) => match mem_disp(&ops[0]) {
+                None => fixed(Instr::PushQwordRax),
+                disp => Ok((Form::Value(Instr::PushQwordRaxOffset), disp)),
+            },
+            _ => Err(bad()),
+        },
+        ("add", 2) if is_reg(&ops[0], "rax") && is_reg(&ops[1], "rbx") => fixed(Instr::AddRaxRbx),
+        ("add", 2) if is_reg(&ops[0], "rsp") => match ops[1] {
+            Operand::Imm(ref expr) => Ok((Form::Value(Instr::AddRsp), Some(expr.clone()))),
+            _ => Err(bad()),
+        },
+        ("sub", 2) if is_reg(&ops[0], "rax") && is_reg(&ops[1], "rbx") => fixed(Instr::SubRaxRbx),
+        ("sub", 2) if is_reg(&ops[0], "rsp") => match ops[1] {
+            Operand::Imm(ref expr) => Ok((Form::Value(Instr::SubRsp), Some(expr.clone()))),
+            _ => Err(bad()),
+        },
+        ("mul", 1) if is_reg(&ops[0], "rbx") => fixed(Instr::MulRbx),
+        ("div", 1) if is_reg(&ops[0], "rbx") => fixed(Instr::DivRbx),
+        ("cmp", 2) if is_reg(&ops[0], "rax") && is_reg(&ops[1], "rbx") => fixed(Instr::CmpRaxRbx),
+        ("test", 2) if is_reg(&ops[0], "rax") && is_reg(&ops[1], "rax") => fixed(Instr::TestRaxRax),
+        ("xor", 2) if is_reg(&ops[0], "rax") && is_reg(&ops[1], "rax") => fixed(Instr::XorRaxRax),
+        ("xor", 2) if is_reg(&ops[0], "rdx") && is_reg(&ops[1], "rdx") => fixed(Instr::XorRdxRdx),
+        ("sete", 1) if is_reg(&ops[0], "dl") => fixed(Instr::SeteDl),
+        ("setne", 1) if is_reg(&ops[0], "dl") => fixed(Instr::SetneDl),
+        ("setb", 1) if is_reg(&ops[0], "dl") => fixed(Instr::SetbDl),
+        ("mov", 2) => {
+            let (dst, src) = (&ops[0], &ops[1]);
+            if is_reg(dst, "rax") {
+                match *src {
+                    Operand::Imm(ref expr) => Ok((Form::Value(Instr::MovRax), Some(expr.clone()))),
+                    _ if is_mem(src, &["rsp"]) => match mem_disp(src) {
+                        None => fixed(Instr::MovRaxQwordRsp),
+                        disp => Ok((Form::Value(Instr::MovRaxRspOffset), disp)),
+                    },
+                    _ => Err(bad()),
+                }
+            } else if is_reg(dst, "rbx") && is_mem(src, &["rsp", "rax"]) {
+                mem_with_disp(src, Instr::MovRbxRspRaxOffset)
+            } else if is_reg(dst, "rbp") && is_reg(src, "rsp") {
+                fixed(Instr::MovRbpRsp)
+            } else if is_mem(dst, &["rax"]) && is_reg(src, "rbx") {
+                mem_with_disp(dst, Instr::MovRaxOffsetRbx)
+            } else if is_mem(dst, &["rsp"]) && is_reg(src, "rbx") {
+                mem_with_disp(dst, Instr::MovRspOffsetRbx)
+            } else {
+                Err(bad())
+            }
+        }
+        ("lea", 2) if is_reg(&ops[0], "rax") && is_mem(&ops[1], &["rbp"]) => {
+            mem_with_disp(&ops[1], Instr::LeaRaxRbpOffset)
+        }
+        ("call", 1) | ("jmp", 1) | ("jz", 1) | ("jnz", 1) => {
+            let make: fn(u64) -> Instr = match mnemonic {
+                "call" => Instr::Call,
+                "jmp" => Instr::Jmp,
+                "jz" => Instr::Jz,
+                _ => Instr::Jnz,
+            };
+            match ops[0] {
+                Operand::Imm(ref expr) => Ok((Form::Branch(make), Some(expr.clone()))),
+                _ => Err(bad()),
+            }
+        }
+        ("ret", 0) => fixed(Instr::Ret),
+        ("syscall", 0) => fixed(Instr::Syscall),
+        ("pop", _) | ("push", _) | ("add", _) | ("sub", _) | ("mul", _) | ("div", _) | ("cmp", _) |
+        ("test", _) | ("xor", _) | ("sete", _) | ("setne", _) | ("setb", _) | ("mov", _) |
+        ("lea", _) | ("call", _) | ("jmp", _) | ("jz", _) | ("jnz", _) | ("ret", _) |
+        ("syscall", _) => Err(bad()),
+        _ => Err(AsmErrorKind::UnknownInstruction(mnemonic.to_string())),
+    }
+}
+
+enum Item {
+    Instr { form: Form, arg: Option<Expr>, addr: u64 },
+    Quad(Vec<Expr>),
+    Zero(u64),
+}
+
+fn fits_in_four_bytes(value: u64) -> bool {
+    let value = value as i64;
+    value >= i64::from(i32::MIN) && value <= i64::from(i32::MAX)
+}
+
+/// Assembles the source into an executable.
+pub fn assemble(source: &str) -> Result<Exe, AsmError> {
+    assemble_with_symbols(source).map(|(exe, _)| exe)
+}
+
+/// Assembles the source, also returning addresses of all labels.
+pub fn assemble_with_symbols(source: &str) -> Result<(Exe, Symbols), AsmError> {
+    let mut symbols = HashMap::new();
+    let mut items = Vec::new();
+    let mut code_size = 0u64;
+    let mut data_size = 0u64;
+    let mut in_data = false;
+
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |kind| AsmError { line: line_number, kind };
+        let mut tokens = &tokenize(line).map_err(error)?[..];
+        if let (Some(Token::Ident(name)), Some(&Token::Colon)) = (tokens.first(), tokens.get(1)) {
+            let addr = if in_data {
+                DATA_START + data_size
+            } else {
+                CODE_START + code_size
+            };
+            if symbols.insert(name.clone(), addr).is_some() {
+                return Err(error(AsmErrorKind::DuplicateLabel(name.clone())));
+            }
+            tokens = &tokens[2..];
+        }
+        let (name, rest) = match tokens.split_first() {
+            Some((Token::Ident(name), rest)) => (name.as_str(), rest),
+            Some((token, _)) => return Err(error(AsmErrorKind::BadOperands(format!("{:?}", token)))),
+            None => continue,
+        };
+        match name {
+            ".text" | ".data" if rest.is_empty() => in_data = name == ".data",
+            ".quad" => {
+                let values = rest
+                    .split(|token| *token == Token::Comma)
+                    .map(parse_expr)
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or_else(|| error(AsmErrorKind::BadOperands(name.to_string())))?;
+                let size = values.len() as u64 * 8;
+                if in_data {
+                    data_size += size;
+                } else {
+                    code_size += size;
+                }
+                items.push((line_number, in_data, Item::Quad(values)));
+            }
+            ".zero" => {
+                let size = match *rest {
+                    [Token::Number(size)] => size,
+                    _ => return Err(error(AsmErrorKind::BadOperands(name.to_string()))),
+                };
+                if size % 8 != 0 {
+                    return Err(error(AsmErrorKind::BadZeroSize(size)));
+                }
+                if in_data {
+                    data_size += size;
+                } else {
+                    code_size += size;
+                }
+                items.push((line_number, in_data, Item::Zero(size)));
+            }
+            _ if name.starts_with('.') => {
+                return Err(error(AsmErrorKind::UnknownDirective(name.to_string())));
+            }
+            _ => {
+                if in_data {
+                    return Err(error(AsmErrorKind::InstructionInData));
+                }
+                let operands = split_operands(rest)
+                    .ok_or_else(|| error(AsmErrorKind::BadOperands(name.to_string())))?;
+                let (form, arg) = select_form(name, &operands).map_err(error)?;
+                let len = match form {
+                    Form::Fixed(instr) => instr.len(),
+                    Form::Value(make) | Form::Branch(make) => make(0).len(),
+                };
+                let addr = CODE_START + code_size;
+                code_size += len;
+                items.push((line_number, false, Item::Instr { form, arg, addr }));
+            }
+        }
+    }
+
+    let mut code = Vec::new();
+    let mut data = Vec::new();
+    for (line_number, in_data, item) in items {
+        let error = |kind| AsmError { line: line_number, kind };
+        let out = if in_data { &mut data } else { &mut code };
+        match item {
+            Item::Instr { form, arg, addr } => {
+                let value = match arg {
+                    Some(ref expr) => expr.eval(&symbols).map_err(error)?,
+                    None => 0,
+                };
+                // value of the four byte field, if instruction has one
+                let (instr, field) = match form {
+                    Form::Fixed(instr) => (instr, None),
+                    Form::Value(make) => match make(value) {
+                        instr @ Instr::MovRax(_) => (instr, None),
+                        instr => (instr, Some(value)),
+                    },
+                    Form::Branch(make) => {
+                        let offset = value.wrapping_sub(addr + make(0).len());
+                        (make(offset), Some(offset))
+                    }
+                };
+                if let Some(field) = field {
+                    if !fits_in_four_bytes(field) {
+                        return Err(error(AsmErrorKind::ValueOutOfRange(field)));
+                    }
+                }
+                instr.encode(out);
+            }
+            Item::Quad(values) => {
+                for expr in values {
+                    let mut value = expr.eval(&symbols).map_err(error)?;
+                    for _ in 0..8 {
+                        out.push(value as u8);
+                        value >>= 8;
+                    }
+                }
+            }
+            Item::Zero(size) => {
+                out.extend((0..size).map(|_| 0));
+            }
+        }
+    }
+    // sort so that choice between labels with the same address is stable
+    let mut labels = symbols.iter().collect::<Vec<_>>();
+    labels.sort();
+    let mut table = Symbols::new();
+    for (name, &addr) in labels {
+        table.insert(addr, name);
+    }
+    Ok((Exe { code, data }, table))
+}
+
+#[cfg(test)]
+mod tests {
+    use executable::{CODE_START, DATA_START};
+    use instruction::{encode_all, Instr};
+    use super::{assemble, assemble_with_symbols, AsmError, AsmErrorKind};
+
+    fn error(source: &str) -> AsmError {
+        match assemble(source) {
+            Ok(_) => panic!("{:?} was assembled", source),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn displayed_instructions_are_assembled_back() {
+        let instrs = [
+            Instr::PopRax, Instr::PopRbx, Instr::PopRbp, Instr::PopRdx,
+            Instr::PushRax, Instr::PushRbp, Instr::PushRbx, Instr::PushRdx,
+            Instr::AddRaxRbx, Instr::SubRaxRbx, Instr::MulRbx, Instr::DivRbx,
+            Instr::PushQwordRax, Instr::PushQwordRaxOffset(16),
+            Instr::MovRaxRspOffset(8), Instr::MovRaxOffsetRbx(24),
+            Instr::AddRsp(32), Instr::SubRsp(40), Instr::CmpRaxRbx,
+            Instr::SeteDl, Instr::SetneDl, Instr::SetbDl,
+            Instr::XorRaxRax, Instr::XorRdxRdx, Instr::MovRax(0x1234_5678_9abc),
+            Instr::TestRaxRax, Instr::Ret, Instr::LeaRaxRbpOffset(-8i64 as u64),
+            Instr::MovRbxRspRaxOffset(8), Instr::MovRspOffsetRbx(0),
+            Instr::MovRaxQwordRsp, Instr::MovRbpRsp, Instr::Syscall,
+        ];
+        let source = instrs.iter().map(|instr| instr.to_string()).collect::<Vec<_>>().join("\n");
+        let exe = assemble(&source).unwrap();
+        assert_eq!(exe.code, encode_all(&instrs));
+        assert!(exe.data.is_empty());
+    }
+
+    #[test]
+    fn branches_are_relative_to_next_instruction() {
+        let exe = assemble("
+            jmp forward  ; 5 bytes
+        back:
+            ret
+        forward:
+            jz back      ; 6 bytes
+            jnz forward
+            call back
+            jmp 0x10000000
+        ").unwrap();
+        let expected = encode_all(&[
+            Instr::Jmp(1),
+            Instr::Ret,
+            Instr::Jz(-7i64 as u64),
+            Instr::Jnz(-12i64 as u64),
+            Instr::Call(-18i64 as u64),
+            Instr::Jmp(-28i64 as u64),
+        ]);
+        assert_eq!(exe.code, expected);
+    }
+
+    #[test]
+    fn labels_name_code_and_data_addresses() {
+        let (exe, symbols) = assemble_with_symbols("
+            mov rax, table + 8
+            push qword [rax]
+        end:
+            ret
+        .data
+            .quad 1
+        table:
+            .quad 2, end, table - 8
+            .zero 16
+        last:
+            .quad last
+        .text
+            ret
+        ").unwrap();
+        let table = DATA_START + 8;
+        let end = CODE_START + 12;
+        let last = table + 24 + 16;
+        assert_eq!(symbols.lookup(end), Some((end, "end")));
+        assert_eq!(symbols.lookup(table), Some((table, "table")));
+        assert_eq!(symbols.lookup(last), Some((last, "last")));
+        assert_eq!(exe.code, encode_all(&[Instr::MovRax(table + 8), Instr::PushQwordRax, Instr::Ret, Instr::Ret]));
+        let quads = [1, 2, end, table - 8, 0, 0, last];
+        let data = quads.iter().flat_map(|quad: &u64| quad.to_le_bytes().to_vec()).collect::<Vec<_>>();
+        assert_eq!(exe.data, data);
+    }
+
+    #[test]
+    fn numbers_can_be_hex_and_have_underscores() {
+        let exe = assemble("mov rax, 0xFF_FF\nsub rsp, 1_000\n.quad 0X10").unwrap();
+        let mut expected = encode_all(&[Instr::MovRax(0xffff), Instr::SubRsp(1000)]);
+        expected.extend_from_slice(&16u64.to_le_bytes());
+        assert_eq!(exe.code, expected);
+    }
+
+    #[test]
+    fn errors_are_reported_with_line_numbers() {
+        let cases = [
+            ("ret\nmov rax, 5 $", 2, AsmErrorKind::UnexpectedChar('$')),
+            ("mov rax, 12ab", 1, AsmErrorKind::InvalidNumber("12ab".to_string())),
+            ("mov rax, 0x1_0000_0000_0000_0000", 1, AsmErrorKind::InvalidNumber("0x1_0000_0000_0000_0000".to_string())),
+            ("\n\nnop", 3, AsmErrorKind::UnknownInstruction("nop".to_string())),
+            (".bss", 1, AsmErrorKind::UnknownDirective(".bss".to_string())),
+            ("mov rbx, rax", 1, AsmErrorKind::BadOperands("mov".to_string())),
+            ("push rax, rbx", 1, AsmErrorKind::BadOperands("push".to_string())),
+            (".quad 1,", 1, AsmErrorKind::BadOperands(".quad".to_string())),
+            ("ret\ncall missing", 2, AsmErrorKind::UndefinedSymbol("missing".to_string())),
+            ("a: ret\n.data\na: .quad 0", 3, AsmErrorKind::DuplicateLabel("a".to_string())),
+            ("add rsp, 0x80000000", 1, AsmErrorKind::ValueOutOfRange(0x8000_0000)),
+            ("jmp 0x90000005", 1, AsmErrorKind::ValueOutOfRange(0x8000_0000)),
+            (".data\n.zero 12", 2, AsmErrorKind::BadZeroSize(12)),
+            (".data\nret", 2, AsmErrorKind::InstructionInData),
+        ];
+        for &(source, line, ref kind) in &cases {
+            assert_eq!(error(source), AsmError { line, kind: kind.clone() }, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn invalid_number_message_shows_whole_literal() {
+        assert_eq!(error("mov rax, 0x12g4").to_string(), "line 1: invalid number `0x12g4`");
+    }
+}
@@ -0,0 +1,275 @@
+//! Call stack reconstruction.
+//!
+//! Shroom functions start with `push rbp; mov rbp, rsp` and return with
+//! `pop rbp; ret`, so inside a function body `[rbp]` holds the caller's
+//! `rbp` and `[rbp + 8]` holds the return address. Following this chain
+//! gives the call stack, as long as the program did not corrupt it.
+//!
+//! Functions without such prologue (syscall wrappers at the start of code
+//! section) do not save `rbp`, so while executing them their caller is
+//! missing from the stack.
+
+use std::fmt;
+use history::History;
+use instruction::Instr;
+use symbols::Symbols;
+use vm::{ExecError, Registers, Vm};
+
+/// Upper limit on the number of frames, in case saved `rbp` values form a
+/// cycle.
+const MAX_FRAMES: usize = 1000;
+
+/// Length of the `call` instruction preceding a return address.
+const CALL_LEN: u64 = 5;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the current instruction for the innermost frame, and of
+    /// the `call` instruction for the others.
+    pub addr: u64,
+    /// Value of `rsp` when this frame was executing `addr`.
+    pub stack_pointer: u64,
+}
+
+/// Walks the saved `rbp` chain, starting with the innermost frame.
+pub fn call_stack(vm: &Vm) -> Vec<Frame> {
+    let regs = vm.registers();
+    let mut frames = vec![Frame { addr: regs.rip, stack_pointer: regs.rsp }];
+
+    // at the start and at the end of a function `rbp` still belongs to the
+    // caller, and the return address is found relative to `rsp` instead
+    let (mut return_slot, mut rbp) = match vm.current_instr() {
+        Ok(Instr::PushRbp) | Ok(Instr::Ret) => (regs.rsp, Some(regs.rbp)),
+        Ok(Instr::MovRbpRsp) => (regs.rsp.wrapping_add(8), Some(regs.rbp)),
+        _ => (regs.rbp.wrapping_add(8), vm.read_data(regs.rbp).ok()),
+    };
+
+    while frames.len() < MAX_FRAMES {
+        let return_addr = match vm.read_data(return_slot) {
+            Ok(addr) => addr,
+            Err(_) => break,
+        };
+        let call_addr = return_addr.wrapping_sub(CALL_LEN);
+        match vm.instr_at(call_addr) {
+            Ok(Instr::Call(_)) => {}
+            _ => break,
+        }
+        frames.push(Frame {
+            addr: call_addr,
+            stack_pointer: return_slot.wrapping_add(8),
+        });
+        let frame_pointer = match rbp {
+            Some(rbp) if rbp != 0 => rbp,
+            _ => break,
+        };
+        return_slot = frame_pointer.wrapping_add(8);
+        rbp = vm.read_data(frame_pointer).ok();
+    }
+    frames
+}
+
+/// State of the machine at the point where execution stopped.
+#[derive(Debug)]
+pub struct Backtrace {
+    /// Registers with `rip` pointing at the instruction that was about to
+    /// be executed.
+    pub registers: Registers,
+    pub call_stack: Vec<Frame>,
+    /// Last executed instructions, if `Vm` was recording them.
+    pub history: Option<History>,
+}
+
+impl Backtrace {
+    /// Captures the current state of `vm`.
+    pub fn new(vm: &Vm) -> Backtrace {
+        Backtrace {
+            registers: vm.registers(),
+            call_stack: call_stack(vm),
+            history: vm.history().cloned(),
+        }
+    }
+
+    /// Detailed report with registers and symbolized call stack.
+    pub fn report<'a>(&'a self, symbols: &'a Symbols) -> BacktraceReport<'a> {
+        BacktraceReport { backtrace: self, symbols }
+    }
+}
+
+pub struct BacktraceReport<'a> {
+    backtrace: &'a Backtrace,
+    symbols: &'a Symbols,
+}
+
+impl<'a> fmt::Display for BacktraceReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let regs = &self.backtrace.registers;
+        writeln!(f, "  at {}", self.symbols.format_address(regs.rip))?;
+        writeln!(f, "registers:")?;
+        writeln!(f, "  rax = {:#018x}  rbx = {:#018x}  rdx = {:#018x}", regs.rax, regs.rbx, regs.rdx)?;
+        writeln!(f, "  rsp = {:#018x}  rbp = {:#018x}", regs.rsp, regs.rbp)?;
+        writeln!(f, "  below_flag = {}, zero_flag = {}", regs.below_flag as u8, regs.zero_flag as u8)?;
+        write!(f, "call stack:")?;
+        for (index, frame) in self.backtrace.call_stack.iter().enumerate() {
+            write!(f, "\n  #{} {}", index, self.symbols.format_address(frame.addr))?;
+        }
+        if let Some(ref history) = self.backtrace.history {
+            write!(f, "\nlast {} executed instructions:", history.len())?;
+            for (entry, changes) in history.entries_with_changes() {
+                let location = self.symbols.format_address(entry.rip);
+                let mut instr = entry.instr.to_string();
+                if let Some(target) = entry.instr.branch_target(entry.rip) {
+                    let mnemonic = instr.split(' ').next().unwrap_or("").to_string();
+                    instr = format!("{} {}", mnemonic, self.symbols.format_address(target));
+                }
+                write!(f, "\n  {:<30} {:<32} {}", location, instr, changes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Execution error together with the state of the machine when it happened.
+#[derive(Debug)]
+pub struct Fault {
+    pub error: ExecError,
+    /// State with `rip` pointing at the faulting instruction.
+    pub backtrace: Backtrace,
+}
+
+impl Fault {
+    /// Captures the current state of `vm`.
+    pub fn new(vm: &Vm, error: ExecError) -> Fault {
+        Fault {
+            error,
+            backtrace: Backtrace::new(vm),
+        }
+    }
+
+    /// Detailed report with registers and symbolized call stack.
+    pub fn report<'a>(&'a self, symbols: &'a Symbols) -> FaultReport<'a> {
+        FaultReport { fault: self, symbols }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (rip = {:#x})", self.error, self.backtrace.registers.rip)
+    }
+}
+
+pub struct FaultReport<'a> {
+    fault: &'a Fault,
+    symbols: &'a Symbols,
+}
+
+impl<'a> fmt::Display for FaultReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.fault.error)?;
+        write!(f, "{}", self.fault.backtrace.report(self.symbols))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use asm::assemble_with_symbols;
+    use executable::CODE_START;
+    use vm::{RunOutcome, Vm};
+    use super::{call_stack, Frame};
+
+    /// `inner` faults after being called from `main`.
+    const NESTED_CALLS: &str = "
+    _start:
+        call main
+        xor rax, rax
+        syscall
+    main:
+        push rbp
+        mov rbp, rsp
+        call inner
+        pop rbp
+        ret
+    inner:
+        push rbp
+        mov rbp, rsp
+        mov rax, 0x30000000
+        push qword [rax]
+        pop rbp
+        ret
+    ";
+
+    const MAIN_CALL: u64 = CODE_START + 14;
+    const INNER: u64 = CODE_START + 21;
+
+    fn addresses(frames: &[Frame]) -> Vec<u64> {
+        frames.iter().map(|frame| frame.addr).collect()
+    }
+
+    #[test]
+    fn call_stack_is_found_in_prologue_and_body() {
+        let (exe, _) = assemble_with_symbols(NESTED_CALLS).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        let mut stacks = Vec::new();
+        for &addr in &[INNER, INNER + 1, INNER + 4] {
+            vm.add_breakpoint(addr);
+            match vm.run() {
+                RunOutcome::Paused(paused) => assert_eq!(paused, addr),
+                other => panic!("unexpected outcome: {:?}", other),
+            }
+            vm.remove_breakpoint(addr);
+            stacks.push(addresses(&call_stack(&vm)));
+        }
+        assert_eq!(stacks, [
+            [INNER, MAIN_CALL, CODE_START],
+            [INNER + 1, MAIN_CALL, CODE_START],
+            [INNER + 4, MAIN_CALL, CODE_START],
+        ]);
+        let frames = call_stack(&vm);
+        // stack pointer of the caller is right above the return address
+        assert_eq!(frames[1].stack_pointer, frames[0].stack_pointer + 16);
+    }
+
+    #[test]
+    fn call_stack_is_found_in_epilogue() {
+        // same layout, but `inner` returns normally
+        let source = NESTED_CALLS.replace("push qword [rax]", "push rax\n pop rax");
+        let (exe, _) = assemble_with_symbols(&source).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        for &addr in &[INNER + 16, INNER + 17] {
+            vm.add_breakpoint(addr);
+            match vm.run() {
+                RunOutcome::Paused(paused) => assert_eq!(paused, addr),
+                other => panic!("unexpected outcome: {:?}", other),
+            }
+            vm.remove_breakpoint(addr);
+            assert_eq!(addresses(&call_stack(&vm)), [addr, MAIN_CALL, CODE_START]);
+        }
+    }
+
+    #[test]
+    fn fault_report_shows_symbolized_call_stack() {
+        let (exe, symbols) = assemble_with_symbols(NESTED_CALLS).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        let fault = match vm.run() {
+            RunOutcome::Faulted(fault) => fault,
+            other => panic!("unexpected outcome: {:?}", other),
+        };
+        assert_eq!(fault.to_string(), "out of range data access at 0x30000000 (rip = 0x10000023)");
+        let report = fault.report(&symbols).to_string();
+        let expected = "\
+out of range data access at 0x30000000
+  at 0x10000023 <inner+14>
+registers:
+  rax = 0x0000000030000000  rbx = 0x0000000000000000  rdx = 0x0000000000000000
+  rsp = 0x000000001fffffe0  rbp = 0x000000001fffffe0
+  below_flag = 0, zero_flag = 0
+call stack:
+  #0 0x10000023 <inner+14>
+  #1 0x1000000e <main+4>
+  #2 0x10000000 <_start>";
+        assert_eq!(report, expected);
+    }
+}
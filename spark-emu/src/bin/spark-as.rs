@@ -0,0 +1,39 @@
+extern crate spark_emu;
+extern crate structopt;
+
+use std::fs;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "spark-as")]
+struct Opt {
+    /// Path to assembly source
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    /// Where to write assembled executable
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: PathBuf,
+    /// Also write addresses of labels to this file, to be used with
+    /// `spark-emu --symbols`
+    #[structopt(short = "s", long = "symbols", parse(from_os_str))]
+    symbols: Option<PathBuf>,
+}
+
+fn run() -> Result<(), String> {
+    let opt = Opt::from_args();
+    let source = fs::read_to_string(&opt.input).map_err(|e| e.to_string())?;
+    let (exe, symbols) = spark_emu::asm::assemble_with_symbols(&source)
+        .map_err(|e| format!("{}: {}", opt.input.display(), e))?;
+    if let Some(ref path) = opt.symbols {
+        symbols.write_to_file(path).map_err(|e| e.to_string())?;
+    }
+    exe.write_to_file(&opt.output).map_err(|e| e.to_string())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
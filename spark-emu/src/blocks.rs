@@ -0,0 +1,151 @@
+//! Basic blocks for the block-at-a-time interpreter.
+//!
+//! A block is a run of instructions that do not touch `rip`, ended by a
+//! `call`, `jmp`, `jz`, `jnz`, `ret` or `syscall`. Blocks are translated
+//! lazily when execution first reaches their start address, with branch
+//! targets resolved to absolute addresses. Once a branch is followed, the
+//! index of the target block is remembered in the block, so that following
+//! it again does not need to look the target up.
+//!
+//! A jump into the middle of an existing block starts a new block, so the
+//! same instruction can belong to several blocks.
+
+use executable::CODE_START;
+use instruction::Instr;
+use vm::ExecResult;
+
+/// Marks successors that were not resolved yet.
+const UNRESOLVED: u32 = u32::MAX;
+
+/// How a block ends.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Exit {
+    Call { target: u64, return_addr: u64 },
+    Jmp { target: u64 },
+    Jz { target: u64, next: u64 },
+    Jnz { target: u64, next: u64 },
+    Ret,
+    Syscall { next: u64 },
+    /// Block ends before an instruction that cannot be decoded, and
+    /// execution continues there without executing anything.
+    Fallthrough { next: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Instructions before the exit, with their addresses.
+    pub ops: Vec<(u64, Instr)>,
+    pub exit: Exit,
+    /// The instruction that ends the block and its address. Unused for
+    /// `Exit::Fallthrough`.
+    pub exit_instr: Instr,
+    pub exit_addr: u64,
+    /// Indices of blocks at the branch target (0) and the following
+    /// instruction (1), once they are known.
+    successors: [u32; 2],
+}
+
+impl Block {
+    /// Translates the block starting at `start`. Fails only if the first
+    /// instruction cannot be decoded.
+    fn translate<F>(start: u64, decode: F) -> ExecResult<Block>
+    where
+        F: Fn(u64) -> ExecResult<Instr>,
+    {
+        let mut ops = Vec::new();
+        let mut addr = start;
+        let mut exit_instr = Instr::Ret;
+        let exit = loop {
+            let instr = match decode(addr) {
+                Ok(instr) => instr,
+                Err(e) if ops.is_empty() => return Err(e),
+                Err(_) => break Exit::Fallthrough { next: addr },
+            };
+            let next = addr.wrapping_add(instr.len());
+            exit_instr = instr;
+            let target = instr.branch_target(addr).unwrap_or(0);
+            match instr {
+                Instr::Call(_) => break Exit::Call { target, return_addr: next },
+                Instr::Jmp(_) => break Exit::Jmp { target },
+                Instr::Jz(_) => break Exit::Jz { target, next },
+                Instr::Jnz(_) => break Exit::Jnz { target, next },
+                Instr::Ret => break Exit::Ret,
+                Instr::Syscall => break Exit::Syscall { next },
+                _ => {
+                    ops.push((addr, instr));
+                    addr = next;
+                }
+            }
+        };
+        Ok(Block {
+            ops,
+            exit,
+            exit_instr,
+            exit_addr: addr,
+            successors: [UNRESOLVED; 2],
+        })
+    }
+
+    /// Number of instructions executed by running the whole block.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        match self.exit {
+            Exit::Fallthrough { .. } => self.ops.len() as u64,
+            _ => self.ops.len() as u64 + 1,
+        }
+    }
+
+    /// Index of the successor block in `slot`, 0 for the branch target and
+    /// 1 for the following instruction.
+    pub fn successor(&self, slot: usize) -> Option<usize> {
+        match self.successors[slot] {
+            UNRESOLVED => None,
+            index => Some(index as usize),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockCache {
+    blocks: Vec<Block>,
+    /// Index of the block starting at each offset in the code section.
+    by_offset: Vec<u32>,
+}
+
+impl BlockCache {
+    pub fn new(code_size: usize) -> BlockCache {
+        BlockCache {
+            blocks: Vec::new(),
+            by_offset: vec![UNRESOLVED; code_size],
+        }
+    }
+
+    pub fn block(&self, index: usize) -> &Block {
+        &self.blocks[index]
+    }
+
+    /// Finds or translates the block starting at `addr`.
+    pub fn find<F>(&mut self, addr: u64, decode: F) -> ExecResult<usize>
+    where
+        F: Fn(u64) -> ExecResult<Instr>,
+    {
+        let offset = addr.wrapping_sub(CODE_START) as usize;
+        if let Some(&index) = self.by_offset.get(offset) {
+            if index != UNRESOLVED {
+                return Ok(index as usize);
+            }
+        }
+        // addresses outside the code section fail to decode, so after this
+        // `offset` is known to be in bounds
+        let block = Block::translate(addr, decode)?;
+        let index = self.blocks.len();
+        self.blocks.push(block);
+        self.by_offset[offset] = index as u32;
+        Ok(index)
+    }
+
+    /// Remembers that `slot` of block `from` leads to block `to`.
+    pub fn link(&mut self, from: usize, slot: usize, to: usize) {
+        self.blocks[from].successors[slot] = to as u32;
+    }
+}
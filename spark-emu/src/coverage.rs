@@ -0,0 +1,468 @@
+//! Code coverage of spark executables.
+//!
+//! Coverage is written in lcov tracefile format. Executables have no source
+//! files, so the "source file" of a record is the executable itself, and
+//! line numbers refer to lines of its disassembly as printed by
+//! `spark-emu disasm`. Every conditional jump is a branch with two sides:
+//! taken (branch 0) and not taken (branch 1).
+//!
+//! Coverage of repeated runs is merged into the existing file, so a single
+//! file can collect coverage of a whole test suite. The file should be
+//! removed when the executable changes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use disasm::{Disassembly, LabelKind};
+use executable::CODE_START;
+use instruction::Instr;
+use symbols::Symbols;
+
+#[derive(Debug)]
+pub enum CoverageError {
+    /// Line of the existing coverage file could not be parsed, with
+    /// one-based line number.
+    BadLine(usize),
+    /// Existing coverage file refers to a disassembly line that is not an
+    /// instruction, so it was probably made for a different executable.
+    Mismatch(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CoverageError::BadLine(line) => write!(f, "invalid coverage data on line {}", line),
+            CoverageError::Mismatch(line) => write!(
+                f,
+                "coverage data on line {} does not match the executable",
+                line,
+            ),
+            CoverageError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for CoverageError {
+    fn from(err: io::Error) -> CoverageError {
+        CoverageError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// Execution counts of instructions by address.
+    pub hits: BTreeMap<u64, u64>,
+    /// How many times each conditional jump was taken and not taken.
+    pub branches: BTreeMap<u64, (u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Collects coverage of instructions found by the disassembler from
+    /// counts indexed by offset in the code, as returned by
+    /// `Vm::hit_counts` and `Vm::taken_counts`.
+    pub fn from_counts(disassembly: &Disassembly, hit_counts: &[u64], taken_counts: &[u64]) -> Coverage {
+        let mut coverage = Coverage::new();
+        for decoded in &disassembly.instrs {
+            let offset = (decoded.addr - CODE_START) as usize;
+            let hits = hit_counts.get(offset).cloned().unwrap_or(0);
+            coverage.hits.insert(decoded.addr, hits);
+            if let Some(Instr::Jz(_)) | Some(Instr::Jnz(_)) = decoded.instr {
+                let taken = taken_counts.get(offset).cloned().unwrap_or(0);
+                coverage.branches.insert(decoded.addr, (taken, hits - taken));
+            }
+        }
+        coverage
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (&addr, &hits) in &other.hits {
+            *self.hits.entry(addr).or_insert(0) += hits;
+        }
+        for (&addr, &(taken, not_taken)) in &other.branches {
+            let entry = self.branches.entry(addr).or_insert((0, 0));
+            entry.0 += taken;
+            entry.1 += not_taken;
+        }
+    }
+
+    fn hits(&self, addr: u64) -> u64 {
+        self.hits.get(&addr).cloned().unwrap_or(0)
+    }
+
+    /// Writes a single lcov record for the executable named `source_name`.
+    pub fn write_lcov<W: Write>(&self, mut out: W, source_name: &str, disassembly: &Disassembly) -> io::Result<()> {
+        let lines = disassembly.line_numbers();
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", source_name)?;
+
+        let functions = functions(disassembly);
+        for &(index, name) in &functions {
+            writeln!(out, "FN:{},{}", lines[index], name)?;
+        }
+        let mut functions_hit = 0;
+        for &(index, name) in &functions {
+            let hits = self.hits(disassembly.instrs[index].addr);
+            if hits > 0 {
+                functions_hit += 1;
+            }
+            writeln!(out, "FNDA:{},{}", hits, name)?;
+        }
+        writeln!(out, "FNF:{}", functions.len())?;
+        writeln!(out, "FNH:{}", functions_hit)?;
+
+        let mut branches_hit = 0;
+        let mut branches_found = 0;
+        for (decoded, &line) in disassembly.instrs.iter().zip(&lines) {
+            if let Some(&(taken, not_taken)) = self.branches.get(&decoded.addr) {
+                for (branch, &count) in [taken, not_taken].iter().enumerate() {
+                    branches_found += 1;
+                    if self.hits(decoded.addr) == 0 {
+                        writeln!(out, "BRDA:{},0,{},-", line, branch)?;
+                    } else {
+                        writeln!(out, "BRDA:{},0,{},{}", line, branch, count)?;
+                    }
+                    if count > 0 {
+                        branches_hit += 1;
+                    }
+                }
+            }
+        }
+        writeln!(out, "BRF:{}", branches_found)?;
+        writeln!(out, "BRH:{}", branches_hit)?;
+
+        let mut lines_hit = 0;
+        for (decoded, &line) in disassembly.instrs.iter().zip(&lines) {
+            let hits = self.hits(decoded.addr);
+            if hits > 0 {
+                lines_hit += 1;
+            }
+            writeln!(out, "DA:{},{}", line, hits)?;
+        }
+        writeln!(out, "LF:{}", lines.len())?;
+        writeln!(out, "LH:{}", lines_hit)?;
+        writeln!(out, "end_of_record")
+    }
+
+    /// Reads line and branch counts from a single lcov record. `first_line`
+    /// is the line number of the record in the whole file, used in errors.
+    fn parse_lcov_record(text: &str, first_line: usize, disassembly: &Disassembly) -> Result<Coverage, CoverageError> {
+        let addrs = disassembly
+            .line_numbers()
+            .into_iter()
+            .zip(disassembly.instrs.iter().map(|decoded| decoded.addr))
+            .collect::<HashMap<_, _>>();
+        let mut coverage = Coverage::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = first_line + index;
+            let bad_line = || CoverageError::BadLine(line_number);
+            let parse_count = |count: &str| match count {
+                "-" => Some(0),
+                count => count.parse::<u64>().ok(),
+            };
+            if let Some(data) = line.strip_prefix("DA:") {
+                let mut parts = data.split(',');
+                let line = parts.next().and_then(|line| line.parse::<usize>().ok()).ok_or_else(bad_line)?;
+                let hits = parts.next().and_then(parse_count).ok_or_else(bad_line)?;
+                let addr = *addrs.get(&line).ok_or(CoverageError::Mismatch(line_number))?;
+                *coverage.hits.entry(addr).or_insert(0) += hits;
+            } else if let Some(data) = line.strip_prefix("BRDA:") {
+                let parts = data.split(',').collect::<Vec<_>>();
+                if parts.len() != 4 {
+                    return Err(bad_line());
+                }
+                let line = parts[0].parse::<usize>().map_err(|_| bad_line())?;
+                let count = parse_count(parts[3]).ok_or_else(bad_line)?;
+                let addr = *addrs.get(&line).ok_or(CoverageError::Mismatch(line_number))?;
+                let entry = coverage.branches.entry(addr).or_insert((0, 0));
+                match parts[2] {
+                    "0" => entry.0 += count,
+                    "1" => entry.1 += count,
+                    _ => return Err(bad_line()),
+                }
+            }
+        }
+        Ok(coverage)
+    }
+
+    /// Merges coverage into the record for `source_name` in the lcov file
+    /// at `path`, creating the file if needed. Records for other
+    /// executables are kept as they are. Returns the merged coverage.
+    pub fn update_lcov_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        source_name: &str,
+        disassembly: &Disassembly,
+    ) -> Result<Coverage, CoverageError> {
+        let path = path.as_ref();
+        let existing = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = Vec::new();
+        let mut record = String::new();
+        let mut record_start = 1;
+        for (index, line) in existing.lines().enumerate() {
+            if record.is_empty() {
+                record_start = index + 1;
+            }
+            record.push_str(line);
+            record.push('\n');
+            if line.trim() == "end_of_record" {
+                records.push((record_start, ::std::mem::take(&mut record)));
+            }
+        }
+        if !record.trim().is_empty() {
+            records.push((record_start, record));
+        }
+
+        let source_line = format!("SF:{}", source_name);
+        let mut merged = self.clone();
+        let mut out = Vec::new();
+        let mut written = false;
+        for (start, record) in records {
+            if record.lines().any(|line| line == source_line) {
+                merged.merge(&Coverage::parse_lcov_record(&record, start, disassembly)?);
+                if !written {
+                    // the merged record is written in place of the first
+                    // record for the executable
+                    written = true;
+                    out.push(None);
+                }
+            } else {
+                out.push(Some(record));
+            }
+        }
+        if !written {
+            out.push(None);
+        }
+
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for record in out {
+            match record {
+                Some(record) => file.write_all(record.as_bytes())?,
+                None => merged.write_lcov(&mut file, source_name, disassembly)?,
+            }
+        }
+        file.flush()?;
+        Ok(merged)
+    }
+
+    /// Writes totals and address ranges of instructions that were never
+    /// executed, followed by conditional jumps that only went one way.
+    pub fn write_summary<W: Write>(&self, mut out: W, disassembly: &Disassembly, symbols: &Symbols) -> io::Result<()> {
+        let total_instrs = disassembly.instrs.len();
+        let hit_instrs = disassembly.instrs.iter().filter(|decoded| self.hits(decoded.addr) > 0).count();
+        let total_branches = self.branches.len() * 2;
+        let hit_branches = self
+            .branches
+            .values()
+            .map(|&(taken, not_taken)| (taken > 0) as usize + (not_taken > 0) as usize)
+            .sum::<usize>();
+        let functions = functions(disassembly);
+        let hit_functions = functions
+            .iter()
+            .filter(|&&(index, _)| self.hits(disassembly.instrs[index].addr) > 0)
+            .count();
+        writeln!(out, "instructions: {}/{} ({:.2}%)", hit_instrs, total_instrs, percent(hit_instrs, total_instrs))?;
+        writeln!(out, "branches: {}/{} ({:.2}%)", hit_branches, total_branches, percent(hit_branches, total_branches))?;
+        writeln!(out, "functions: {}/{} ({:.2}%)", hit_functions, functions.len(), percent(hit_functions, functions.len()))?;
+
+        let mut uncovered = Vec::new();
+        let mut range: Option<(u64, u64, usize)> = None;
+        for decoded in &disassembly.instrs {
+            if self.hits(decoded.addr) > 0 {
+                uncovered.extend(range.take());
+                continue;
+            }
+            let end = decoded.addr + decoded.len();
+            range = Some(match range {
+                Some((start, _, count)) => (start, end, count + 1),
+                None => (decoded.addr, end, 1),
+            });
+        }
+        uncovered.extend(range);
+        if !uncovered.is_empty() {
+            writeln!(out, "uncovered instructions:")?;
+        }
+        for (start, end, count) in uncovered {
+            writeln!(
+                out,
+                "  {:<45} {} instruction{}, up to {:#x}",
+                symbols.format_address(start),
+                count,
+                if count == 1 { "" } else { "s" },
+                end,
+            )?;
+        }
+
+        let partial = self
+            .branches
+            .iter()
+            .filter(|&(&addr, &(taken, not_taken))| self.hits(addr) > 0 && (taken == 0 || not_taken == 0))
+            .collect::<Vec<_>>();
+        if !partial.is_empty() {
+            writeln!(out, "partially covered branches:")?;
+        }
+        for (&addr, &(taken, _)) in partial {
+            let side = if taken == 0 { "never taken" } else { "always taken" };
+            writeln!(out, "  {:<45} {}", symbols.format_address(addr), side)?;
+        }
+        Ok(())
+    }
+}
+
+/// Indices in the disassembly and names of the entry point and functions.
+fn functions(disassembly: &Disassembly) -> Vec<(usize, &str)> {
+    disassembly
+        .instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, decoded)| {
+            let label = disassembly.labels.get(&decoded.addr)?;
+            if label.kind == LabelKind::Local {
+                None
+            } else {
+                Some((index, label.name.as_str()))
+            }
+        })
+        .collect()
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use asm::assemble;
+    use disasm::Disassembly;
+    use executable::{Exe, CODE_START};
+    use vm::{RunOutcome, Vm};
+    use super::{Coverage, CoverageError};
+
+    /// Exits with 97 if the first input byte is `a`, and with 0 otherwise.
+    const PROGRAM: &str = "
+        _start:
+            mov rax, 1
+            syscall
+            mov rax, 97
+            cmp rax, rbx
+            jz yes
+            xor rax, rax
+            push rax
+            pop rbx
+            syscall
+        yes:
+            xor rax, rax
+            syscall
+    ";
+
+    const JZ: u64 = CODE_START + 25;
+
+    fn run(exe: &Exe, input: &[u8], disassembly: &Disassembly) -> Coverage {
+        let mut input = input;
+        let mut output = Vec::new();
+        let mut vm = Vm::new(exe.clone(), &mut input, &mut output, false).unwrap();
+        vm.set_hit_counting(true);
+        match vm.run() {
+            RunOutcome::Exited(_) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        Coverage::from_counts(disassembly, vm.hit_counts().unwrap(), vm.taken_counts().unwrap())
+    }
+
+    #[test]
+    fn runs_are_merged_into_lcov_file() {
+        let exe = assemble(PROGRAM).unwrap();
+        let disassembly = Disassembly::new(&exe.code);
+        let taken = run(&exe, b"a", &disassembly);
+        let not_taken = run(&exe, b"b", &disassembly);
+        assert_eq!(taken.branches[&JZ], (1, 0));
+        assert_eq!(not_taken.branches[&JZ], (0, 1));
+
+        let path = env::temp_dir().join(format!("spark-emu-coverage-{}.info", process::id()));
+        let other_record = "TN:\nSF:other.exe\nDA:1,5\nend_of_record\n";
+        fs::write(&path, other_record).unwrap();
+        let first = taken.update_lcov_file(&path, "test.exe", &disassembly).unwrap();
+        assert_eq!(first, taken);
+        let second = not_taken.update_lcov_file(&path, "test.exe", &disassembly).unwrap();
+        let mut expected = taken.clone();
+        expected.merge(&not_taken);
+        assert_eq!(second, expected);
+        assert_eq!(second.branches[&JZ], (1, 1));
+        assert_eq!(second.hits[&CODE_START], 2);
+
+        // merging nothing reads the file back unchanged
+        let before = fs::read_to_string(&path).unwrap();
+        let reread = Coverage::new().update_lcov_file(&path, "test.exe", &disassembly).unwrap();
+        let after = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reread, expected);
+        assert_eq!(after, before);
+        // lines are counted in disassembly, where line 1 is the `_start:` label
+        let expected_record = "\
+TN:
+SF:test.exe
+FN:2,_start
+FNDA:2,_start
+FNF:1
+FNH:1
+BRDA:6,0,0,1
+BRDA:6,0,1,1
+BRF:2
+BRH:2
+DA:2,2
+DA:3,2
+DA:4,2
+DA:5,2
+DA:6,2
+DA:7,1
+DA:8,1
+DA:9,1
+DA:10,1
+DA:13,1
+DA:14,1
+LF:11
+LH:11
+end_of_record
+";
+        assert_eq!(after, format!("{}{}", other_record, expected_record));
+    }
+
+    #[test]
+    fn invalid_lcov_files_are_rejected() {
+        let exe = assemble(PROGRAM).unwrap();
+        let disassembly = Disassembly::new(&exe.code);
+        let path = env::temp_dir().join(format!("spark-emu-coverage-bad-{}.info", process::id()));
+        let cases = [
+            ("TN:\nSF:test.exe\nDA:2,x\nend_of_record\n", 3, false),
+            ("TN:\nSF:test.exe\nBRDA:6,0,2,1\nend_of_record\n", 3, false),
+            ("TN:\nSF:test.exe\nDA:2,1\nDA:1,1\nend_of_record\n", 4, true),
+        ];
+        for &(text, line, mismatch) in &cases {
+            fs::write(&path, text).unwrap();
+            match Coverage::new().update_lcov_file(&path, "test.exe", &disassembly) {
+                Err(CoverageError::BadLine(l)) if !mismatch && l == line => {}
+                Err(CoverageError::Mismatch(l)) if mismatch && l == line => {}
+                other => panic!("{:?}: {:?}", text, other),
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
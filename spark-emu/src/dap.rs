@@ -0,0 +1,804 @@
+//! Debug Adapter Protocol server.
+//!
+//! Messages are exchanged over the given input and output streams, normally
+//! stdin and stdout of the emulator. A single program is launched with the
+//! `launch` request, which takes these arguments:
+//!
+//! * `program` - path to spark executable
+//! * `stdin` - file to be used as program's stdin, empty if not given
+//! * `stdout` - file to be used as program's stdout, if not given then
+//!   program output is sent to the client as `output` events
+//! * `stopOnEntry` - pause before executing the first instruction
+//!
+//! Spark programs have no source information, so breakpoints are set with
+//! `setInstructionBreakpoints` and stepping works on single instructions.
+//!
+//! Requests are read on a separate thread, so that a running program can be
+//! stopped with `pause`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, VecDeque};
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use serde_json::{self, Value};
+use backtrace::{self, Frame};
+use debugger::{self, Stop};
+use disasm::Disassembly;
+use executable::{Exe, CODE_START, DATA_START};
+use vm::Vm;
+
+const THREAD_ID: u64 = 1;
+const REGISTERS_REFERENCE: u64 = 1;
+/// Stack scope of frame `n` has reference `STACK_REFERENCE_BASE + n`.
+const STACK_REFERENCE_BASE: u64 = 2;
+/// Maximum number of quad words shown in a stack scope.
+const STACK_WINDOW: u64 = 64;
+/// Longest accepted message body, in bytes.
+const MAX_MESSAGE_LENGTH: usize = 16 << 20;
+/// Number of instructions executed between checks for a `pause` request
+/// while the program is running.
+const PAUSE_CHECK_INTERVAL: u64 = 0x10000;
+
+/// Serves a single debugging session.
+pub fn serve<R: BufRead + Send + 'static, W: Write>(input: R, output: W) -> io::Result<()> {
+    let mut adapter = Adapter {
+        messages: read_messages(input),
+        pending: VecDeque::new(),
+        output,
+        seq: 1,
+        breakpoints: BTreeSet::new(),
+    };
+    let (request, launch) = match adapter.wait_for_launch()? {
+        Some(launch) => launch,
+        None => return Ok(()),
+    };
+    let Launch { exe, mut stdin, stdout, stop_on_entry } = launch;
+    let captured = Rc::new(RefCell::new(Vec::new()));
+    let mut program_output: Box<dyn Write> = match stdout {
+        Some(file) => Box::new(file),
+        None => Box::new(CapturedOutput(captured.clone())),
+    };
+    let disasm = Disassembly::new(&exe.code);
+    let code = exe.code.clone();
+    let mut vm = match Vm::new(exe, stdin.as_mut(), program_output.as_mut(), false) {
+        Ok(vm) => vm,
+        Err(e) => {
+            adapter.respond_error(&request, &format!("cannot load program: {}", e))?;
+            return adapter.send_event("terminated", Value::Null);
+        }
+    };
+    adapter.respond(&request, Value::Null)?;
+    for &addr in &adapter.breakpoints {
+        vm.add_breakpoint(addr);
+    }
+    let mut session = Session {
+        adapter,
+        vm: &mut vm,
+        disasm: &disasm,
+        code: &code,
+        captured,
+        stop_on_entry,
+        exited: false,
+    };
+    session.run()
+}
+
+struct Launch {
+    exe: Exe,
+    stdin: Box<dyn Read>,
+    stdout: Option<fs::File>,
+    stop_on_entry: bool,
+}
+
+/// Program output that is forwarded to the client.
+struct CapturedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for CapturedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Adapter<W> {
+    messages: Receiver<io::Result<Value>>,
+    /// Messages that were received while the program was running, and
+    /// are handled once it stops.
+    pending: VecDeque<Value>,
+    output: W,
+    seq: u64,
+    breakpoints: BTreeSet<u64>,
+}
+
+impl<W: Write> Adapter<W> {
+    /// Handles requests until the client asks to launch a program. Returns
+    /// `None` if the client disconnected instead.
+    fn wait_for_launch(&mut self) -> io::Result<Option<(Value, Launch)>> {
+        loop {
+            let request = match self.next_message()? {
+                Some(request) => request,
+                None => return Ok(None),
+            };
+            match command(&request) {
+                "initialize" => {
+                    let capabilities = json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsInstructionBreakpoints": true,
+                        "supportsSteppingGranularity": true,
+                        "supportsDisassembleRequest": true,
+                        "supportsTerminateRequest": true,
+                    });
+                    self.respond(&request, capabilities)?;
+                    self.send_event("initialized", Value::Null)?;
+                }
+                "launch" => match open_program(&request["arguments"]) {
+                    Ok(launch) => return Ok(Some((request, launch))),
+                    Err(message) => self.respond_error(&request, &message)?,
+                },
+                "disconnect" | "terminate" => {
+                    self.respond(&request, Value::Null)?;
+                    return Ok(None);
+                }
+                _ => {
+                    if !self.handle_common(&request)? {
+                        self.respond_error(&request, "program is not launched")?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles requests that do not need the program to be running.
+    /// Returns false if request is not one of those.
+    fn handle_common(&mut self, request: &Value) -> io::Result<bool> {
+        match command(request) {
+            "setInstructionBreakpoints" => {
+                self.breakpoints.clear();
+                let mut verified = Vec::new();
+                let requested = request["arguments"]["breakpoints"].as_array().cloned().unwrap_or_default();
+                for breakpoint in requested {
+                    let addr = breakpoint["instructionReference"]
+                        .as_str()
+                        .and_then(parse_address)
+                        .map(|addr| addr.wrapping_add(breakpoint["offset"].as_i64().unwrap_or(0) as u64));
+                    match addr {
+                        Some(addr) => {
+                            self.breakpoints.insert(addr);
+                            verified.push(json!({
+                                "verified": true,
+                                "instructionReference": format!("{:#x}", addr),
+                            }));
+                        }
+                        None => verified.push(json!({
+                            "verified": false,
+                            "message": "invalid instruction reference",
+                        })),
+                    }
+                }
+                self.respond(request, json!({ "breakpoints": verified }))?;
+            }
+            "setBreakpoints" => {
+                // there is no source code to set breakpoints in
+                let count = request["arguments"]["breakpoints"].as_array().map_or(0, |b| b.len());
+                let breakpoints = vec![json!({ "verified": false, "message": "spark programs have no sources" }); count];
+                self.respond(request, json!({ "breakpoints": breakpoints }))?;
+            }
+            "setExceptionBreakpoints" => {
+                self.respond(request, json!({ "breakpoints": [] }))?;
+            }
+            "threads" => {
+                self.respond(request, json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Waits for the next message. Returns `None` if the input ended.
+    fn next_message(&mut self) -> io::Result<Option<Value>> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some(message));
+        }
+        match self.messages.recv() {
+            Ok(message) => message.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Returns the next message if one was already received.
+    fn poll_message(&mut self) -> io::Result<Option<Value>> {
+        match self.messages.try_recv() {
+            Ok(message) => message.map(Some),
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn respond_error(&mut self, request: &Value, message: &str) -> io::Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+}
+
+struct Session<'s, 'a: 's, W> {
+    adapter: Adapter<W>,
+    vm: &'s mut Vm<'a>,
+    disasm: &'s Disassembly,
+    code: &'s [u8],
+    captured: Rc<RefCell<Vec<u8>>>,
+    stop_on_entry: bool,
+    exited: bool,
+}
+
+impl<'s, 'a, W: Write> Session<'s, 'a, W> {
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let request = match self.adapter.next_message()? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            match command(&request) {
+                "setInstructionBreakpoints" => self.set_breakpoints(&request)?,
+                "configurationDone" => {
+                    self.adapter.respond(&request, Value::Null)?;
+                    if self.stop_on_entry {
+                        self.send_stopped("entry", None)?;
+                    } else if let Some(stop) = self.resume()? {
+                        self.report(stop, "pause")?;
+                    }
+                }
+                "continue" | "next" | "stepIn" | "stepOut" if self.exited => {
+                    self.adapter.respond_error(&request, "program has exited")?;
+                }
+                "continue" => {
+                    self.adapter.respond(&request, json!({ "allThreadsContinued": true }))?;
+                    if let Some(stop) = self.resume()? {
+                        self.report(stop, "pause")?;
+                    }
+                }
+                // the program is already stopped
+                "pause" => self.adapter.respond(&request, Value::Null)?,
+                "next" => {
+                    self.adapter.respond(&request, Value::Null)?;
+                    let stop = debugger::step_over(self.vm, &self.adapter.breakpoints);
+                    self.report(stop, "step")?;
+                }
+                "stepIn" => {
+                    self.adapter.respond(&request, Value::Null)?;
+                    let stop = debugger::step(self.vm);
+                    self.report(stop, "step")?;
+                }
+                "stepOut" => {
+                    self.adapter.respond(&request, Value::Null)?;
+                    let stop = debugger::step_out(self.vm, &self.adapter.breakpoints);
+                    self.report(stop, "step")?;
+                }
+                "stackTrace" => {
+                    let body = self.stack_trace(&request["arguments"]);
+                    self.adapter.respond(&request, body)?;
+                }
+                "scopes" => {
+                    let frame = request["arguments"]["frameId"].as_u64().unwrap_or(0);
+                    let mut scopes = vec![json!({
+                        "name": "Stack",
+                        "variablesReference": STACK_REFERENCE_BASE + frame,
+                        "expensive": false,
+                    })];
+                    // registers are known only for the innermost frame
+                    if frame == 0 {
+                        scopes.insert(0, json!({
+                            "name": "Registers",
+                            "presentationHint": "registers",
+                            "variablesReference": REGISTERS_REFERENCE,
+                            "expensive": false,
+                        }));
+                    }
+                    self.adapter.respond(&request, json!({ "scopes": scopes }))?;
+                }
+                "variables" => {
+                    let reference = request["arguments"]["variablesReference"].as_u64().unwrap_or(0);
+                    let variables = self.variables(reference);
+                    self.adapter.respond(&request, json!({ "variables": variables }))?;
+                }
+                "disassemble" => match self.disassemble(&request["arguments"]) {
+                    Some(body) => self.adapter.respond(&request, body)?,
+                    None => self.adapter.respond_error(&request, "invalid memory reference")?,
+                },
+                "disconnect" | "terminate" => {
+                    self.adapter.respond(&request, Value::Null)?;
+                    return Ok(());
+                }
+                _ => {
+                    if !self.adapter.handle_common(&request)? {
+                        self.adapter.respond_error(&request, "unsupported request")?;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_breakpoints(&mut self, request: &Value) -> io::Result<()> {
+        for &addr in &self.adapter.breakpoints {
+            self.vm.remove_breakpoint(addr);
+        }
+        self.adapter.handle_common(request)?;
+        for &addr in &self.adapter.breakpoints {
+            self.vm.add_breakpoint(addr);
+        }
+        Ok(())
+    }
+
+    /// Runs the program until it stops, checking for requests every
+    /// `PAUSE_CHECK_INTERVAL` instructions. `pause` stops the program with
+    /// `Stop::Done`. Returns `None` if the client asked to end the session
+    /// instead.
+    fn resume(&mut self) -> io::Result<Option<Stop>> {
+        loop {
+            if let Some(outcome) = self.vm.run_for(PAUSE_CHECK_INTERVAL) {
+                return Ok(Some(debugger::stop_for(outcome)));
+            }
+            while let Some(request) = self.adapter.poll_message()? {
+                match command(&request) {
+                    "pause" => {
+                        self.adapter.respond(&request, Value::Null)?;
+                        return Ok(Some(Stop::Done));
+                    }
+                    "disconnect" | "terminate" => {
+                        self.adapter.pending.push_back(request);
+                        return Ok(None);
+                    }
+                    "setInstructionBreakpoints" => self.set_breakpoints(&request)?,
+                    _ => {
+                        if !self.adapter.handle_common(&request)? {
+                            self.adapter.respond_error(&request, "program is running")?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, stop: Stop, done_reason: &str) -> io::Result<()> {
+        let output = self.captured.borrow_mut().split_off(0);
+        if !output.is_empty() {
+            let output = String::from_utf8_lossy(&output).into_owned();
+            self.adapter.send_event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+        match stop {
+            Stop::Breakpoint(_) => self.send_stopped("breakpoint", None),
+            Stop::Watchpoint(hits) => {
+                let text = hits.iter().map(|hit| hit.to_string()).collect::<Vec<_>>().join("\n");
+                self.send_stopped("data breakpoint", Some(&text))
+            }
+            Stop::Done => self.send_stopped(done_reason, None),
+            Stop::Faulted(message) => self.send_stopped("exception", Some(&message)),
+            Stop::Exited(code) => {
+                self.exited = true;
+                self.adapter.send_event("exited", json!({ "exitCode": code }))?;
+                self.adapter.send_event("terminated", Value::Null)
+            }
+        }
+    }
+
+    fn send_stopped(&mut self, reason: &str, text: Option<&str>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.adapter.send_event("stopped", body)
+    }
+
+    fn stack_trace(&self, args: &Value) -> Value {
+        let frames = backtrace::call_stack(self.vm);
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(0) | None => frames.len(),
+            Some(levels) => levels as usize,
+        };
+        let stack_frames = frames
+            .iter()
+            .enumerate()
+            .skip(start)
+            .take(levels)
+            .map(|(index, frame)| {
+                let name = match self.disasm.function_containing(frame.addr) {
+                    Some((start, name)) => format!("{}+{}", name, frame.addr - start),
+                    None => format!("{:#x}", frame.addr),
+                };
+                json!({
+                    "id": index,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("{:#x}", frame.addr),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "stackFrames": stack_frames, "totalFrames": frames.len() })
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Value> {
+        if reference == REGISTERS_REFERENCE {
+            let regs = self.vm.registers();
+            let mut variables = vec![
+                variable("rip", format!("{:#018x}", regs.rip)),
+                variable("rax", format!("{:#018x}", regs.rax)),
+                variable("rbx", format!("{:#018x}", regs.rbx)),
+                variable("rdx", format!("{:#018x}", regs.rdx)),
+                variable("rsp", format!("{:#018x}", regs.rsp)),
+                variable("rbp", format!("{:#018x}", regs.rbp)),
+            ];
+            variables.push(variable("below_flag", (regs.below_flag as u8).to_string()));
+            variables.push(variable("zero_flag", (regs.zero_flag as u8).to_string()));
+            return variables;
+        }
+        let frames = backtrace::call_stack(self.vm);
+        let index = reference.wrapping_sub(STACK_REFERENCE_BASE) as usize;
+        let frame = match frames.get(index) {
+            Some(frame) => frame,
+            None => return Vec::new(),
+        };
+        // frame's stack window ends where its caller's begins
+        let end = frames
+            .get(index + 1)
+            .map(|caller: &Frame| caller.stack_pointer)
+            .unwrap_or(DATA_START);
+        let mut variables = Vec::new();
+        let mut addr = frame.stack_pointer;
+        while addr < end && variables.len() < STACK_WINDOW as usize {
+            let value = match self.vm.read_data(addr) {
+                Ok(value) => format!("{:#018x}", value),
+                Err(_) => break,
+            };
+            let regs = self.vm.registers();
+            let name = if index == 0 && addr == regs.rbp {
+                format!("{:#x} (rbp)", addr)
+            } else {
+                format!("{:#x}", addr)
+            };
+            variables.push(variable(&name, value));
+            addr += 8;
+        }
+        variables
+    }
+
+    fn disassemble(&self, args: &Value) -> Option<Value> {
+        let base = parse_address(args["memoryReference"].as_str()?)?
+            .wrapping_add(args["offset"].as_i64().unwrap_or(0) as u64);
+        let instruction_offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64()?;
+        let instrs = &self.disasm.instrs;
+        // index of the instruction containing `base`, or where it would be
+        let base_index = if base < CODE_START {
+            -((CODE_START - base) as i64)
+        } else {
+            instrs.iter().take_while(|decoded| decoded.addr <= base).count() as i64 - 1
+        };
+        let end_address = CODE_START + self.code.len() as u64;
+        let mut instructions = Vec::new();
+        for position in 0..count as i64 {
+            let index = base_index + instruction_offset + position;
+            if index < 0 || index >= instrs.len() as i64 {
+                let addr = if index < 0 {
+                    CODE_START.wrapping_sub(index.unsigned_abs())
+                } else {
+                    end_address + (index - instrs.len() as i64) as u64
+                };
+                instructions.push(json!({
+                    "address": format!("{:#x}", addr),
+                    "instruction": "??",
+                    "presentationHint": "invalid",
+                }));
+                continue;
+            }
+            let decoded = &instrs[index as usize];
+            let start = (decoded.addr - CODE_START) as usize;
+            let bytes = self.code[start..start + decoded.len() as usize]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut instruction = json!({
+                "address": format!("{:#x}", decoded.addr),
+                "instructionBytes": bytes,
+                "instruction": self.disasm.format_instr(decoded, self.code),
+            });
+            if let Some(label) = self.disasm.label(decoded.addr) {
+                instruction["symbol"] = json!(label);
+            }
+            instructions.push(instruction);
+        }
+        Some(json!({ "instructions": instructions }))
+    }
+}
+
+fn open_program(args: &Value) -> Result<Launch, String> {
+    let path = args["program"].as_str().ok_or("`program` is not specified")?;
+    let exe = Exe::read_from_file(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let stdin: Box<dyn Read> = match args["stdin"].as_str() {
+        Some(path) => Box::new(fs::File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?),
+        None => Box::new(io::empty()),
+    };
+    let stdout = match args["stdout"].as_str() {
+        Some(path) => Some(fs::File::create(path).map_err(|e| format!("cannot create {}: {}", path, e))?),
+        None => None,
+    };
+    Ok(Launch {
+        exe,
+        stdin,
+        stdout,
+        stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+    })
+}
+
+/// Reads messages on a separate thread until the input ends or cannot be
+/// read.
+fn read_messages<R: BufRead + Send + 'static>(mut input: R) -> Receiver<io::Result<Value>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let failed = message.is_err();
+        if sender.send(message).is_err() || failed {
+            return;
+        }
+    });
+    receiver
+}
+
+/// Reads a message made of headers, an empty line, and a JSON body
+/// with the length given by the `Content-Length` header. Returns `None`
+/// if the input ends before the next message.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    let mut has_headers = false;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            if has_headers {
+                break;
+            }
+            continue;
+        }
+        has_headers = true;
+        let mut parts = line.splitn(2, ':');
+        if parts.next().map(str::trim) == Some("Content-Length") {
+            let value = parts.next().unwrap_or("").trim();
+            match value.parse::<usize>() {
+                Ok(len) if len <= MAX_MESSAGE_LENGTH => content_length = Some(len),
+                Ok(len) => return Err(invalid_data(format!("message is too long: {} bytes", len))),
+                Err(_) => return Err(invalid_data(format!("invalid Content-Length: {:?}", value))),
+            }
+        }
+    }
+    let content_length = content_length.ok_or_else(|| invalid_data("missing Content-Length header".to_string()))?;
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn command(request: &Value) -> &str {
+    request["command"].as_str().unwrap_or("")
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+fn parse_address(text: &str) -> Option<u64> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u64::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io;
+    use std::process;
+    use serde_json::Value;
+    use asm::assemble;
+    use super::{read_message, serve, MAX_MESSAGE_LENGTH};
+
+    fn read_error(input: &str) -> io::Error {
+        match read_message(&mut input.as_bytes()) {
+            Ok(message) => panic!("read {:?}", message),
+            Err(e) => e,
+        }
+    }
+
+    /// Frames messages the way clients send them.
+    fn frame(messages: &[Value]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for message in messages {
+            let body = message.to_string();
+            input.extend_from_slice(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).as_bytes());
+        }
+        input
+    }
+
+    /// Splits output of the adapter into messages.
+    fn unframe(output: &[u8]) -> Vec<Value> {
+        let mut messages = Vec::new();
+        let mut output = output;
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// Shows responses with their success and events with their body.
+    fn summarize(output: &[u8]) -> Vec<String> {
+        unframe(output)
+            .iter()
+            .map(|message| match message["type"].as_str() {
+                Some("response") => format!("{} {}", message["command"].as_str().unwrap(), message["success"]),
+                Some("event") if message["event"] == "stopped" => format!("stopped {}", message["body"]["reason"]),
+                Some("event") => format!("{} {}", message["event"].as_str().unwrap(), message["body"]),
+                _ => panic!("unexpected message: {}", message),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_framed_by_content_length() {
+        let input = "Content-Length: 10\r\nContent-Type: application/json\r\n\r\n{\"seq\": 1}\
+                     Content-Length:2\n\n{}";
+        let mut input = input.as_bytes();
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({ "seq": 1 })));
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({})));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn bad_headers_are_invalid_data() {
+        let cases = [
+            "Content-Type: application/json\r\n\r\n{}",
+            "Content-Length: two\r\n\r\n{}",
+            "Content-Length: -2\r\n\r\n{}",
+            "Content-Length:\r\n\r\n",
+        ];
+        for &input in &cases {
+            assert_eq!(read_error(input).kind(), io::ErrorKind::InvalidData, "{:?}", input);
+        }
+        let huge = format!("Content-Length: {}\r\n\r\n{{}}", MAX_MESSAGE_LENGTH + 1);
+        assert_eq!(read_error(&huge).kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_error("Content-Length: 5\r\n\r\n{]}}}").kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        assert_eq!(read_error("Content-Length: 50\r\n\r\n{}").kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn session_stops_at_breakpoint_on_entry_point() {
+        let exe = assemble("
+            mov rax, 2
+            push rax
+            pop rbx
+            xor rax, rax
+            syscall
+        ").unwrap();
+        let path = env::temp_dir().join(format!("spark-dap-test-{}.exe", process::id()));
+        exe.write_to_file(&path).unwrap();
+        let input = frame(&[
+            json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} }),
+            json!({ "seq": 2, "type": "request", "command": "launch", "arguments": { "program": path } }),
+            json!({
+                "seq": 3,
+                "type": "request",
+                "command": "setInstructionBreakpoints",
+                "arguments": { "breakpoints": [{ "instructionReference": "0x10000000" }] },
+            }),
+            json!({ "seq": 4, "type": "request", "command": "configurationDone" }),
+            json!({ "seq": 5, "type": "request", "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "seq": 6, "type": "request", "command": "disconnect" }),
+        ]);
+        let mut output = Vec::new();
+        serve(io::Cursor::new(input), &mut output).unwrap();
+        let _ = ::std::fs::remove_file(&path);
+        assert_eq!(summarize(&output), [
+            "initialize true",
+            "initialized null",
+            "launch true",
+            "setInstructionBreakpoints true",
+            "configurationDone true",
+            "stopped \"breakpoint\"",
+            "continue true",
+            "exited {\"exitCode\":2}",
+            "terminated null",
+            "disconnect true",
+        ]);
+        let seqs = unframe(&output).iter().map(|message| message["seq"].as_u64().unwrap()).collect::<Vec<_>>();
+        assert_eq!(seqs, (1..11).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn running_program_is_paused() {
+        let exe = assemble("
+        spin:
+            jmp spin
+        ").unwrap();
+        let path = env::temp_dir().join(format!("spark-dap-pause-test-{}.exe", process::id()));
+        exe.write_to_file(&path).unwrap();
+        let input = frame(&[
+            json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} }),
+            json!({ "seq": 2, "type": "request", "command": "launch", "arguments": { "program": path } }),
+            json!({ "seq": 3, "type": "request", "command": "configurationDone" }),
+            json!({ "seq": 4, "type": "request", "command": "threads" }),
+            json!({ "seq": 5, "type": "request", "command": "pause", "arguments": { "threadId": 1 } }),
+            json!({ "seq": 6, "type": "request", "command": "disconnect" }),
+        ]);
+        let mut output = Vec::new();
+        serve(io::Cursor::new(input), &mut output).unwrap();
+        let _ = ::std::fs::remove_file(&path);
+        assert_eq!(summarize(&output), [
+            "initialize true",
+            "initialized null",
+            "launch true",
+            "configurationDone true",
+            "threads true",
+            "pause true",
+            "stopped \"pause\"",
+            "disconnect true",
+        ]);
+    }
+}
@@ -0,0 +1,642 @@
+//! Interactive command-line debugger.
+//!
+//! Commands are read line by line, similarly to gdb:
+//!
+//! * `break <addr>` - stop before executing instruction at `addr`
+//! * `delete <addr>` - remove a breakpoint
+//! * `watch <addr>[:r|:w|:rw][=<value>]` - stop when memory at `addr` is
+//!   accessed, see `watch` module for the syntax
+//! * `unwatch <addr>` - remove watchpoints on `addr`
+//! * `run` - start the program from the beginning
+//! * `continue` - run until a breakpoint or until the program stops
+//! * `step` - execute a single instruction
+//! * `next` - like `step`, but run called function to completion
+//! * `finish` - run until current function returns
+//! * `regs` - show registers and flags
+//! * `x/<n>g <addr>` - show `n` quad words of memory at `addr`
+//! * `disas` - show instructions around `rip`
+//! * `quit`
+//!
+//! Addresses can be written in decimal or hex, or as labels generated by
+//! the disassembler (like `fn_0x10000041`). Empty line repeats the previous
+//! command.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Read, Write};
+use disasm::{DecodedInstr, Disassembly};
+use executable::{Exe, CODE_START};
+use instruction::Instr;
+use vm::{ExecResult, LoadError, RunOutcome, Status, Vm};
+use watch::{WatchHit, Watchpoint};
+
+const HELP: &str = "\
+commands:
+  break <addr>      set breakpoint (b)
+  delete <addr>     remove breakpoint (d)
+  watch <spec>      set watchpoint, <addr>[:r|:w|:rw][=<value>]
+  unwatch <addr>    remove watchpoints on addr
+  run               start program from the beginning (r)
+  continue          continue execution (c)
+  step              execute one instruction (s)
+  next              execute one instruction, stepping over calls (n)
+  finish            run until current function returns
+  regs              show registers
+  x/<n>g <addr>     examine n quad words at addr
+  disas             disassemble around rip
+  quit              exit debugger (q)";
+
+/// What happened to the program while running a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u64),
+    Watchpoint(Vec<WatchHit>),
+    Exited(u64),
+    Faulted(String),
+    /// Command finished without the program stopping on its own.
+    Done,
+}
+
+enum Action {
+    Restart,
+    Quit,
+}
+
+struct Session<'a, W: Write> {
+    code: &'a [u8],
+    disasm: &'a Disassembly,
+    breakpoints: &'a mut BTreeSet<u64>,
+    watchpoints: &'a mut Vec<Watchpoint>,
+    out: W,
+    /// Set once the program exits or faults.
+    stopped: bool,
+    started: bool,
+}
+
+/// Runs the debugger on `exe`, reading commands from `commands` and writing
+/// responses to `out`. Program input is obtained from `open_stdin` every
+/// time the program is (re)started.
+pub fn debug<C, W>(
+    exe: &Exe,
+    open_stdin: &mut dyn FnMut() -> io::Result<Box<dyn Read>>,
+    program_stdout: &mut dyn Write,
+    mut commands: C,
+    mut out: W,
+) -> io::Result<()>
+where
+    C: BufRead,
+    W: Write,
+{
+    let disasm = Disassembly::new(&exe.code);
+    let mut breakpoints = BTreeSet::new();
+    let mut watchpoints = Vec::new();
+    let mut started = false;
+    loop {
+        let mut stdin = open_stdin()?;
+        let mut vm = match Vm::new(exe.clone(), stdin.as_mut(), &mut *program_stdout, false) {
+            Ok(vm) => vm,
+            Err(LoadError::BadDataLength(len)) => {
+                writeln!(out, "cannot load program: data section length {} is not divisible by 8", len)?;
+                return Ok(());
+            }
+        };
+        for &addr in &breakpoints {
+            vm.add_breakpoint(addr);
+        }
+        for &watchpoint in &watchpoints {
+            vm.add_watchpoint(watchpoint);
+        }
+        let mut session = Session {
+            code: &exe.code,
+            disasm: &disasm,
+            breakpoints: &mut breakpoints,
+            watchpoints: &mut watchpoints,
+            out: &mut out,
+            stopped: false,
+            started,
+        };
+        let action = session.repl(&mut vm, &mut commands)?;
+        started = true;
+        match action {
+            Action::Restart => continue,
+            Action::Quit => return Ok(()),
+        }
+    }
+}
+
+impl<'a, W: Write> Session<'a, W> {
+    fn repl<C: BufRead>(&mut self, vm: &mut Vm, commands: &mut C) -> io::Result<Action> {
+        if self.started {
+            // program was restarted with `run`
+            let stop = resume(vm);
+            self.report(vm, stop)?;
+        }
+        let mut last_command = String::new();
+        loop {
+            write!(self.out, "(sdb) ")?;
+            self.out.flush()?;
+            let mut line = String::new();
+            if commands.read_line(&mut line)? == 0 {
+                return Ok(Action::Quit);
+            }
+            let line = line.trim();
+            let command = if line.is_empty() {
+                last_command.clone()
+            } else {
+                line.to_string()
+            };
+            last_command = command.clone();
+            if let Some(action) = self.execute(vm, &command)? {
+                return Ok(action);
+            }
+        }
+    }
+
+    fn execute(&mut self, vm: &mut Vm, command: &str) -> io::Result<Option<Action>> {
+        let mut parts = command.split_whitespace();
+        let name = match parts.next() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let arg = parts.next();
+        match name {
+            "b" | "break" => match arg.and_then(|arg| self.parse_address(arg)) {
+                Some(addr) => {
+                    self.breakpoints.insert(addr);
+                    vm.add_breakpoint(addr);
+                    writeln!(self.out, "breakpoint at {}", self.describe(addr))?;
+                }
+                None => writeln!(self.out, "usage: break <addr>")?,
+            },
+            "d" | "delete" => match arg.and_then(|arg| self.parse_address(arg)) {
+                Some(addr) => {
+                    if self.breakpoints.remove(&addr) {
+                        vm.remove_breakpoint(addr);
+                    } else {
+                        writeln!(self.out, "no breakpoint at {:#x}", addr)?;
+                    }
+                }
+                None => writeln!(self.out, "usage: delete <addr>")?,
+            },
+            "watch" => match arg.map(str::parse::<Watchpoint>) {
+                Some(Ok(watchpoint)) => {
+                    self.watchpoints.push(watchpoint);
+                    vm.add_watchpoint(watchpoint);
+                    writeln!(self.out, "watchpoint {}", watchpoint)?;
+                }
+                Some(Err(e)) => writeln!(self.out, "{}", e)?,
+                None => writeln!(self.out, "usage: watch <addr>[:r|:w|:rw][=<value>]")?,
+            },
+            "unwatch" => match arg.and_then(|arg| self.parse_address(arg)) {
+                Some(addr) => {
+                    let count = self.watchpoints.len();
+                    self.watchpoints.retain(|watchpoint| watchpoint.addr != addr);
+                    if self.watchpoints.len() == count {
+                        writeln!(self.out, "no watchpoint at {:#x}", addr)?;
+                    }
+                    vm.remove_watchpoint(addr);
+                }
+                None => writeln!(self.out, "usage: unwatch <addr>")?,
+            },
+            "r" | "run" => {
+                if self.started {
+                    return Ok(Some(Action::Restart));
+                }
+                self.started = true;
+                let stop = resume(vm);
+                self.report(vm, stop)?;
+            }
+            "c" | "continue" => {
+                if self.check_running()? {
+                    self.started = true;
+                    let stop = resume(vm);
+                    self.report(vm, stop)?;
+                }
+            }
+            "s" | "step" => {
+                if self.check_running()? {
+                    self.started = true;
+                    let stop = step(vm);
+                    self.report(vm, stop)?;
+                }
+            }
+            "n" | "next" => {
+                if self.check_running()? {
+                    self.started = true;
+                    let stop = step_over(vm, self.breakpoints);
+                    self.report(vm, stop)?;
+                }
+            }
+            "finish" => {
+                if self.check_running()? {
+                    self.started = true;
+                    let stop = step_out(vm, self.breakpoints);
+                    self.report(vm, stop)?;
+                }
+            }
+            "regs" => self.print_registers(vm)?,
+            "disas" => self.print_disassembly(vm.registers().rip)?,
+            "h" | "help" => writeln!(self.out, "{}", HELP)?,
+            "q" | "quit" => return Ok(Some(Action::Quit)),
+            _ if name == "x" || name.starts_with("x/") => {
+                let count = name
+                    .trim_start_matches('x')
+                    .trim_start_matches('/')
+                    .trim_end_matches('g');
+                let count = if count.is_empty() { Some(1) } else { count.parse().ok() };
+                match (count, arg.and_then(|arg| self.parse_address(arg))) {
+                    (Some(count), Some(addr)) => self.examine(vm, addr, count)?,
+                    _ => writeln!(self.out, "usage: x/<n>g <addr>")?,
+                }
+            }
+            _ => writeln!(self.out, "unknown command `{}`, try `help`", name)?,
+        }
+        Ok(None)
+    }
+
+    fn check_running(&mut self) -> io::Result<bool> {
+        if self.stopped {
+            writeln!(self.out, "the program is not being run")?;
+        }
+        Ok(!self.stopped)
+    }
+
+    fn report(&mut self, vm: &Vm, stop: Stop) -> io::Result<()> {
+        match stop {
+            Stop::Breakpoint(addr) => {
+                writeln!(self.out, "breakpoint hit at {}", self.describe(addr))?;
+            }
+            Stop::Watchpoint(hits) => {
+                for hit in hits {
+                    writeln!(self.out, "watchpoint hit: {}, in {}", hit, self.describe(hit.rip))?;
+                }
+            }
+            Stop::Exited(code) => {
+                self.stopped = true;
+                writeln!(self.out, "program exited with value {} ({:#x})", code, code)?;
+                return Ok(());
+            }
+            Stop::Faulted(message) => {
+                self.stopped = true;
+                writeln!(self.out, "program faulted: {}", message)?;
+            }
+            Stop::Done => {}
+        }
+        self.print_current(vm.registers().rip)
+    }
+
+    fn print_current(&mut self, rip: u64) -> io::Result<()> {
+        let offset = rip.wrapping_sub(CODE_START) as usize;
+        if offset >= self.code.len() {
+            return writeln!(self.out, "=> {:#x}: <outside of code section>", rip);
+        }
+        let text = match Instr::decode(&self.code[offset..]) {
+            Some(instr) => {
+                let decoded = DecodedInstr { addr: rip, instr: Some(instr) };
+                self.disasm.format_instr(&decoded, self.code)
+            }
+            None => "(bad)".to_string(),
+        };
+        writeln!(self.out, "=> {}: {}", self.describe(rip), text)
+    }
+
+    fn print_registers(&mut self, vm: &Vm) -> io::Result<()> {
+        let regs = vm.registers();
+        writeln!(self.out, "rip  {:#018x}  {}", regs.rip, self.describe(regs.rip))?;
+        for &(name, value) in &[
+            ("rax", regs.rax),
+            ("rbx", regs.rbx),
+            ("rdx", regs.rdx),
+            ("rsp", regs.rsp),
+            ("rbp", regs.rbp),
+        ] {
+            writeln!(self.out, "{}  {:#018x}  {}", name, value, value)?;
+        }
+        writeln!(self.out, "below_flag = {}, zero_flag = {}", regs.below_flag as u8, regs.zero_flag as u8)
+    }
+
+    fn examine(&mut self, vm: &Vm, addr: u64, count: u64) -> io::Result<()> {
+        for index in 0..count {
+            let addr = addr.wrapping_add(index * 8);
+            if index % 2 == 0 {
+                if index > 0 {
+                    writeln!(self.out)?;
+                }
+                write!(self.out, "{:#x}:", addr)?;
+            }
+            match vm.read_data(addr) {
+                Ok(value) => write!(self.out, "  {:#018x}", value)?,
+                Err(e) => {
+                    writeln!(self.out)?;
+                    return writeln!(self.out, "cannot read memory: {}", e);
+                }
+            }
+        }
+        writeln!(self.out)
+    }
+
+    fn print_disassembly(&mut self, rip: u64) -> io::Result<()> {
+        let index = match self.disasm.index_of(rip) {
+            Some(index) => index,
+            None => return self.print_current(rip),
+        };
+        let start = index.saturating_sub(5);
+        let end = (index + 10).min(self.disasm.instrs.len());
+        for decoded in &self.disasm.instrs[start..end] {
+            if let Some(label) = self.disasm.label(decoded.addr) {
+                writeln!(self.out, "{}:", label)?;
+            }
+            let marker = if decoded.addr == rip { "=>" } else { "  " };
+            let text = self.disasm.format_instr(decoded, self.code);
+            writeln!(self.out, "{} {:#010x}:  {}", marker, decoded.addr, text)?;
+        }
+        Ok(())
+    }
+
+    fn parse_address(&self, text: &str) -> Option<u64> {
+        if text.starts_with("0x") || text.starts_with("0X") {
+            return u64::from_str_radix(&text[2..], 16).ok();
+        }
+        if let Ok(value) = text.parse() {
+            return Some(value);
+        }
+        self.disasm
+            .labels
+            .iter()
+            .find(|&(_, label)| label.name == text)
+            .map(|(&addr, _)| addr)
+    }
+
+    /// Formats address together with the function it belongs to.
+    fn describe(&self, addr: u64) -> String {
+        match self.disasm.function_containing(addr) {
+            Some((start, name)) if start == addr => format!("{:#x} <{}>", addr, name),
+            Some((start, name)) => format!("{:#x} <{}+{}>", addr, name, addr - start),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+fn stop_from(result: ExecResult<Status>) -> Stop {
+    match result {
+        Ok(Status::Running) => Stop::Done,
+        Ok(Status::Halted(code)) => Stop::Exited(code),
+        Err(e) => Stop::Faulted(e.to_string()),
+    }
+}
+
+/// Executes a single instruction.
+pub fn step(vm: &mut Vm) -> Stop {
+    match stop_from(vm.cycle()) {
+        Stop::Done => {
+            let hits = vm.take_watch_hits();
+            if hits.is_empty() {
+                Stop::Done
+            } else {
+                Stop::Watchpoint(hits)
+            }
+        }
+        stop => stop,
+    }
+}
+
+/// Executes a single instruction, but if it is a `Call`, keeps going until
+/// the called function returns.
+pub fn step_over(vm: &mut Vm, breakpoints: &BTreeSet<u64>) -> Stop {
+    let regs = vm.registers();
+    match vm.current_instr() {
+        Ok(instr @ Instr::Call(_)) => {
+            let return_addr = regs.rip + instr.len();
+            let first = step(vm);
+            // the return address is reached with a different `rsp` when the
+            // function calls itself recursively
+            run_to(vm, first, &[return_addr], breakpoints, |vm| {
+                let now = vm.registers();
+                if now.rip == return_addr && now.rsp == regs.rsp {
+                    Some(Stop::Done)
+                } else if breakpoints.contains(&now.rip) {
+                    Some(Stop::Breakpoint(now.rip))
+                } else {
+                    None
+                }
+            })
+        }
+        _ => step(vm),
+    }
+}
+
+/// Runs until the `Ret` that returns from the current function is
+/// executed. That is the first `Ret` executed with `rsp` at or above its
+/// current value, as returns from nested calls happen below it.
+pub fn step_out(vm: &mut Vm, breakpoints: &BTreeSet<u64>) -> Stop {
+    let start_rsp = vm.registers().rsp;
+    if matches!(vm.current_instr(), Ok(Instr::Ret)) {
+        return step(vm);
+    }
+    let first = step(vm);
+    let returns = Disassembly::new(vm.code())
+        .instrs
+        .iter()
+        .filter(|decoded| decoded.instr == Some(Instr::Ret))
+        .map(|decoded| decoded.addr)
+        .collect::<Vec<_>>();
+    run_to(vm, first, &returns, breakpoints, |vm| {
+        let now = vm.registers();
+        if breakpoints.contains(&now.rip) {
+            Some(Stop::Breakpoint(now.rip))
+        } else if matches!(vm.current_instr(), Ok(Instr::Ret)) && now.rsp >= start_rsp {
+            Some(step(vm))
+        } else {
+            None
+        }
+    })
+}
+
+/// Runs until a breakpoint set in `vm` is reached or the program stops.
+pub fn resume(vm: &mut Vm) -> Stop {
+    stop_for(vm.run())
+}
+
+/// Converts the result of running `vm` into a stop.
+pub fn stop_for(outcome: RunOutcome) -> Stop {
+    match outcome {
+        RunOutcome::Exited(code) => Stop::Exited(code),
+        RunOutcome::Faulted(e) => Stop::Faulted(e.to_string()),
+        RunOutcome::Paused(addr) => Stop::Breakpoint(addr),
+        RunOutcome::Watchpoint(hits) => Stop::Watchpoint(hits),
+        RunOutcome::OutOfFuel(backtrace) => {
+            Stop::Faulted(format!("instruction limit reached (rip = {:#x})", backtrace.registers.rip))
+        }
+        RunOutcome::TimedOut(backtrace) => {
+            Stop::Faulted(format!("time limit reached (rip = {:#x})", backtrace.registers.rip))
+        }
+    }
+}
+
+/// Runs with temporary breakpoints at `addrs` until `check` returns a stop
+/// or the program stops on its own. `check` is called before running and
+/// whenever a breakpoint is reached.
+fn run_to<F>(vm: &mut Vm, first: Stop, addrs: &[u64], breakpoints: &BTreeSet<u64>, mut check: F) -> Stop
+where
+    F: FnMut(&mut Vm) -> Option<Stop>,
+{
+    match first {
+        Stop::Done => {}
+        other => return other,
+    }
+    if let Some(stop) = check(vm) {
+        return stop;
+    }
+    let temporary = addrs
+        .iter()
+        .cloned()
+        .filter(|addr| !breakpoints.contains(addr))
+        .collect::<Vec<_>>();
+    for &addr in &temporary {
+        vm.add_breakpoint(addr);
+    }
+    let stop = loop {
+        match resume(vm) {
+            Stop::Breakpoint(_) => {
+                if let Some(stop) = check(vm) {
+                    break stop;
+                }
+            }
+            other => break other,
+        }
+    };
+    for &addr in &temporary {
+        vm.remove_breakpoint(addr);
+    }
+    stop
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+    use asm::assemble;
+    use super::debug;
+
+    /// Runs the debugger with the given commands and returns its output.
+    fn session(source: &str, commands: &str) -> String {
+        let exe = assemble(source).unwrap();
+        let mut open_stdin = || Ok(Box::new(io::empty()) as Box<dyn Read>);
+        let mut program_output = Vec::new();
+        let mut out = Vec::new();
+        debug(&exe, &mut open_stdin, &mut program_output, commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const EXIT_PROGRAM: &str = "
+        mov rax, 0
+        push rax
+        pop rbx
+        syscall
+    ";
+
+    #[test]
+    fn breakpoint_on_entry_point_stops_run() {
+        let output = session(EXIT_PROGRAM, "break 0x10000000\nrun\ncontinue\n");
+        let expected = "\
+(sdb) breakpoint at 0x10000000 <_start>
+(sdb) breakpoint hit at 0x10000000 <_start>
+=> 0x10000000 <_start>: mov rax, 0
+(sdb) program exited with value 0 (0x0)
+(sdb) ";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn restart_stops_at_entry_point_again() {
+        let output = session(EXIT_PROGRAM, "break _start\nrun\nrun\ncontinue\n");
+        assert_eq!(output.matches("breakpoint hit at 0x10000000 <_start>").count(), 2);
+        assert!(output.ends_with("(sdb) program exited with value 0 (0x0)\n(sdb) "), "{}", output);
+    }
+
+    #[test]
+    fn continue_after_step_onto_breakpoint_does_not_stop_there() {
+        let output = session(EXIT_PROGRAM, "break 0x1000000a\nstep\ncontinue\ncontinue\n");
+        let expected = "\
+(sdb) breakpoint at 0x1000000a <_start+10>
+(sdb) => 0x1000000a <_start+10>: push rax
+(sdb) program exited with value 0 (0x0)
+(sdb) the program is not being run
+(sdb) ";
+        assert_eq!(output, expected);
+    }
+
+    /// `main` calls the recursive `count` with 2 and then `helper`, which
+    /// has no prologue.
+    const CALLS_PROGRAM: &str = "
+        call main
+        xor rax, rax
+        syscall
+    main:
+        push rbp
+        mov rbp, rsp
+        mov rax, 2
+        call count
+        call helper
+        pop rbp
+        ret
+    count:
+        test rax, rax
+        jz done
+        push rax
+        mov rax, 1
+        push rax
+        pop rbx
+        pop rax
+        sub rax, rbx
+        call count
+    done:
+        ret
+    helper:
+        push rax
+        pop rax
+        ret
+    ";
+
+    #[test]
+    fn next_steps_over_recursive_calls() {
+        let output = session(CALLS_PROGRAM, "break 0x10000018\nrun\nnext\nnext\n");
+        let expected = "\
+(sdb) breakpoint at 0x10000018 <fn_0x1000000a+14>
+(sdb) breakpoint hit at 0x10000018 <fn_0x1000000a+14>
+=> 0x10000018 <fn_0x1000000a+14>: call 0x10000024 <fn_0x10000024>
+(sdb) => 0x1000001d <fn_0x1000000a+19>: call 0x10000044 <fn_0x10000044>
+(sdb) => 0x10000022 <fn_0x1000000a+24>: pop rbp
+(sdb) ";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn next_stops_at_breakpoint_in_called_function() {
+        let output = session(CALLS_PROGRAM, "break 0x10000018\nbreak 0x10000043\nrun\nnext\n");
+        let expected = "\
+(sdb) breakpoint at 0x10000018 <fn_0x1000000a+14>
+(sdb) breakpoint at 0x10000043 <fn_0x10000024+31>
+(sdb) breakpoint hit at 0x10000018 <fn_0x1000000a+14>
+=> 0x10000018 <fn_0x1000000a+14>: call 0x10000024 <fn_0x10000024>
+(sdb) breakpoint hit at 0x10000043 <fn_0x10000024+31>
+=> 0x10000043 <fn_0x10000024+31>: ret
+(sdb) ";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn finish_returns_to_caller() {
+        let output = session(CALLS_PROGRAM, "break 0x10000024\nbreak 0x10000044\nrun\ndelete 0x10000024\nfinish\ncontinue\nstep\nfinish\n");
+        let expected = "\
+(sdb) breakpoint at 0x10000024 <fn_0x10000024>
+(sdb) breakpoint at 0x10000044 <fn_0x10000044>
+(sdb) breakpoint hit at 0x10000024 <fn_0x10000024>
+=> 0x10000024 <fn_0x10000024>: test rax, rax
+(sdb) (sdb) => 0x1000001d <fn_0x1000000a+19>: call 0x10000044 <fn_0x10000044>
+(sdb) breakpoint hit at 0x10000044 <fn_0x10000044>
+=> 0x10000044 <fn_0x10000044>: push rax
+(sdb) => 0x10000045 <fn_0x10000044+1>: pop rax
+(sdb) => 0x10000022 <fn_0x1000000a+24>: pop rbp
+(sdb) ";
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,260 @@
+//! Static disassembly of code sections.
+//!
+//! Code is decoded linearly from `CODE_START`. Bytes that cannot be decoded
+//! are skipped one at a time, so that decoding can recover and continue.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use executable::CODE_START;
+use instruction::Instr;
+
+#[derive(Debug, Copy, Clone)]
+pub struct DecodedInstr {
+    pub addr: u64,
+    /// `None` if the byte at `addr` does not start a valid instruction.
+    pub instr: Option<Instr>,
+}
+
+impl DecodedInstr {
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> u64 {
+        self.instr.map(|instr| instr.len()).unwrap_or(1)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LabelKind {
+    /// Program entry point.
+    Entry,
+    /// Target of a `call`.
+    Function,
+    /// Target of a jump.
+    Local,
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub kind: LabelKind,
+    pub name: String,
+}
+
+pub struct Disassembly {
+    pub instrs: Vec<DecodedInstr>,
+    pub labels: BTreeMap<u64, Label>,
+}
+
+impl Disassembly {
+    pub fn new(code: &[u8]) -> Disassembly {
+        let mut instrs = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let addr = CODE_START + offset as u64;
+            let decoded = DecodedInstr {
+                addr,
+                instr: Instr::decode(&code[offset..]),
+            };
+            offset += decoded.len() as usize;
+            instrs.push(decoded);
+        }
+
+        let mut kinds = BTreeMap::new();
+        kinds.insert(CODE_START, LabelKind::Entry);
+        for decoded in &instrs {
+            let instr = match decoded.instr {
+                Some(instr) => instr,
+                None => continue,
+            };
+            if let Some(target) = instr.branch_target(decoded.addr) {
+                // entry point jumps to `main`, which is a function as well
+                let kind = match instr {
+                    Instr::Call(_) => LabelKind::Function,
+                    Instr::Jmp(_) if decoded.addr == CODE_START => LabelKind::Function,
+                    _ => LabelKind::Local,
+                };
+                let entry = kinds.entry(target).or_insert(kind);
+                // function labels take precedence over local ones
+                if kind == LabelKind::Function && *entry == LabelKind::Local {
+                    *entry = kind;
+                }
+            }
+        }
+        let mut locals = 0;
+        let labels = kinds
+            .into_iter()
+            .map(|(addr, kind)| {
+                let name = match kind {
+                    LabelKind::Entry => "_start".to_string(),
+                    LabelKind::Function => format!("fn_{:#x}", addr),
+                    LabelKind::Local => {
+                        locals += 1;
+                        format!(".L{}", locals - 1)
+                    }
+                };
+                (addr, Label { kind, name })
+            })
+            .collect();
+
+        Disassembly { instrs, labels }
+    }
+
+    pub fn label(&self, addr: u64) -> Option<&str> {
+        self.labels.get(&addr).map(|label| label.name.as_str())
+    }
+
+    /// Finds the function (entry point or `call` target) that `addr`
+    /// belongs to, assuming that functions are laid out contiguously.
+    pub fn function_containing(&self, addr: u64) -> Option<(u64, &str)> {
+        self.labels
+            .range(..=addr)
+            .rev()
+            .find(|&(_, label)| label.kind != LabelKind::Local)
+            .map(|(&start, label)| (start, label.name.as_str()))
+    }
+
+    /// Index in `instrs` of the instruction starting at `addr`.
+    pub fn index_of(&self, addr: u64) -> Option<usize> {
+        self.instrs.binary_search_by_key(&addr, |decoded| decoded.addr).ok()
+    }
+
+    /// Formats the instruction, showing branch targets as absolute
+    /// addresses with their labels.
+    pub fn format_instr(&self, decoded: &DecodedInstr, code: &[u8]) -> String {
+        let instr = match decoded.instr {
+            Some(instr) => instr,
+            None => {
+                let byte = code[(decoded.addr - CODE_START) as usize];
+                return format!("(bad) {:#04x}", byte);
+            }
+        };
+        let mnemonic = match instr {
+            Instr::Call(_) => "call",
+            Instr::Jmp(_) => "jmp",
+            Instr::Jnz(_) => "jnz",
+            Instr::Jz(_) => "jz",
+            _ => return instr.to_string(),
+        };
+        let target = instr.branch_target(decoded.addr).unwrap();
+        match self.label(target) {
+            Some(label) => format!("{} {:#x} <{}>", mnemonic, target, label),
+            None => format!("{} {:#x}", mnemonic, target),
+        }
+    }
+
+    /// Writes a single line with address, raw bytes and instruction.
+    pub fn write_instr<W: Write>(&self, mut out: W, decoded: &DecodedInstr, code: &[u8]) -> io::Result<()> {
+        let start = (decoded.addr - CODE_START) as usize;
+        let end = start + decoded.len() as usize;
+        let mut bytes = String::new();
+        for byte in &code[start..end] {
+            bytes.push_str(&format!("{:02x} ", byte));
+        }
+        writeln!(out, "  {:#010x}:  {:<30} {}", decoded.addr, bytes, self.format_instr(decoded, code))
+    }
+
+    /// One-based line numbers of instructions in the output of `write`.
+    pub fn line_numbers(&self) -> Vec<usize> {
+        let mut line = 0;
+        let mut lines = Vec::with_capacity(self.instrs.len());
+        for (index, decoded) in self.instrs.iter().enumerate() {
+            if self.label(decoded.addr).is_some() {
+                line += if index > 0 { 2 } else { 1 };
+            }
+            line += 1;
+            lines.push(line);
+        }
+        lines
+    }
+
+    /// Writes the whole disassembly with labels.
+    pub fn write<W: Write>(&self, mut out: W, code: &[u8]) -> io::Result<()> {
+        for (index, decoded) in self.instrs.iter().enumerate() {
+            if let Some(label) = self.label(decoded.addr) {
+                if index > 0 {
+                    writeln!(out)?;
+                }
+                writeln!(out, "{}:", label)?;
+            }
+            self.write_instr(&mut out, decoded, code)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm::assemble;
+    use executable::CODE_START;
+    use super::{Disassembly, LabelKind};
+
+    #[test]
+    fn disassembly_is_written_with_labels() {
+        let exe = assemble("
+            jmp main
+        helper:
+            ret
+        main:
+            call helper
+        loop:
+            test rax, rax
+            jz loop
+            jnz main + 1
+            ret
+            .quad 0xff
+        ").unwrap();
+        let disassembly = Disassembly::new(&exe.code);
+        let mut text = Vec::new();
+        disassembly.write(&mut text, &exe.code).unwrap();
+        // `.L0` points into the middle of `call`, so it is only shown at
+        // the branch
+        let expected = "\
+_start:
+  0x10000000:  e9 01 00 00 00                 jmp 0x10000006 <fn_0x10000006>
+
+fn_0x10000005:
+  0x10000005:  c3                             ret
+
+fn_0x10000006:
+  0x10000006:  e8 fa ff ff ff                 call 0x10000005 <fn_0x10000005>
+
+.L1:
+  0x1000000b:  48 85 c0                       test rax, rax
+  0x1000000e:  0f 84 f7 ff ff ff              jz 0x1000000b <.L1>
+  0x10000014:  0f 85 ed ff ff ff              jnz 0x10000007 <.L0>
+  0x1000001a:  c3                             ret
+  0x1000001b:  ff                             (bad) 0xff
+  0x1000001c:  00                             (bad) 0x00
+  0x1000001d:  00                             (bad) 0x00
+  0x1000001e:  00                             (bad) 0x00
+  0x1000001f:  00                             (bad) 0x00
+  0x10000020:  00                             (bad) 0x00
+  0x10000021:  00                             (bad) 0x00
+  0x10000022:  00                             (bad) 0x00
+";
+        assert_eq!(String::from_utf8(text).unwrap(), expected);
+        assert_eq!(disassembly.line_numbers()[..6], [2, 5, 8, 11, 12, 13]);
+    }
+
+    #[test]
+    fn calls_take_precedence_over_jumps() {
+        let exe = assemble("
+            call f
+            jz f
+            jz g
+            jmp g
+        f:
+            ret
+        g:
+            ret
+        ").unwrap();
+        let disassembly = Disassembly::new(&exe.code);
+        let kinds = disassembly.labels.iter().map(|(&addr, label)| (addr, label.kind)).collect::<Vec<_>>();
+        assert_eq!(kinds, [
+            (CODE_START, LabelKind::Entry),
+            (CODE_START + 22, LabelKind::Function),
+            (CODE_START + 23, LabelKind::Local),
+        ]);
+        assert_eq!(disassembly.function_containing(CODE_START + 23), Some((CODE_START + 22, "fn_0x10000016")));
+        assert_eq!(disassembly.index_of(CODE_START + 5), Some(1));
+        assert_eq!(disassembly.index_of(CODE_START + 6), None);
+    }
+}
@@ -0,0 +1,519 @@
+//! Export of spark programs as static x86-64 Linux executables.
+//!
+//! The code section is prepared the same way as for native execution, see
+//! `native`, and is mapped at `CODE_START` with the stack and data at
+//! `STACK_START`. Syscall stubs jump to a small runtime instead of back to
+//! the emulator. The runtime translates spark syscalls into Linux `read`,
+//! `write` and `exit_group`, buffering output and flushing it before
+//! reading input and before exiting, like `Vm` does.
+//!
+//! Exported programs behave the same way as under `--native`, except that
+//! there is no interpreter to fall back to. Faults and jumps into code that
+//! was not found by exploring it kill the process with a signal instead of
+//! being reported, and invalid syscalls and output errors kill it with
+//! `SIGILL` after flushing output.
+
+use std::collections::HashMap;
+use std::fmt;
+use executable::{Exe, CODE_START, DATA_START, STACK_SIZE, STACK_START};
+use native::{round_to_pages, NativeError, NativeProgram, PAGE_SIZE};
+
+/// Where the runtime code is mapped.
+const RUNTIME_START: u64 = 0x40_0000;
+/// Where the runtime keeps its buffers.
+const RUNTIME_DATA: u64 = RUNTIME_START + 0x1_0000;
+/// Runtime variables, each a quad word at the start of `RUNTIME_DATA`.
+const OUT_LEN: u64 = RUNTIME_DATA;
+const IN_POS: u64 = RUNTIME_DATA + 8;
+const IN_LEN: u64 = RUNTIME_DATA + 16;
+const OUT_BUF: u64 = RUNTIME_DATA + 64;
+const IN_BUF: u64 = OUT_BUF + BUF_SIZE;
+const BUF_SIZE: u64 = 64 * 1024;
+const RUNTIME_DATA_SIZE: u64 = IN_BUF + BUF_SIZE - RUNTIME_DATA;
+
+const HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_E551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+#[derive(Debug)]
+pub enum ExportError {
+    Native(NativeError),
+    /// Syscall at the address has no room before it for a jump to the
+    /// runtime.
+    UnpatchableSyscall(u64),
+    TooManySyscalls,
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExportError::Native(ref e) => write!(f, "{}", e),
+            ExportError::UnpatchableSyscall(addr) => write!(
+                f,
+                "syscall at {:#x} is a jump target or follows one too closely, and cannot be exported",
+                addr,
+            ),
+            ExportError::TooManySyscalls => write!(f, "program has too many syscalls to be exported"),
+        }
+    }
+}
+
+impl From<NativeError> for ExportError {
+    fn from(err: NativeError) -> Self {
+        ExportError::Native(err)
+    }
+}
+
+struct Segment<'a> {
+    kind: u32,
+    flags: u32,
+    addr: u64,
+    bytes: &'a [u8],
+    /// Size in memory, the part after `bytes` is filled with zeros.
+    size: u64,
+}
+
+impl<'a> Segment<'a> {
+    fn load(flags: u32, addr: u64, bytes: &'a [u8]) -> Segment<'a> {
+        Segment { kind: PT_LOAD, flags, addr, bytes, size: bytes.len() as u64 }
+    }
+
+    fn zeroed(flags: u32, addr: u64, size: u64) -> Segment<'a> {
+        Segment { kind: PT_LOAD, flags, addr, bytes: &[], size }
+    }
+}
+
+/// Builds a static ELF executable that runs `exe` directly on x86-64 Linux.
+pub fn export(exe: &Exe) -> Result<Vec<u8>, ExportError> {
+    let (runtime, syscall_entry) = runtime();
+    let program = NativeProgram::new(&exe.code, RUNTIME_START + syscall_entry as u64)?;
+    if let Some(&addr) = program.trapped_syscalls().first() {
+        return Err(ExportError::UnpatchableSyscall(addr));
+    }
+    if program.stubs_start() < RUNTIME_DATA + RUNTIME_DATA_SIZE {
+        return Err(ExportError::TooManySyscalls);
+    }
+
+    let rw = PF_R | PF_W;
+    let rx = PF_R | PF_X;
+    let mut segments = vec![
+        Segment::load(rx, RUNTIME_START, &runtime),
+        Segment::zeroed(rw, RUNTIME_DATA, RUNTIME_DATA_SIZE),
+    ];
+    if !program.stubs().is_empty() {
+        segments.push(Segment::load(rx, program.stubs_start(), program.stubs()));
+    }
+    segments.push(Segment::load(rx, CODE_START, program.code()));
+    segments.push(Segment::zeroed(rw, STACK_START, STACK_SIZE));
+    if !exe.data.is_empty() {
+        segments.push(Segment::load(rw, DATA_START, &exe.data));
+    }
+    // marks the host stack as not executable, even though it is not used
+    segments.push(Segment { kind: PT_GNU_STACK, ..Segment::zeroed(rw, 0, 0) });
+    Ok(write_elf(&segments, RUNTIME_START))
+}
+
+fn write_elf(segments: &[Segment], entry: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(b"\x7FELF");
+    // 64-bit, little endian, version 1, System V ABI
+    out.extend_from_slice(&[2, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&2u16.to_le_bytes()); // executable
+    out.extend_from_slice(&62u16.to_le_bytes()); // x86-64
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&entry.to_le_bytes());
+    out.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes()); // no section headers
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+    out.extend_from_slice(&[0; 6]);
+
+    // contents start on page boundaries, so that file offsets and addresses
+    // agree modulo the page size
+    let mut offset = HEADER_SIZE + PROGRAM_HEADER_SIZE * segments.len() as u64;
+    let mut offsets = Vec::new();
+    for segment in segments {
+        if segment.bytes.is_empty() {
+            offsets.push(0);
+        } else {
+            offset = round_to_pages(offset);
+            offsets.push(offset);
+            offset += segment.bytes.len() as u64;
+        }
+    }
+    for (segment, &offset) in segments.iter().zip(&offsets) {
+        out.extend_from_slice(&segment.kind.to_le_bytes());
+        out.extend_from_slice(&segment.flags.to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&segment.addr.to_le_bytes());
+        out.extend_from_slice(&segment.addr.to_le_bytes());
+        out.extend_from_slice(&(segment.bytes.len() as u64).to_le_bytes());
+        out.extend_from_slice(&segment.size.to_le_bytes());
+        let align = if segment.kind == PT_LOAD { PAGE_SIZE } else { 16 };
+        out.extend_from_slice(&align.to_le_bytes());
+    }
+    for (segment, &offset) in segments.iter().zip(&offsets) {
+        if !segment.bytes.is_empty() {
+            out.resize(offset as usize, 0);
+            out.extend_from_slice(segment.bytes);
+        }
+    }
+    out
+}
+
+/// Machine code with forward references to labels. `ExeBuilder` only
+/// encodes spark instructions, while the runtime also needs the other
+/// registers and Linux syscalls.
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    labels: HashMap<&'static str, usize>,
+    /// Positions of 32-bit offsets relative to the end of the instruction,
+    /// with the label they point to.
+    fixups: Vec<(usize, &'static str)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Emits an instruction that ends with a 32-bit address or immediate.
+    fn emit_u32(&mut self, bytes: &[u8], value: u64) {
+        self.emit(bytes);
+        self.code.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    /// Emits an instruction that ends with a 32-bit offset to `label`.
+    fn emit_rel(&mut self, bytes: &[u8], label: &'static str) {
+        self.emit(bytes);
+        self.fixups.push((self.code.len(), label));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    fn bind(&mut self, label: &'static str) {
+        self.labels.insert(label, self.code.len());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for &(pos, label) in &self.fixups {
+            let target = self.labels[label];
+            let offset = target.wrapping_sub(pos + 4) as u32;
+            self.code[pos..pos + 4].copy_from_slice(&offset.to_le_bytes());
+        }
+        self.code
+    }
+}
+
+/// Assembles the runtime, returning its code, which starts with the entry
+/// point, and the offset of the syscall entry.
+///
+/// Syscalls are entered with `r11` pointing to the spark `syscall`
+/// instruction. The runtime only changes `rbx` and the registers that
+/// spark programs do not use, and never touches the program's stack, so
+/// it can keep its state in `r8`-`r13` while running. Routines that are
+/// used from several places return to the address in `r10`.
+fn runtime() -> (Vec<u8>, usize) {
+    let mut asm = Assembler::default();
+    let je = [0x0F, 0x84];
+    let jmp = [0xE9];
+    let lea_r10_rip = [0x4C, 0x8D, 0x15];
+    let syscall = [0x0F, 0x05];
+
+    asm.bind("start");
+    asm.emit(&[0x31, 0xC0]); // xor eax, eax
+    asm.emit(&[0x31, 0xDB]); // xor ebx, ebx
+    asm.emit(&[0x31, 0xD2]); // xor edx, edx
+    asm.emit(&[0x31, 0xED]); // xor ebp, ebp
+    asm.emit_u32(&[0xBC], STACK_START + STACK_SIZE); // mov esp, imm32
+    asm.emit(&[0x48, 0x85, 0xE4]); // test rsp, rsp: clears zero and below flags
+    asm.emit_u32(&[0xB9], CODE_START); // mov ecx, imm32
+    asm.emit(&[0xFF, 0xE1]); // jmp rcx
+
+    asm.bind("syscall");
+    asm.emit(&[0x49, 0x89, 0xC0]); // mov r8, rax
+    asm.emit(&[0x49, 0x89, 0xD1]); // mov r9, rdx
+    asm.emit(&[0x9F]); // lahf
+    asm.emit(&[0x49, 0x89, 0xC4]); // mov r12, rax
+    asm.emit(&[0x4D, 0x8D, 0x6B, 0x02]); // lea r13, [r11 + 2]
+    asm.emit(&[0x49, 0x83, 0xF8, 0x02]); // cmp r8, 2
+    asm.emit_rel(&je, "write_byte");
+    asm.emit(&[0x49, 0x83, 0xF8, 0x01]); // cmp r8, 1
+    asm.emit_rel(&je, "read_byte");
+    asm.emit(&[0x4D, 0x85, 0xC0]); // test r8, r8
+    asm.emit_rel(&je, "exit");
+    asm.emit_rel(&lea_r10_rip, "fail");
+    asm.emit_rel(&jmp, "flush");
+
+    asm.bind("resume");
+    asm.emit(&[0x4C, 0x89, 0xE0]); // mov rax, r12
+    asm.emit(&[0x9E]); // sahf
+    asm.emit(&[0x4C, 0x89, 0xC0]); // mov rax, r8
+    asm.emit(&[0x4C, 0x89, 0xCA]); // mov rdx, r9
+    asm.emit(&[0x41, 0xFF, 0xE5]); // jmp r13
+
+    asm.bind("write_byte");
+    asm.emit_u32(&[0x48, 0x8B, 0x0C, 0x25], OUT_LEN); // mov rcx, [OUT_LEN]
+    asm.emit_u32(&[0x88, 0x99], OUT_BUF); // mov [rcx + OUT_BUF], bl
+    asm.emit(&[0x48, 0xFF, 0xC1]); // inc rcx
+    asm.emit_u32(&[0x48, 0x89, 0x0C, 0x25], OUT_LEN); // mov [OUT_LEN], rcx
+    asm.emit_u32(&[0x48, 0x81, 0xF9], BUF_SIZE); // cmp rcx, BUF_SIZE
+    asm.emit_rel(&[0x0F, 0x85], "resume"); // jne
+    asm.emit_rel(&lea_r10_rip, "resume");
+    asm.emit_rel(&jmp, "flush");
+
+    asm.bind("read_byte");
+    asm.emit_rel(&lea_r10_rip, "read_flushed");
+    asm.emit_rel(&jmp, "flush");
+    asm.bind("read_flushed");
+    asm.emit_u32(&[0x48, 0x8B, 0x0C, 0x25], IN_POS); // mov rcx, [IN_POS]
+    asm.emit_u32(&[0x48, 0x3B, 0x0C, 0x25], IN_LEN); // cmp rcx, [IN_LEN]
+    asm.emit_rel(&[0x0F, 0x82], "read_buffered"); // jb
+    asm.bind("refill");
+    asm.emit(&[0x31, 0xFF]); // xor edi, edi: stdin
+    asm.emit_u32(&[0xBE], IN_BUF); // mov esi, IN_BUF
+    asm.emit_u32(&[0xBA], BUF_SIZE); // mov edx, BUF_SIZE
+    asm.emit(&[0x31, 0xC0]); // xor eax, eax: read
+    asm.emit(&syscall);
+    asm.emit(&[0x48, 0x83, 0xF8, 0xFC]); // cmp rax, -EINTR
+    asm.emit_rel(&je, "refill");
+    asm.emit(&[0x48, 0x85, 0xC0]); // test rax, rax
+    asm.emit_rel(&[0x0F, 0x8C], "fail"); // jl
+    asm.emit_rel(&je, "end_of_input");
+    asm.emit_u32(&[0x48, 0x89, 0x04, 0x25], IN_LEN); // mov [IN_LEN], rax
+    asm.emit(&[0x31, 0xC9]); // xor ecx, ecx
+    asm.bind("read_buffered");
+    asm.emit_u32(&[0x0F, 0xB6, 0x99], IN_BUF); // movzx ebx, byte [rcx + IN_BUF]
+    asm.emit(&[0x48, 0xFF, 0xC1]); // inc rcx
+    asm.emit_u32(&[0x48, 0x89, 0x0C, 0x25], IN_POS); // mov [IN_POS], rcx
+    asm.emit_rel(&jmp, "resume");
+    asm.bind("end_of_input");
+    asm.emit_u32(&[0xBB], 256); // mov ebx, 256
+    asm.emit_rel(&jmp, "resume");
+
+    asm.bind("exit");
+    asm.emit_rel(&lea_r10_rip, "exit_flushed");
+    asm.emit_rel(&jmp, "flush");
+    asm.bind("exit_flushed");
+    // exit codes above 255 become 255, like in `spark-emu`
+    asm.emit_u32(&[0xBF], 255); // mov edi, 255
+    asm.emit_u32(&[0x48, 0x81, 0xFB], 255); // cmp rbx, 255
+    asm.emit(&[0x48, 0x0F, 0x46, 0xFB]); // cmovbe rdi, rbx
+    asm.emit_u32(&[0xB8], 231); // mov eax, exit_group
+    asm.emit(&syscall);
+
+    asm.bind("flush");
+    asm.emit_u32(&[0xBE], OUT_BUF); // mov esi, OUT_BUF
+    asm.emit_u32(&[0x48, 0x8B, 0x14, 0x25], OUT_LEN); // mov rdx, [OUT_LEN]
+    asm.bind("flush_next");
+    asm.emit(&[0x48, 0x85, 0xD2]); // test rdx, rdx
+    asm.emit_rel(&je, "flushed");
+    asm.emit_u32(&[0xBF], 1); // mov edi, stdout
+    asm.emit_u32(&[0xB8], 1); // mov eax, write
+    asm.emit(&syscall);
+    asm.emit(&[0x48, 0x83, 0xF8, 0xFC]); // cmp rax, -EINTR
+    asm.emit_rel(&je, "flush_next");
+    asm.emit(&[0x48, 0x85, 0xC0]); // test rax, rax
+    asm.emit_rel(&[0x0F, 0x8E], "fail"); // jle
+    asm.emit(&[0x48, 0x01, 0xC6]); // add rsi, rax
+    asm.emit(&[0x48, 0x29, 0xC2]); // sub rdx, rax
+    asm.emit_rel(&jmp, "flush_next");
+    asm.bind("flushed");
+    asm.emit_u32(&[0x48, 0xC7, 0x04, 0x25], OUT_LEN); // mov qword [OUT_LEN], 0
+    asm.emit(&[0; 4]);
+    asm.emit(&[0x41, 0xFF, 0xE2]); // jmp r10
+
+    asm.bind("fail");
+    asm.emit(&[0x0F, 0x0B]); // ud2
+
+    let syscall_entry = asm.labels["syscall"];
+    (asm.finish(), syscall_entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use asm::assemble;
+    use executable::{CODE_START, DATA_START, STACK_START};
+    use super::{export, ExportError, PT_LOAD, RUNTIME_START};
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        let mut buf = [0; 2];
+        buf.copy_from_slice(&bytes[offset..offset + 2]);
+        u16::from_le_bytes(buf)
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_le_bytes(buf)
+    }
+
+    #[test]
+    fn sections_are_loaded_at_their_addresses() {
+        let exe = assemble("
+            mov rax, value
+            push qword [rax]
+            pop rbx
+            xor rax, rax
+            syscall
+        .data
+        value:
+            .quad 7
+        ").unwrap();
+        let elf = export(&exe).unwrap();
+        assert_eq!(elf[..4], *b"\x7FELF");
+        assert_eq!(u64_at(&elf, 24), RUNTIME_START);
+
+        // (address, file offset, size in file) of every loaded segment
+        let mut loaded = Vec::new();
+        for index in 0..u16_at(&elf, 56) as usize {
+            let header = &elf[64 + index * 56..];
+            if header[..4] == PT_LOAD.to_le_bytes() {
+                loaded.push((u64_at(header, 16), u64_at(header, 8), u64_at(header, 32)));
+            }
+        }
+        for &(addr, offset, _) in &loaded {
+            assert_eq!(addr % 4096, offset % 4096);
+        }
+        let contents = |addr| {
+            let &(_, offset, size) = loaded.iter().find(|segment| segment.0 == addr).unwrap();
+            &elf[offset as usize..(offset + size) as usize]
+        };
+        assert_eq!(contents(DATA_START), &exe.data[..]);
+        assert!(contents(STACK_START).is_empty());
+        // the syscall and the instructions before it are replaced with a
+        // jump to a stub
+        let code = contents(CODE_START);
+        let changed = (0..code.len()).find(|&i| code[i] != exe.code[i]).unwrap();
+        assert_eq!(code[changed], 0xE9);
+    }
+
+    #[test]
+    fn syscalls_at_jump_targets_are_rejected() {
+        let exe = assemble("
+            xor rax, rax
+            jmp exit
+        exit:
+            syscall
+        ").unwrap();
+        match export(&exe) {
+            Err(ExportError::UnpatchableSyscall(addr)) => assert_eq!(addr, CODE_START + 8),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    mod host {
+        use std::env;
+        use std::fs;
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::{Command, Stdio};
+        use asm::assemble;
+        use vm::{RunOutcome, Vm};
+        use super::super::export;
+
+        /// Exports program to a temporary file and runs it, returning its
+        /// exit code and output, after checking that they are the same as in
+        /// the interpreter.
+        fn run_exported(name: &str, source: &str, input: &[u8]) -> (i32, Vec<u8>) {
+            let exe = assemble(source).unwrap();
+            let path = env::temp_dir().join(format!("spark-emu-elf-{}-{}", name, std::process::id()));
+            fs::write(&path, export(&exe).unwrap()).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o700)).unwrap();
+            let mut child = Command::new(&path)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            let result = child.wait_with_output().unwrap();
+            fs::remove_file(&path).unwrap();
+            let code = result.status.code().expect("exported program was killed");
+
+            let mut input = input;
+            let mut output = Vec::new();
+            let expected_code = match Vm::new(exe, &mut input, &mut output, false).unwrap().run() {
+                RunOutcome::Exited(code) => code.min(255) as i32,
+                other => panic!("unexpected outcome: {:?}", other),
+            };
+            assert_eq!(code, expected_code);
+            assert_eq!(result.stdout, output);
+            (code, result.stdout)
+        }
+
+        #[test]
+        fn echo() {
+            // reads input until the end, writes it back and exits with the
+            // number of bytes, keeping flags and registers across syscalls
+            let input = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let (code, output) = run_exported("echo", "
+                xor rdx, rdx
+                mov rax, 256
+                test rax, rax
+            next:
+                mov rax, 1
+                syscall
+                jz next
+                push rbx
+                mov rax, 256
+                push rax
+                pop rbx
+                pop rax
+                cmp rax, rbx
+                jz done
+                push rax
+                pop rbx
+                mov rax, 2
+                syscall
+                mov rax, 1
+                push rax
+                pop rbx
+                push rdx
+                pop rax
+                add rax, rbx
+                push rax
+                pop rdx
+                jmp next
+            done:
+                push rdx
+                pop rbx
+                xor rax, rax
+                syscall
+            ", &input);
+            assert_eq!(code, 255);
+            assert!(output == input, "output differs");
+        }
+
+        #[test]
+        fn data_and_stack() {
+            let (code, output) = run_exported("data", "
+                mov rax, values
+                push qword [rax + 8]
+                pop rbx
+                mov rax, 2
+                syscall
+                mov rax, 8
+                mov rbx, [rsp + rax - 16]
+                mov rax, values
+                push qword [rax]
+                pop rbx
+                xor rax, rax
+                syscall
+            .data
+            values:
+                .quad 42, 65
+            ", &[]);
+            assert_eq!(code, 42);
+            assert_eq!(output, b"A");
+        }
+    }
+}
@@ -2,6 +2,7 @@ use std::fmt;
 use std::fs;
 use std::io::{self, prelude::*};
 use std::path::Path;
+use instruction::Instr;
 
 pub const CODE_START: u64 = 1024 * 1024 * 256;
 pub const DATA_START: u64 = 1024 * 1024 * 512;
@@ -70,7 +71,11 @@ impl fmt::Debug for Exe {
 
 impl Exe {
     pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Exe, ReadError> {
-        let mut file = fs::File::open(path)?;
+        let file = fs::File::open(path)?;
+        Exe::read(io::BufReader::new(file))
+    }
+
+    pub fn read<R: Read>(mut file: R) -> Result<Exe, ReadError> {
         let magic_string = read_quad_word(&mut file)?;
         if magic_string != MAGIC_STRING {
             return Err(ReadError::BadHeader);
@@ -89,6 +94,214 @@ impl Exe {
         file.read_exact(&mut data).map_err(convert_unexpected_eof)?;
         Ok(Exe { code, data })
     }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = fs::File::create(path)?;
+        let mut writer = io::BufWriter::new(file);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Writes the executable in the same format that `read` accepts.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&MAGIC_STRING)?;
+        writer.write_all(&u64_bytes(self.code.len() as u64))?;
+        writer.write_all(&u64_bytes(self.data.len() as u64))?;
+        writer.write_all(&self.code)?;
+        writer.write_all(&self.data)
+    }
+}
+
+/// Label created by `ExeBuilder::new_label`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug)]
+pub enum BuildError {
+    /// Branch targets a label that was never bound.
+    UnboundLabel(Label),
+    Io(io::Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::UnboundLabel(Label(index)) => write!(f, "label #{} is used but never bound", index),
+            BuildError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        BuildError::Io(err)
+    }
+}
+
+/// Builds an executable instruction by instruction.
+///
+/// Branches to labels that are not yet bound are emitted with a zero offset
+/// and patched once the label is bound, the same way `emit_jump_fix` in
+/// `shroom.shr` patches forward jumps.
+///
+/// ```
+/// use std::io;
+/// use spark_emu::{ExeBuilder, Instr, RunOutcome, Vm};
+///
+/// let mut builder = ExeBuilder::new();
+/// let exit_code = builder.data_quads(&[42]);
+/// let exit = builder.new_label();
+/// builder.emit_branch(Instr::Jmp, exit);
+/// builder.emit(Instr::Syscall); // skipped
+/// builder.bind(exit);
+/// builder.emit(Instr::MovRax(exit_code));
+/// builder.emit(Instr::PushQwordRax);
+/// builder.emit(Instr::PopRbx);
+/// builder.emit(Instr::XorRaxRax);
+/// builder.emit(Instr::Syscall);
+/// let exe = builder.finish().unwrap();
+///
+/// let (mut input, mut output) = (io::empty(), io::sink());
+/// let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+/// match vm.run() {
+///     RunOutcome::Exited(code) => assert_eq!(code, 42),
+///     other => panic!("unexpected outcome: {:?}", other),
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct ExeBuilder {
+    code: Vec<u8>,
+    data: Vec<u8>,
+    labels: Vec<Option<u64>>,
+    /// Branches waiting for their label to be bound, as position of the
+    /// offset in `code` and the label.
+    fixups: Vec<(usize, Label)>,
+}
+
+impl ExeBuilder {
+    pub fn new() -> ExeBuilder {
+        ExeBuilder::default()
+    }
+
+    /// Address of the next emitted instruction.
+    pub fn current_address(&self) -> u64 {
+        CODE_START + self.code.len() as u64
+    }
+
+    /// Appends an instruction and returns its address. Offsets of branches
+    /// are taken as is, use `emit_branch` to jump to a label.
+    pub fn emit(&mut self, instr: Instr) -> u64 {
+        let addr = self.current_address();
+        instr.encode(&mut self.code);
+        addr
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Binds the label to the current address and patches branches that
+    /// were already emitted to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the label is already bound, or if it is too far from a
+    /// branch for the offset to fit in 32 bits.
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "label is bound twice");
+        let addr = self.current_address();
+        self.labels[label.0] = Some(addr);
+        let code = &mut self.code;
+        self.fixups.retain(|&(pos, fixup_label)| {
+            if fixup_label != label {
+                return true;
+            }
+            let next_instr = CODE_START + pos as u64 + 4;
+            write_four_bytes(&mut code[pos..pos + 4], branch_offset(next_instr, addr));
+            false
+        });
+    }
+
+    pub fn label_address(&self, label: Label) -> Option<u64> {
+        self.labels[label.0]
+    }
+
+    /// Emits `call`, `jmp`, `jz` or `jnz` to the label, for example
+    /// `builder.emit_branch(Instr::Jz, label)`. Returns the address of the
+    /// branch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `branch` does not create a branch instruction, or if the
+    /// label is bound too far away for the offset to fit in 32 bits.
+    pub fn emit_branch(&mut self, branch: fn(u64) -> Instr, label: Label) -> u64 {
+        let addr = self.current_address();
+        let len = branch(0).len();
+        assert!(branch(0).branch_target(addr).is_some(), "not a branch instruction");
+        match self.labels[label.0] {
+            Some(target) => {
+                self.emit(branch(branch_offset(addr + len, target)));
+            }
+            None => {
+                self.emit(branch(0));
+                self.fixups.push((self.code.len() - 4, label));
+            }
+        }
+        addr
+    }
+
+    /// Reserves zero initialized quad words in data section and returns the
+    /// address of the first one.
+    pub fn reserve_data(&mut self, quads: usize) -> u64 {
+        let addr = DATA_START + self.data.len() as u64;
+        self.data.extend((0..quads * 8).map(|_| 0));
+        addr
+    }
+
+    /// Appends initialized quad words to data section and returns the
+    /// address of the first one.
+    pub fn data_quads(&mut self, values: &[u64]) -> u64 {
+        let addr = DATA_START + self.data.len() as u64;
+        for &value in values {
+            self.data.extend_from_slice(&u64_bytes(value));
+        }
+        addr
+    }
+
+    pub fn finish(self) -> Result<Exe, BuildError> {
+        if let Some(&(_, label)) = self.fixups.first() {
+            return Err(BuildError::UnboundLabel(label));
+        }
+        Ok(Exe {
+            code: self.code,
+            data: self.data,
+        })
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(self, path: P) -> Result<(), BuildError> {
+        self.finish()?.write_to_file(path)?;
+        Ok(())
+    }
+}
+
+/// Offset that a branch ending at `next_instr` needs to reach `target`.
+fn branch_offset(next_instr: u64, target: u64) -> u64 {
+    let offset = target.wrapping_sub(next_instr);
+    let signed = offset as i64;
+    assert!(
+        signed >= i64::from(i32::MIN) && signed <= i64::from(i32::MAX),
+        "branch from {:#x} to {:#x} does not fit in 32 bits",
+        next_instr,
+        target,
+    );
+    offset
+}
+
+fn write_four_bytes(buf: &mut [u8], value: u64) {
+    for (index, byte) in buf.iter_mut().enumerate() {
+        *byte = (value >> (index * 8)) as u8;
+    }
 }
 
 fn convert_unexpected_eof(err: io::Error) -> ReadError {
@@ -118,3 +331,95 @@ fn read_u64<R: Read>(reader: R) -> Result<u64, ReadError> {
             total
         })
 }
+
+fn u64_bytes(mut value: u64) -> [u8; 8] {
+    let mut buf = [0; 8];
+    for byte in buf.iter_mut() {
+        *byte = value as u8;
+        value >>= 8;
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use instruction::{encode_all, Instr};
+    use super::{branch_offset, BuildError, ExeBuilder, CODE_START};
+
+    #[test]
+    fn branches_to_bound_and_unbound_labels() {
+        let mut builder = ExeBuilder::new();
+        let start = builder.new_label();
+        let end = builder.new_label();
+        builder.bind(start);
+        assert_eq!(builder.emit_branch(Instr::Jz, end), CODE_START);
+        builder.emit(Instr::Ret);
+        builder.emit_branch(Instr::Call, start);
+        builder.emit_branch(Instr::Jnz, end);
+        builder.bind(end);
+        builder.emit_branch(Instr::Jmp, start);
+        assert_eq!(builder.label_address(end), Some(CODE_START + 18));
+        let exe = builder.finish().unwrap();
+        let expected = encode_all(&[
+            Instr::Jz(12),
+            Instr::Ret,
+            Instr::Call(-12i64 as u64),
+            Instr::Jnz(0),
+            Instr::Jmp(-23i64 as u64),
+        ]);
+        assert_eq!(exe.code, expected);
+    }
+
+    #[test]
+    fn data_addresses_follow_each_other() {
+        let mut builder = ExeBuilder::new();
+        let first = builder.data_quads(&[1, 0x0102_0304_0506_0708]);
+        let reserved = builder.reserve_data(2);
+        let last = builder.data_quads(&[3]);
+        assert_eq!((reserved - first, last - first), (16, 32));
+        let exe = builder.finish().unwrap();
+        assert_eq!(exe.data.len(), 40);
+        assert_eq!(&exe.data[8..16], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(exe.data[32], 3);
+    }
+
+    #[test]
+    fn unbound_label_is_an_error() {
+        let mut builder = ExeBuilder::new();
+        let label = builder.new_label();
+        builder.emit_branch(Instr::Jmp, label);
+        match builder.finish() {
+            Err(BuildError::UnboundLabel(unbound)) => assert_eq!(unbound, label),
+            other => panic!("unexpected result: {:?}", other.map(|exe| exe.code)),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "label is bound twice")]
+    fn binding_twice_panics() {
+        let mut builder = ExeBuilder::new();
+        let label = builder.new_label();
+        builder.bind(label);
+        builder.bind(label);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a branch instruction")]
+    fn emitting_non_branch_panics() {
+        let mut builder = ExeBuilder::new();
+        let label = builder.new_label();
+        builder.emit_branch(Instr::MovRax, label);
+    }
+
+    #[test]
+    fn branch_offsets_are_limited_to_32_bits() {
+        assert_eq!(branch_offset(CODE_START, CODE_START + 0x7fff_ffff), 0x7fff_ffff);
+        assert_eq!(branch_offset(CODE_START + 0x8000_0000, CODE_START), -0x8000_0000i64 as u64);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in 32 bits")]
+    fn too_long_branch_panics() {
+        branch_offset(CODE_START, CODE_START + 0x8000_0000);
+    }
+}
@@ -0,0 +1,723 @@
+//! Stub for the GDB remote serial protocol.
+//!
+//! Spark registers are exposed as x86-64 registers with the same names, and
+//! below and zero flags as CF and ZF bits of `eflags`. Registers that spark
+//! does not have read as zero, and writes to them are ignored. Instruction
+//! limit and deadline of `Vm` stop the program with `SIGXCPU` and
+//! `SIGALRM`. To connect:
+//!
+//! ```text
+//! (gdb) set architecture i386:x86-64
+//! (gdb) target remote localhost:1234
+//! ```
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use backtrace::{Backtrace, Fault};
+use vm::{ExecError, Registers, RunOutcome, Status, Vm};
+use watch::{Access, WatchHit, WatchKind, Watchpoint};
+
+/// Maximum number of instructions executed between checks for an interrupt
+/// request while continuing.
+const INTERRUPT_CHECK_INTERVAL: u64 = 0x10000;
+
+// signal numbers as defined by gdb, not by the host
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+const SIGSYS: u8 = 12;
+const SIGALRM: u8 = 14;
+const SIGXCPU: u8 = 24;
+
+/// Registers in the order gdb uses for x86-64 without a target description:
+/// 16 general purpose registers, rip, eflags, 6 segment registers, x87
+/// registers, x87 control registers, xmm0-15 and mxcsr.
+const REGISTER_COUNT: usize = 57;
+
+const RAX: usize = 0;
+const RBX: usize = 1;
+const RDX: usize = 3;
+const RBP: usize = 6;
+const RSP: usize = 7;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+
+const CARRY_FLAG: u64 = 1 << 0;
+const ZERO_FLAG: u64 = 1 << 6;
+/// Bit 1 of `eflags` is reserved and always set.
+const RESERVED_FLAG: u64 = 1 << 1;
+
+/// How the debugging session ended.
+#[derive(Debug)]
+pub enum SessionEnd {
+    /// Program exited or faulted and then gdb disconnected.
+    Finished(RunOutcome),
+    /// Gdb detached, and the program should keep running without it.
+    Detached,
+    /// Gdb killed the program or disconnected while it was still running.
+    Killed,
+}
+
+/// Serves a single gdb connection, controlling execution of `vm`.
+pub fn serve(vm: &mut Vm, stream: TcpStream) -> io::Result<SessionEnd> {
+    let mut stub = Stub {
+        vm,
+        stream,
+        breakpoints: HashSet::new(),
+        fault: None,
+        last_signal: SIGTRAP,
+        watch_hit: None,
+        limit_reached: None,
+    };
+    stub.serve()
+}
+
+struct Stub<'v, 'a: 'v> {
+    vm: &'v mut Vm<'a>,
+    stream: TcpStream,
+    breakpoints: HashSet<u64>,
+    /// Error that stopped the program most recently, if it is still stopped
+    /// there.
+    fault: Option<ExecError>,
+    last_signal: u8,
+    /// Watchpoint that stopped the program most recently.
+    watch_hit: Option<WatchHit>,
+    /// `OutOfFuel` or `TimedOut` outcome that stopped the program most
+    /// recently, if it is still stopped there.
+    limit_reached: Option<RunOutcome>,
+}
+
+impl<'v, 'a> Stub<'v, 'a> {
+    fn serve(&mut self) -> io::Result<SessionEnd> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(self.end(SessionEnd::Killed)),
+            };
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => self.stop_reply(),
+                Some(b'g') => self.read_registers(),
+                Some(b'G') => self.write_registers(&packet[1..]),
+                Some(b'p') => self.read_register(&packet[1..]),
+                Some(b'P') => self.write_register(&packet[1..]),
+                Some(b'm') => self.read_memory(&packet[1..]),
+                Some(b'Z') => self.set_breakpoint(&packet[1..], true),
+                Some(b'z') => self.set_breakpoint(&packet[1..], false),
+                Some(b'c') => self.resume(&packet[1..], false)?,
+                Some(b's') => self.resume(&packet[1..], true)?,
+                Some(b'k') => return Ok(self.end(SessionEnd::Killed)),
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    return Ok(self.end(SessionEnd::Detached));
+                }
+                Some(b'H') | Some(b'T') => "OK".to_string(),
+                _ => match packet.as_str() {
+                    "qAttached" => "1".to_string(),
+                    "qC" => "QC1".to_string(),
+                    "qfThreadInfo" => "m1".to_string(),
+                    "qsThreadInfo" => "l".to_string(),
+                    _ if packet.starts_with("qSupported") => "PacketSize=1000".to_string(),
+                    // empty reply means that packet is not supported
+                    _ => String::new(),
+                },
+            };
+            self.write_packet(&reply)?;
+        }
+    }
+
+    /// Program state takes precedence over how gdb wanted to end the session:
+    /// if the program is no longer able to run, it is reported as finished.
+    fn end(&mut self, requested: SessionEnd) -> SessionEnd {
+        if let Status::Halted(code) = self.vm.status() {
+            return SessionEnd::Finished(RunOutcome::Exited(code));
+        }
+        match (self.fault.take(), self.limit_reached.take()) {
+            (Some(e), _) => SessionEnd::Finished(RunOutcome::Faulted(Fault::new(self.vm, e))),
+            (None, Some(outcome)) => SessionEnd::Finished(outcome),
+            (None, None) => requested,
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.vm.status() {
+            Status::Halted(code) => format!("W{:02x}", code & 0xff),
+            Status::Running => match self.watch_hit {
+                Some(hit) => {
+                    let kind = match hit.access {
+                        Access::Read => "rwatch",
+                        Access::Write => "watch",
+                    };
+                    format!("T{:02x}{}:{:x};", SIGTRAP, kind, hit.addr)
+                }
+                None => format!("S{:02x}", self.last_signal),
+            },
+        }
+    }
+
+    fn resume(&mut self, args: &str, single_step: bool) -> io::Result<String> {
+        if !args.is_empty() {
+            match u64::from_str_radix(args, 16) {
+                Ok(addr) => {
+                    let mut regs = self.vm.registers();
+                    regs.rip = addr;
+                    self.vm.set_registers(regs);
+                }
+                Err(_) => return Ok("E01".to_string()),
+            }
+        }
+        self.fault = None;
+        self.watch_hit = None;
+        self.limit_reached = None;
+        // the first instruction is executed even if there is a breakpoint
+        // on it, as that is where the program stopped
+        self.last_signal = match self.step() {
+            Some(signal) => signal,
+            None if single_step || self.breakpoints.contains(&self.vm.rip()) => SIGTRAP,
+            None => self.run()?,
+        };
+        Ok(self.stop_reply())
+    }
+
+    /// Executes a single instruction. Returns the signal to stop with if
+    /// the program cannot continue or triggered a watchpoint.
+    fn step(&mut self) -> Option<u8> {
+        if let Some(limit) = self.vm.max_instructions() {
+            if self.vm.instruction_count() >= limit && self.vm.status() == Status::Running {
+                return Some(self.stop_signal(RunOutcome::OutOfFuel(Backtrace::new(self.vm))));
+            }
+        }
+        match self.vm.cycle() {
+            Ok(Status::Running) => {
+                let hit = *self.vm.take_watch_hits().first()?;
+                self.watch_hit = Some(hit);
+                Some(SIGTRAP)
+            }
+            Ok(Status::Halted(_)) => Some(SIGTRAP),
+            Err(e) => {
+                let signal = signal_for(&e);
+                self.fault = Some(e);
+                Some(signal)
+            }
+        }
+    }
+
+    /// Runs the program with breakpoints set by gdb until it stops, in
+    /// slices of at most `INTERRUPT_CHECK_INTERVAL` instructions so that
+    /// gdb can interrupt it in between.
+    fn run(&mut self) -> io::Result<u8> {
+        for &addr in &self.breakpoints {
+            self.vm.add_breakpoint(addr);
+        }
+        let result = loop {
+            match self.vm.run_for(INTERRUPT_CHECK_INTERVAL) {
+                Some(outcome) => break Ok(self.stop_signal(outcome)),
+                None => match self.interrupt_requested() {
+                    Ok(true) => break Ok(SIGINT),
+                    Ok(false) => {}
+                    Err(e) => break Err(e),
+                },
+            }
+        };
+        for &addr in &self.breakpoints {
+            self.vm.remove_breakpoint(addr);
+        }
+        result
+    }
+
+    /// Records why `run` stopped and returns the signal to report.
+    fn stop_signal(&mut self, outcome: RunOutcome) -> u8 {
+        match outcome {
+            RunOutcome::Exited(_) | RunOutcome::Paused(_) => SIGTRAP,
+            RunOutcome::Watchpoint(hits) => {
+                self.watch_hit = hits.first().cloned();
+                SIGTRAP
+            }
+            RunOutcome::Faulted(fault) => {
+                let signal = signal_for(&fault.error);
+                self.fault = Some(fault.error);
+                signal
+            }
+            RunOutcome::OutOfFuel(_) => {
+                self.limit_reached = Some(outcome);
+                SIGXCPU
+            }
+            RunOutcome::TimedOut(_) => {
+                self.limit_reached = Some(outcome);
+                SIGALRM
+            }
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let regs = self.vm.registers();
+        let mut reply = String::new();
+        for number in 0..REGISTER_COUNT {
+            push_register(&mut reply, number, register_value(&regs, number));
+        }
+        reply
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let mut regs = self.vm.registers();
+        let mut offset = 0;
+        for number in 0..REGISTER_COUNT {
+            let size = register_size(number).unwrap() * 2;
+            if offset + size > data.len() {
+                break;
+            }
+            match parse_register(&data[offset..offset + size]) {
+                Some(value) => set_register_value(&mut regs, number, value),
+                None => return "E01".to_string(),
+            }
+            offset += size;
+        }
+        self.vm.set_registers(regs);
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let number = match usize::from_str_radix(args, 16) {
+            Ok(number) if number < REGISTER_COUNT => number,
+            _ => return "E01".to_string(),
+        };
+        let mut reply = String::new();
+        push_register(&mut reply, number, register_value(&self.vm.registers(), number));
+        reply
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let number = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+        let value = parts.next().and_then(parse_register);
+        match (number, value) {
+            (Some(number), Some(value)) if number < REGISTER_COUNT => {
+                let mut regs = self.vm.registers();
+                set_register_value(&mut regs, number, value);
+                self.vm.set_registers(regs);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_address_length(args) {
+            Some(parsed) => parsed,
+            None => return "E01".to_string(),
+        };
+        let mut reply = String::new();
+        for index in 0..len {
+            match self.vm.load_byte(addr.wrapping_add(index)) {
+                Some(byte) => reply.push_str(&format!("{:02x}", byte)),
+                None => break,
+            }
+        }
+        // partial reads are allowed, but reading nothing is an error
+        if reply.is_empty() && len > 0 {
+            return "E14".to_string();
+        }
+        reply
+    }
+
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        // software breakpoints and write, read and access watchpoints
+        let watch_kind = match args.get(..2) {
+            Some("0,") => None,
+            Some("2,") => Some(WatchKind::Write),
+            Some("3,") => Some(WatchKind::Read),
+            Some("4,") => Some(WatchKind::ReadWrite),
+            _ => return String::new(),
+        };
+        let addr = match parse_address_length(&args[2..]) {
+            Some((addr, _)) => addr,
+            None => return "E01".to_string(),
+        };
+        match watch_kind {
+            None if insert => {
+                self.breakpoints.insert(addr);
+            }
+            None => {
+                self.breakpoints.remove(&addr);
+            }
+            Some(kind) if insert => {
+                // spark accesses memory only as aligned quad words
+                let addr = addr & !7;
+                self.vm.add_watchpoint(Watchpoint { addr, kind, value: None });
+            }
+            Some(_) => self.vm.remove_watchpoint(addr & !7),
+        }
+        "OK".to_string()
+    }
+
+    /// Reads packet data, acknowledging it. Returns `None` if gdb closed the
+    /// connection.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acks and interrupts sent while the program was stopped
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            // checksum covers the data as sent, with escapes
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = ::std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+            if expected != Some(packet_checksum(&data)) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let data = escape(data.as_bytes());
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{:02x}", packet_checksum(&data)).as_bytes());
+        loop {
+            self.stream.write_all(&packet)?;
+            // resend until gdb acknowledges the packet
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Checks without blocking whether gdb sent an interrupt (Ctrl-C).
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            // stop if gdb disconnected, the session will end after the stop
+            // reply fails to be acknowledged
+            Ok(0) => Ok(true),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn signal_for(error: &ExecError) -> u8 {
+    match *error {
+        ExecError::MisalignedDataAccess(_) | ExecError::MisalignedStack(_) => SIGBUS,
+        ExecError::BadDataAccess(_) | ExecError::BadCodeRead(_) => SIGSEGV,
+        ExecError::InvalidInstruction(_) => SIGILL,
+        ExecError::BadDivide | ExecError::DivByZero => SIGFPE,
+        ExecError::InvalidSyscall(_) => SIGSYS,
+        ExecError::Io(_) => SIGABRT,
+    }
+}
+
+fn register_size(number: usize) -> Option<usize> {
+    match number {
+        0..=16 => Some(8),
+        17..=23 => Some(4),
+        24..=31 => Some(10),
+        32..=39 => Some(4),
+        40..=55 => Some(16),
+        56 => Some(4),
+        _ => None,
+    }
+}
+
+fn register_value(regs: &Registers, number: usize) -> u64 {
+    match number {
+        RAX => regs.rax,
+        RBX => regs.rbx,
+        RDX => regs.rdx,
+        RBP => regs.rbp,
+        RSP => regs.rsp,
+        RIP => regs.rip,
+        EFLAGS => {
+            let mut flags = RESERVED_FLAG;
+            if regs.below_flag {
+                flags |= CARRY_FLAG;
+            }
+            if regs.zero_flag {
+                flags |= ZERO_FLAG;
+            }
+            flags
+        }
+        _ => 0,
+    }
+}
+
+fn set_register_value(regs: &mut Registers, number: usize, value: u64) {
+    match number {
+        RAX => regs.rax = value,
+        RBX => regs.rbx = value,
+        RDX => regs.rdx = value,
+        RBP => regs.rbp = value,
+        RSP => regs.rsp = value,
+        RIP => regs.rip = value,
+        EFLAGS => {
+            regs.below_flag = value & CARRY_FLAG != 0;
+            regs.zero_flag = value & ZERO_FLAG != 0;
+        }
+        _ => {}
+    }
+}
+
+/// Appends register value in target byte order (little endian).
+fn push_register(out: &mut String, number: usize, value: u64) {
+    for index in 0..register_size(number).unwrap() {
+        let byte = if index < 8 { (value >> (index * 8)) as u8 } else { 0 };
+        out.push_str(&format!("{:02x}", byte));
+    }
+}
+
+/// Parses little endian register value. Bytes beyond the lowest 8 are
+/// ignored.
+fn parse_register(hex: &str) -> Option<u64> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let mut value = 0;
+    for index in 0..hex.len() / 2 {
+        let byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+        if index < 8 {
+            value |= u64::from(byte) << (index * 8);
+        }
+    }
+    Some(value)
+}
+
+fn parse_address_length(args: &str) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let len = u64::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Escapes bytes that have a special meaning in packets as `}` followed by
+/// the byte xored with 0x20.
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+            _ => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => match bytes.next() {
+                Some(&escaped) => unescaped.push(escaped ^ 0x20),
+                None => unescaped.push(byte),
+            },
+            _ => unescaped.push(byte),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use asm::assemble;
+    use vm::{RunOutcome, Vm};
+    use super::{escape, packet_checksum, serve, unescape, SessionEnd};
+
+    #[test]
+    fn checksum_is_sum_of_bytes_modulo_256() {
+        assert_eq!(packet_checksum(b""), 0);
+        assert_eq!(packet_checksum(b"OK"), 0x9a);
+        assert_eq!(packet_checksum(b"qSupported"), 0x37);
+        assert_eq!(packet_checksum(&[0xff, 0xff, 0x03]), 0x01);
+    }
+
+    #[test]
+    fn special_bytes_are_escaped() {
+        assert_eq!(escape(b"a$b#c}d*e"), b"a}\x04b}\x03c}]d}\x0ae".to_vec());
+        assert_eq!(unescape(b"a}\x04b}\x03c}]d}\x0ae"), b"a$b#c}d*e".to_vec());
+        assert_eq!(unescape(b"OK"), b"OK".to_vec());
+        // dangling escape character is kept as is
+        assert_eq!(unescape(b"x}"), b"x}".to_vec());
+        for byte in 0..=255u8 {
+            assert_eq!(unescape(&escape(&[byte])), [byte]);
+        }
+    }
+
+    fn send(stream: &mut TcpStream, data: &[u8], checksum: u8) {
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        stream.write_all(&packet).unwrap();
+    }
+
+    /// Sends a packet with the given raw data and returns the acknowledgment
+    /// and the reply packet, acknowledging it.
+    fn exchange(stream: &mut TcpStream, data: &[u8]) -> String {
+        send(stream, data, packet_checksum(data));
+        receive(stream)
+    }
+
+    /// Reads the acknowledgment and the reply packet, acknowledging it.
+    fn receive(stream: &mut TcpStream) -> String {
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while !reply.ends_with(b"#") {
+            stream.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        reply.extend_from_slice(&checksum);
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn session_over_tcp() {
+        let exe = assemble("
+            mov rax, 0x7d7d
+            xor rax, rax
+            syscall
+        ").unwrap();
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut replies = Vec::new();
+            replies.push(exchange(&mut stream, b"?"));
+            // packet with a bad checksum is rejected
+            send(&mut stream, b"s", 0);
+            let mut ack = [0];
+            stream.read_exact(&mut ack).unwrap();
+            replies.push(String::from_utf8(ack.to_vec()).unwrap());
+            replies.push(exchange(&mut stream, b"s"));
+            // `p0` with the `0` escaped reads rax
+            replies.push(exchange(&mut stream, b"p}\x10"));
+            replies.push(exchange(&mut stream, b"c"));
+            send(&mut stream, b"k", packet_checksum(b"k"));
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        match serve(&mut vm, stream).unwrap() {
+            SessionEnd::Finished(RunOutcome::Exited(0)) => {}
+            other => panic!("unexpected end: {:?}", other),
+        }
+        assert_eq!(client.join().unwrap(), [
+            "+$S05#b8",
+            "-",
+            "+$S05#b8",
+            "+$7d7d000000000000#76",
+            "+$W00#b7",
+        ]);
+    }
+
+    /// Serves a session for a client that sends `packets` and returns
+    /// how the session ended and the replies. Packets ending with `\x03`
+    /// are followed by an interrupt before waiting for the reply.
+    fn session<F>(vm: &mut Vm, packets: &'static [&'static [u8]], prepare: F) -> (SessionEnd, Vec<String>)
+    where
+        F: FnOnce(&mut Vm),
+    {
+        prepare(vm);
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut replies = Vec::new();
+            for &packet in packets {
+                match packet.split_last() {
+                    Some((&0x03, data)) => {
+                        send(&mut stream, data, packet_checksum(data));
+                        stream.write_all(&[0x03]).unwrap();
+                        replies.push(receive(&mut stream));
+                    }
+                    _ => replies.push(exchange(&mut stream, packet)),
+                }
+            }
+            send(&mut stream, b"k", packet_checksum(b"k"));
+            replies
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let end = serve(vm, stream).unwrap();
+        (end, client.join().unwrap())
+    }
+
+    const LOOP_PROGRAM: &str = "
+        mov rax, 1
+    loop:
+        test rax, rax
+        jnz loop
+        xor rax, rax
+        syscall
+    ";
+
+    #[test]
+    fn continue_stops_at_breakpoints() {
+        let exe = assemble(LOOP_PROGRAM).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        let packets: &[&[u8]] = &[b"Z0,1000000a,1", b"c", b"c", b"z0,1000000a,1", b"c\x03"];
+        let (end, replies) = session(&mut vm, packets, |_| {});
+        assert_eq!(replies, ["+$OK#9a", "+$S05#b8", "+$S05#b8", "+$OK#9a", "+$S02#b5"]);
+        // the loop was run by `Vm::run` after the breakpoint was removed
+        assert!(vm.instruction_count() >= 3 + 0x10000);
+        match end {
+            SessionEnd::Killed => {}
+            other => panic!("unexpected end: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn instruction_limit_stops_continue() {
+        let exe = assemble(LOOP_PROGRAM).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        let packets: &[&[u8]] = &[b"c", b"s"];
+        let (end, replies) = session(&mut vm, packets, |vm| vm.set_max_instructions(Some(100_000)));
+        assert_eq!(replies, ["+$S18#bc", "+$S18#bc"]);
+        assert_eq!(vm.instruction_count(), 100_000);
+        match end {
+            SessionEnd::Finished(RunOutcome::OutOfFuel(_)) => {}
+            other => panic!("unexpected end: {:?}", other),
+        }
+    }
+}
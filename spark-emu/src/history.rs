@@ -0,0 +1,191 @@
+//! Ring buffer of recently executed instructions.
+//!
+//! Keeping only the last few instructions is much cheaper than tracing the
+//! whole run, and usually enough to see how the program got to a crash.
+
+use std::fmt::Write;
+use instruction::Instr;
+use vm::Registers;
+
+#[derive(Debug, Copy, Clone)]
+pub struct HistoryEntry {
+    pub rip: u64,
+    pub instr: Instr,
+    /// Registers after the instruction was executed.
+    pub registers: Registers,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    /// Entries in a circular buffer, oldest at `next` once it is full.
+    entries: Vec<HistoryEntry>,
+    capacity: usize,
+    next: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    pub fn record(&mut self, rip: u64, instr: Instr, registers: Registers) {
+        let entry = HistoryEntry { rip, instr, registers };
+        if self.entries.len() < self.capacity {
+            self.entries.push(entry);
+        } else if self.capacity > 0 {
+            self.entries[self.next] = entry;
+            self.next = (self.next + 1) % self.capacity;
+        }
+    }
+
+    /// Entries from oldest to newest, each with a description of registers
+    /// it changed, like `rax = 0x5, zf = 1`. Changes made by the oldest
+    /// entry are not known.
+    pub fn entries_with_changes(&self) -> Vec<(&HistoryEntry, String)> {
+        let (newer, older) = self.entries.split_at(self.next);
+        let mut before: Option<&Registers> = None;
+        let mut result = Vec::new();
+        for entry in older.iter().chain(newer) {
+            let changes = match before {
+                Some(before) => describe_changes(before, &entry.registers),
+                None => String::new(),
+            };
+            result.push((entry, changes));
+            before = Some(&entry.registers);
+        }
+        result
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+fn describe_changes(before: &Registers, after: &Registers) -> String {
+    let mut changes = String::new();
+    let values = [
+        ("rax", before.rax, after.rax),
+        ("rbx", before.rbx, after.rbx),
+        ("rdx", before.rdx, after.rdx),
+        ("rsp", before.rsp, after.rsp),
+        ("rbp", before.rbp, after.rbp),
+    ];
+    for &(name, old, new) in &values {
+        if old != new {
+            separate(&mut changes);
+            write!(changes, "{} = {:#x}", name, new).unwrap();
+        }
+    }
+    let flags = [
+        ("bf", before.below_flag, after.below_flag),
+        ("zf", before.zero_flag, after.zero_flag),
+    ];
+    for &(name, old, new) in &flags {
+        if old != new {
+            separate(&mut changes);
+            write!(changes, "{} = {}", name, new as u8).unwrap();
+        }
+    }
+    changes
+}
+
+fn separate(changes: &mut String) {
+    if !changes.is_empty() {
+        changes.push_str(", ");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use asm::assemble;
+    use executable::CODE_START;
+    use instruction::Instr;
+    use vm::{Registers, RunOutcome, Vm};
+    use super::History;
+
+    fn registers(rax: u64) -> Registers {
+        Registers {
+            rip: CODE_START,
+            rax,
+            rbx: 0,
+            rdx: 0,
+            rsp: 0x1fff_fff0,
+            rbp: 0,
+            below_flag: false,
+            zero_flag: false,
+        }
+    }
+
+    #[test]
+    fn only_last_entries_are_kept() {
+        let mut history = History::new(3);
+        for rax in 0..5 {
+            history.record(CODE_START + rax, Instr::PushRax, registers(rax));
+        }
+        assert_eq!(history.len(), 3);
+        let entries = history.entries_with_changes();
+        let rips = entries.iter().map(|&(entry, _)| entry.rip).collect::<Vec<_>>();
+        assert_eq!(rips, [CODE_START + 2, CODE_START + 3, CODE_START + 4]);
+        let changes = entries.iter().map(|(_, changes)| changes.as_str()).collect::<Vec<_>>();
+        assert_eq!(changes, ["", "rax = 0x3", "rax = 0x4"]);
+    }
+
+    #[test]
+    fn zero_capacity_records_nothing() {
+        let mut history = History::new(0);
+        history.record(CODE_START, Instr::PushRax, registers(0));
+        assert_eq!(history.len(), 0);
+        assert!(history.entries_with_changes().is_empty());
+    }
+
+    #[test]
+    fn changed_registers_and_flags_are_described() {
+        let mut history = History::new(2);
+        let before = registers(1);
+        let mut after = before;
+        after.rbx = 0x20;
+        after.rbp = 0x1fff_ffe8;
+        after.zero_flag = true;
+        history.record(CODE_START, Instr::PushRax, before);
+        history.record(CODE_START + 1, Instr::PushRax, after);
+        let entries = history.entries_with_changes();
+        assert_eq!(entries[1].1, "rbx = 0x20, rbp = 0x1fffffe8, zf = 1");
+    }
+
+    #[test]
+    fn fault_includes_instructions_leading_to_it() {
+        let exe = assemble("
+            mov rax, 1
+            push rax
+            pop rbx
+            mov rax, 0x30000000
+            push qword [rax]
+        ").unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.set_history(3);
+        let fault = match vm.run() {
+            RunOutcome::Faulted(fault) => fault,
+            other => panic!("unexpected outcome: {:?}", other),
+        };
+        let history = fault.backtrace.history.expect("history was recorded");
+        let entries = history
+            .entries_with_changes()
+            .into_iter()
+            .map(|(entry, changes)| (entry.rip, entry.instr.to_string(), changes))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [
+            (CODE_START + 10, "push rax".to_string(), String::new()),
+            (CODE_START + 11, "pop rbx".to_string(), "rbx = 0x1, rsp = 0x20000000".to_string()),
+            (CODE_START + 12, "mov rax, 805306368".to_string(), "rax = 0x30000000".to_string()),
+        ]);
+        // registers are recorded after the instruction, including `rip`
+        assert_eq!(history.entries_with_changes()[2].0.registers.rip, CODE_START + 22);
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Instr {
     PopRax,
     PopRbx,
@@ -41,7 +41,93 @@ pub enum Instr {
     Syscall,
 }
 
+/// Names of instruction variants, indexed by `Instr::kind`.
+pub const INSTR_NAMES: [&str; 37] = [
+    "PopRax",
+    "PopRbx",
+    "PopRbp",
+    "PopRdx",
+    "PushRax",
+    "PushRbx",
+    "PushRbp",
+    "PushRdx",
+    "AddRaxRbx",
+    "SubRaxRbx",
+    "MulRbx",
+    "DivRbx",
+    "PushQwordRax",
+    "PushQwordRaxOffset",
+    "MovRaxRspOffset",
+    "MovRaxOffsetRbx",
+    "AddRsp",
+    "SubRsp",
+    "CmpRaxRbx",
+    "SeteDl",
+    "XorRaxRax",
+    "XorRdxRdx",
+    "SetneDl",
+    "SetbDl",
+    "MovRax",
+    "TestRaxRax",
+    "Call",
+    "Jmp",
+    "Jnz",
+    "Jz",
+    "Ret",
+    "LeaRaxRbpOffset",
+    "MovRbxRspRaxOffset",
+    "MovRspOffsetRbx",
+    "MovRaxQwordRsp",
+    "MovRbpRsp",
+    "Syscall",
+];
+
 impl Instr {
+    /// Index of the variant in `INSTR_NAMES`.
+    pub fn kind(&self) -> usize {
+        match *self {
+            Instr::PopRax => 0,
+            Instr::PopRbx => 1,
+            Instr::PopRbp => 2,
+            Instr::PopRdx => 3,
+            Instr::PushRax => 4,
+            Instr::PushRbx => 5,
+            Instr::PushRbp => 6,
+            Instr::PushRdx => 7,
+            Instr::AddRaxRbx => 8,
+            Instr::SubRaxRbx => 9,
+            Instr::MulRbx => 10,
+            Instr::DivRbx => 11,
+            Instr::PushQwordRax => 12,
+            Instr::PushQwordRaxOffset(_) => 13,
+            Instr::MovRaxRspOffset(_) => 14,
+            Instr::MovRaxOffsetRbx(_) => 15,
+            Instr::AddRsp(_) => 16,
+            Instr::SubRsp(_) => 17,
+            Instr::CmpRaxRbx => 18,
+            Instr::SeteDl => 19,
+            Instr::XorRaxRax => 20,
+            Instr::XorRdxRdx => 21,
+            Instr::SetneDl => 22,
+            Instr::SetbDl => 23,
+            Instr::MovRax(_) => 24,
+            Instr::TestRaxRax => 25,
+            Instr::Call(_) => 26,
+            Instr::Jmp(_) => 27,
+            Instr::Jnz(_) => 28,
+            Instr::Jz(_) => 29,
+            Instr::Ret => 30,
+            Instr::LeaRaxRbpOffset(_) => 31,
+            Instr::MovRbxRspRaxOffset(_) => 32,
+            Instr::MovRspOffsetRbx(_) => 33,
+            Instr::MovRaxQwordRsp => 34,
+            Instr::MovRbpRsp => 35,
+            Instr::Syscall => 36,
+        }
+    }
+
+    /// Length of the encoded instruction in bytes.
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u64 {
         match *self {
             Instr::PopRax => 1,
@@ -84,8 +170,18 @@ impl Instr {
         }
     }
 
+    /// Absolute target address of a `call` or a jump located at `addr`.
+    pub fn branch_target(&self, addr: u64) -> Option<u64> {
+        match *self {
+            Instr::Call(off) | Instr::Jmp(off) | Instr::Jnz(off) | Instr::Jz(off) => {
+                Some(addr.wrapping_add(self.len()).wrapping_add(off))
+            }
+            _ => None,
+        }
+    }
+
     pub fn decode(bytes: &[u8]) -> Option<Self> {
-        if bytes.len() >= 1 {
+        if !bytes.is_empty() {
             match bytes[0] {
                 0x58 => return Some(Instr::PopRax),
                 0x5B => return Some(Instr::PopRbx),
@@ -124,9 +220,8 @@ impl Instr {
             }
         }
         if bytes.len() >= 4 {
-            match (bytes[0], bytes[1], bytes[2], bytes[3]) {
-                (0x48, 0x8B, 0x04, 0x24) => return Some(Instr::MovRaxQwordRsp),
-                _ => {}
+            if let (0x48, 0x8B, 0x04, 0x24) = (bytes[0], bytes[1], bytes[2], bytes[3]) {
+                return Some(Instr::MovRaxQwordRsp);
             }
         }
         if bytes.len() >= 5 {
@@ -198,16 +293,99 @@ impl Instr {
             }
         }
         if bytes.len() >= 10 {
-            match (bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7], bytes[8], bytes[9]) {
-                (0x48, 0xB8, a0, a1, a2, a3, a4, a5, a6, a7) => {
-                    let arg = eight_byte(a7, a6, a5, a4, a3, a2, a1, a0);
-                    return Some(Instr::MovRax(arg));
-                }
-                _ => {}
+            if let (0x48, 0xB8) = (bytes[0], bytes[1]) {
+                let arg = eight_byte(&bytes[2..10]);
+                return Some(Instr::MovRax(arg));
             }
         }
         None
     }
+
+    /// Appends encoded instruction to `out`. Four byte offsets are truncated
+    /// to their lowest 32 bits, so that `decode` sign-extends them back to
+    /// the original value.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Instr::PopRax => out.push(0x58),
+            Instr::PopRbx => out.push(0x5B),
+            Instr::PopRbp => out.push(0x5D),
+            Instr::PopRdx => out.push(0x5A),
+            Instr::PushRax => out.push(0x50),
+            Instr::PushRbx => out.push(0x53),
+            Instr::PushRbp => out.push(0x55),
+            Instr::PushRdx => out.push(0x52),
+            Instr::AddRaxRbx => out.extend_from_slice(&[0x48, 0x01, 0xD8]),
+            Instr::SubRaxRbx => out.extend_from_slice(&[0x48, 0x29, 0xD8]),
+            Instr::MulRbx => out.extend_from_slice(&[0x48, 0xF7, 0xE3]),
+            Instr::DivRbx => out.extend_from_slice(&[0x48, 0xF7, 0xF3]),
+            Instr::PushQwordRax => out.extend_from_slice(&[0xFF, 0x30]),
+            Instr::PushQwordRaxOffset(o) => {
+                out.extend_from_slice(&[0xFF, 0xB0]);
+                push_four_bytes(out, o);
+            }
+            Instr::MovRaxRspOffset(o) => {
+                out.extend_from_slice(&[0x48, 0x8B, 0x84, 0x24]);
+                push_four_bytes(out, o);
+            }
+            Instr::MovRaxOffsetRbx(o) => {
+                out.extend_from_slice(&[0x48, 0x89, 0x98]);
+                push_four_bytes(out, o);
+            }
+            Instr::AddRsp(x) => {
+                out.extend_from_slice(&[0x48, 0x81, 0xC4]);
+                push_four_bytes(out, x);
+            }
+            Instr::SubRsp(x) => {
+                out.extend_from_slice(&[0x48, 0x81, 0xEC]);
+                push_four_bytes(out, x);
+            }
+            Instr::CmpRaxRbx => out.extend_from_slice(&[0x48, 0x39, 0xD8]),
+            Instr::SeteDl => out.extend_from_slice(&[0x0F, 0x94, 0xC2]),
+            Instr::XorRaxRax => out.extend_from_slice(&[0x48, 0x31, 0xC0]),
+            Instr::XorRdxRdx => out.extend_from_slice(&[0x48, 0x31, 0xD2]),
+            Instr::SetneDl => out.extend_from_slice(&[0x0F, 0x95, 0xC2]),
+            Instr::SetbDl => out.extend_from_slice(&[0x0F, 0x92, 0xC2]),
+            Instr::MovRax(x) => {
+                out.extend_from_slice(&[0x48, 0xB8]);
+                for i in 0..8 {
+                    out.push((x >> (i * 8)) as u8);
+                }
+            }
+            Instr::TestRaxRax => out.extend_from_slice(&[0x48, 0x85, 0xC0]),
+            Instr::Call(off) => {
+                out.push(0xE8);
+                push_four_bytes(out, off);
+            }
+            Instr::Jmp(off) => {
+                out.push(0xE9);
+                push_four_bytes(out, off);
+            }
+            Instr::Jnz(off) => {
+                out.extend_from_slice(&[0x0F, 0x85]);
+                push_four_bytes(out, off);
+            }
+            Instr::Jz(off) => {
+                out.extend_from_slice(&[0x0F, 0x84]);
+                push_four_bytes(out, off);
+            }
+            Instr::Ret => out.push(0xC3),
+            Instr::LeaRaxRbpOffset(o) => {
+                out.extend_from_slice(&[0x48, 0x8D, 0x85]);
+                push_four_bytes(out, o);
+            }
+            Instr::MovRbxRspRaxOffset(o) => {
+                out.extend_from_slice(&[0x48, 0x8B, 0x9C, 0x04]);
+                push_four_bytes(out, o);
+            }
+            Instr::MovRspOffsetRbx(o) => {
+                out.extend_from_slice(&[0x48, 0x89, 0x9C, 0x24]);
+                push_four_bytes(out, o);
+            }
+            Instr::MovRaxQwordRsp => out.extend_from_slice(&[0x48, 0x8B, 0x04, 0x24]),
+            Instr::MovRbpRsp => out.extend_from_slice(&[0x48, 0x89, 0xE5]),
+            Instr::Syscall => out.extend_from_slice(&[0x0F, 0x05]),
+        }
+    }
 }
 
 impl fmt::Display for Instr {
@@ -258,7 +436,7 @@ fn four_byte_sign_extend(a3: u8, a2: u8, a1: u8, a0: u8) -> u64 {
     let a3 = u64::from(a3) << 24;
     let a2 = u64::from(a2) << 16;
     let a1 = u64::from(a1) << 8;
-    let a0 = u64::from(a0) << 0;
+    let a0 = u64::from(a0);
     let total = a3 | a2 | a1 | a0;
     if total & (1 << 31) == 0 {
         total
@@ -267,14 +445,144 @@ fn four_byte_sign_extend(a3: u8, a2: u8, a1: u8, a0: u8) -> u64 {
     }
 }
 
-fn eight_byte(a7: u8, a6: u8, a5: u8, a4: u8, a3: u8, a2: u8, a1: u8, a0: u8) -> u64 {
-    let a7 = u64::from(a7) << 56;
-    let a6 = u64::from(a6) << 48;
-    let a5 = u64::from(a5) << 40;
-    let a4 = u64::from(a4) << 32;
-    let a3 = u64::from(a3) << 24;
-    let a2 = u64::from(a2) << 16;
-    let a1 = u64::from(a1) << 8;
-    let a0 = u64::from(a0) << 0;
-    a7 | a6 | a5 | a4 | a3 | a2 | a1 | a0
+fn push_four_bytes(out: &mut Vec<u8>, value: u64) {
+    for i in 0..4 {
+        out.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn eight_byte(bytes: &[u8]) -> u64 {
+    let mut total = 0;
+    for &byte in bytes[..8].iter().rev() {
+        total = (total << 8) | u64::from(byte);
+    }
+    total
+}
+
+/// Encodes instructions one after another.
+#[cfg(test)]
+pub fn encode_all(instrs: &[Instr]) -> Vec<u8> {
+    let mut code = Vec::new();
+    for instr in instrs {
+        instr.encode(&mut code);
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::Instr;
+
+    const OFFSETS: [u64; 7] = [
+        0,
+        8,
+        0x1234_5678,
+        0x7FFF_FFFF,
+        0xFFFF_FFFF_FFFF_FFF8,
+        0xFFFF_FFFF_8000_0000,
+        0xFFFF_FFFF_FFFF_FFFF,
+    ];
+
+    fn all_instructions() -> Vec<Instr> {
+        let mut instrs = vec![
+            Instr::PopRax,
+            Instr::PopRbx,
+            Instr::PopRbp,
+            Instr::PopRdx,
+            Instr::PushRax,
+            Instr::PushRbx,
+            Instr::PushRbp,
+            Instr::PushRdx,
+            Instr::AddRaxRbx,
+            Instr::SubRaxRbx,
+            Instr::MulRbx,
+            Instr::DivRbx,
+            Instr::PushQwordRax,
+            Instr::CmpRaxRbx,
+            Instr::SeteDl,
+            Instr::XorRaxRax,
+            Instr::XorRdxRdx,
+            Instr::SetneDl,
+            Instr::SetbDl,
+            Instr::TestRaxRax,
+            Instr::Ret,
+            Instr::MovRaxQwordRsp,
+            Instr::MovRbpRsp,
+            Instr::Syscall,
+        ];
+        let with_offset: [fn(u64) -> Instr; 12] = [
+            Instr::PushQwordRaxOffset,
+            Instr::MovRaxRspOffset,
+            Instr::MovRaxOffsetRbx,
+            Instr::AddRsp,
+            Instr::SubRsp,
+            Instr::Call,
+            Instr::Jmp,
+            Instr::Jnz,
+            Instr::Jz,
+            Instr::LeaRaxRbpOffset,
+            Instr::MovRbxRspRaxOffset,
+            Instr::MovRspOffsetRbx,
+        ];
+        for make in &with_offset {
+            for &offset in &OFFSETS {
+                instrs.push(make(offset));
+            }
+        }
+        for &value in &[0, 1, 0x6578_656B_7261_7073, u64::MAX, 1 << 63] {
+            instrs.push(Instr::MovRax(value));
+        }
+        instrs
+    }
+
+    #[test]
+    fn covers_every_variant() {
+        let variants = all_instructions()
+            .into_iter()
+            .map(|instr| ::std::mem::discriminant(&instr))
+            .collect::<HashSet<_>>();
+        assert_eq!(variants.len(), 37);
+    }
+
+    #[test]
+    fn encode_length_matches_len() {
+        for instr in all_instructions() {
+            let mut bytes = Vec::new();
+            instr.encode(&mut bytes);
+            assert_eq!(bytes.len() as u64, instr.len(), "{:?}", instr);
+        }
+    }
+
+    #[test]
+    fn decode_inverts_encode() {
+        for instr in all_instructions() {
+            let mut bytes = Vec::new();
+            instr.encode(&mut bytes);
+            assert_eq!(Instr::decode(&bytes), Some(instr), "{:?}", instr);
+            // trailing bytes must not affect decoding
+            bytes.extend_from_slice(&[0xC3; 10]);
+            assert_eq!(Instr::decode(&bytes), Some(instr), "{:?}", instr);
+        }
+    }
+
+    #[test]
+    fn encode_truncates_offsets() {
+        let mut bytes = Vec::new();
+        Instr::Jmp(0xFFFF_FFFF_FFFF_FFFB).encode(&mut bytes);
+        assert_eq!(bytes, [0xE9, 0xFB, 0xFF, 0xFF, 0xFF]);
+        bytes.clear();
+        Instr::AddRsp(0x1_0000_0010).encode(&mut bytes);
+        assert_eq!(bytes, [0x48, 0x81, 0xC4, 0x10, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn decode_rejects_truncated_input() {
+        for instr in all_instructions() {
+            let mut bytes = Vec::new();
+            instr.encode(&mut bytes);
+            let shorter = &bytes[..bytes.len() - 1];
+            assert_ne!(Instr::decode(shorter), Some(instr), "{:?}", instr);
+        }
+    }
 }
@@ -0,0 +1,51 @@
+//! Emulator for spark programs.
+//!
+//! A program is loaded from a sparkexe file with `Exe::read_from_file` and
+//! executed with `Vm::run`:
+//!
+//! ```no_run
+//! use std::io;
+//! use spark_emu::{Exe, RunOutcome, Vm};
+//!
+//! let exe = Exe::read_from_file("program.exe").unwrap();
+//! let (mut input, mut output) = (io::empty(), io::sink());
+//! let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+//! match vm.run() {
+//!     RunOutcome::Exited(code) => println!("exited with {}", code),
+//!     RunOutcome::Faulted(e) => println!("error: {}", e),
+//!     RunOutcome::Paused(addr) => println!("paused at {:#x}", addr),
+//!     RunOutcome::Watchpoint(hits) => println!("watchpoint hit: {}", hits[0]),
+//!     RunOutcome::OutOfFuel(_) | RunOutcome::TimedOut(_) => println!("too slow"),
+//! }
+//! ```
+
+#[macro_use]
+extern crate serde_json;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+extern crate libc;
+
+pub mod annotate;
+pub mod asm;
+pub mod backtrace;
+pub mod blocks;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod elf;
+pub mod executable;
+pub mod gdb;
+pub mod history;
+pub mod instruction;
+pub mod nasm;
+pub mod native;
+pub mod profile;
+pub mod shroom;
+pub mod stats;
+pub mod symbols;
+pub mod vm;
+pub mod watch;
+
+pub use executable::{Exe, ExeBuilder, ReadError};
+pub use instruction::Instr;
+pub use vm::{ExecError, LoadError, RunOutcome, Status, Vm};
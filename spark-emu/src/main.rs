@@ -1,14 +1,26 @@
 #[macro_use]
+extern crate serde_json;
+extern crate spark_emu;
 extern crate structopt;
 
-mod executable;
-mod instruction;
-mod vm;
-
 use std::fmt;
 use std::fs;
 use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use spark_emu::{Exe, RunOutcome, Vm};
+use spark_emu::{LoadError, ReadError};
+use spark_emu::backtrace::{Backtrace, Fault};
+use spark_emu::coverage::{Coverage, CoverageError};
+use spark_emu::{annotate, dap, debugger};
+use spark_emu::disasm::Disassembly;
+use spark_emu::elf::{self, ExportError};
+use spark_emu::gdb::{self, SessionEnd};
+use spark_emu::nasm;
+use spark_emu::shroom::{self, CompileError, Diagnostic};
+use spark_emu::symbols::{Symbols, SymbolsError};
+use spark_emu::watch::Watchpoint;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -19,38 +31,190 @@ struct Opt {
     trace: bool,
     /// Path to spark executable
     #[structopt(parse(from_os_str))]
-    file: PathBuf,
+    file: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
     /// File to be used as program's stdin
     #[structopt(short = "i", long = "stdin", parse(from_os_str))]
     stdin: Option<PathBuf>,
     /// File to be used as program's stdout
     #[structopt(short = "o", long = "stdout", parse(from_os_str))]
     stdout: Option<PathBuf>,
+    /// Print full exit value of the program to stderr
+    #[structopt(long = "exit-report")]
+    exit_report: bool,
+    /// Write exit report to a file instead of stderr
+    #[structopt(long = "exit-report-file", parse(from_os_str))]
+    exit_report_file: Option<PathBuf>,
+    /// Write exit report as JSON
+    #[structopt(long = "exit-report-json")]
+    exit_report_json: bool,
+    /// Treat the program as shroom compiler and explain its exit value as
+    /// a compile error in the file given by --stdin
+    #[structopt(long = "shroom-diagnostics")]
+    shroom_diagnostics: bool,
+    /// Wait for gdb to connect on the given local port and let it control
+    /// the program
+    #[structopt(long = "gdb")]
+    gdb: Option<u16>,
+    /// Log accesses to memory, given as <addr>[:r|:w|:rw][=<value>], for
+    /// example 0x20000010:w
+    #[structopt(long = "watch", number_of_values = 1)]
+    watch: Vec<Watchpoint>,
+    /// Symbol file used to name addresses in error reports, as written by
+    /// spark-as --symbols
+    #[structopt(long = "symbols", parse(from_os_str))]
+    symbols: Option<PathBuf>,
+    /// Number of last executed instructions to show when program faults,
+    /// 0 to disable. Recording them makes execution slower
+    #[structopt(long = "history", default_value = "0")]
+    history: usize,
+    /// Count executed instructions per call stack, write them to the given
+    /// file in folded stack format and print the top functions to stderr
+    #[structopt(long = "profile", parse(from_os_str))]
+    profile: Option<PathBuf>,
+    /// Count executions of every instruction and write disassembly of the
+    /// most executed functions annotated with the counts to the given file
+    #[structopt(long = "annotate", parse(from_os_str))]
+    annotate: Option<PathBuf>,
+    /// Record executed instructions and branches, merge them into the given
+    /// lcov file and print uncovered code to stderr
+    #[structopt(long = "coverage", parse(from_os_str))]
+    coverage: Option<PathBuf>,
+    /// Print execution statistics to stderr when the program halts
+    #[structopt(long = "stats")]
+    stats: bool,
+    /// Write execution statistics as JSON to the given file
+    #[structopt(long = "stats-json", parse(from_os_str))]
+    stats_json: Option<PathBuf>,
+    /// Stop the program after executing this many instructions
+    #[structopt(long = "max-instructions")]
+    max_instructions: Option<u64>,
+    /// Stop the program if it is still running after this many seconds
+    #[structopt(long = "timeout", parse(try_from_str = "parse_timeout"))]
+    timeout: Option<Duration>,
+    /// Run the program directly on the host CPU, falling back to the
+    /// interpreter on hosts other than x86-64 Linux. Only use it for
+    /// trusted programs that run without errors in the interpreter: natively
+    /// the stack alignment, memory bounds and rdx before div are not
+    /// checked, and add, sub and mul change flags and rdx like on real
+    /// hardware
+    #[structopt(
+        long = "native",
+        raw(conflicts_with_all = r#"&["trace", "gdb", "watch", "profile", "annotate", "coverage", "stats", "stats_json", "max_instructions", "history"]"#)
+    )]
+    native: bool,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Compile shroom source code using shroom compiler executable
+    #[structopt(name = "compile")]
+    Compile {
+        /// Path to shroom compiler executable
+        #[structopt(long = "compiler", parse(from_os_str))]
+        compiler: PathBuf,
+        /// Source file to compile
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        /// Where to write compiled executable
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Print disassembly of the code section
+    #[structopt(name = "disasm")]
+    Disasm {
+        /// Path to spark executable
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Run program under interactive debugger
+    #[structopt(name = "debug")]
+    Debug {
+        /// Path to spark executable
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// File to be used as program's stdin
+        #[structopt(short = "i", long = "stdin", parse(from_os_str))]
+        stdin: Option<PathBuf>,
+        /// File to be used as program's stdout
+        #[structopt(short = "o", long = "stdout", parse(from_os_str))]
+        stdout: Option<PathBuf>,
+    },
+    /// Serve Debug Adapter Protocol over stdin and stdout
+    #[structopt(name = "dap")]
+    Dap,
+    /// Convert program into a static x86-64 Linux executable that runs
+    /// without the emulator
+    #[structopt(name = "export-elf")]
+    ExportElf {
+        /// Path to spark executable
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Where to write the ELF executable
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Write disassembly as NASM source that assembles to the same code
+    #[structopt(name = "export-nasm")]
+    ExportNasm {
+        /// Path to spark executable
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Where to write the source
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: PathBuf,
+    },
 }
 
 #[derive(Debug)]
 enum Error {
-    ExeRead(executable::ReadError),
-    VmLoad(vm::LoadError),
-    Exec(vm::ExecError),
+    ExeRead(ReadError),
+    VmLoad(LoadError),
+    Fault(Box<Fault>, Symbols),
+    OutOfFuel(u64, Box<Backtrace>, Symbols),
+    TimedOut(Duration, Box<Backtrace>, Symbols),
+    Symbols(SymbolsError),
+    Coverage(CoverageError),
+    Compile(CompileError),
+    Export(ExportError),
     Io(io::Error),
+    Killed,
 }
 
-impl From<executable::ReadError> for Error {
-    fn from(err: executable::ReadError) -> Error {
+impl From<ReadError> for Error {
+    fn from(err: ReadError) -> Error {
         Error::ExeRead(err)
     }
 }
 
-impl From<vm::LoadError> for Error {
-    fn from(err: vm::LoadError) -> Error {
+impl From<LoadError> for Error {
+    fn from(err: LoadError) -> Error {
         Error::VmLoad(err)
     }
 }
 
-impl From<vm::ExecError> for Error {
-    fn from(err: vm::ExecError) -> Error {
-        Error::Exec(err)
+impl From<SymbolsError> for Error {
+    fn from(err: SymbolsError) -> Error {
+        Error::Symbols(err)
+    }
+}
+
+impl From<CoverageError> for Error {
+    fn from(err: CoverageError) -> Error {
+        Error::Coverage(err)
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(err: CompileError) -> Error {
+        Error::Compile(err)
+    }
+}
+
+impl From<ExportError> for Error {
+    fn from(err: ExportError) -> Error {
+        Error::Export(err)
     }
 }
 
@@ -65,42 +229,463 @@ impl fmt::Display for Error {
         match *self {
             Error::ExeRead(ref e) => write!(f, "{}", e),
             Error::VmLoad(ref e) => write!(f, "{}", e),
-            Error::Exec(ref e) => write!(f, "{}", e),
+            Error::Fault(ref fault, ref symbols) => write!(f, "{}", fault.report(symbols)),
+            Error::OutOfFuel(limit, ref backtrace, ref symbols) => {
+                writeln!(f, "program did not finish within {} instructions", limit)?;
+                write!(f, "{}", backtrace.report(symbols))
+            }
+            Error::TimedOut(timeout, ref backtrace, ref symbols) => {
+                writeln!(f, "program did not finish within {} seconds", timeout.as_secs_f64())?;
+                write!(f, "{}", backtrace.report(symbols))
+            }
+            Error::Symbols(ref e) => write!(f, "{}", e),
+            Error::Coverage(ref e) => write!(f, "{}", e),
+            Error::Compile(ref e) => write!(f, "{}", e),
+            Error::Export(ref e) => write!(f, "{}", e),
             Error::Io(ref e) => write!(f, "{}", e),
+            Error::Killed => write!(f, "program was killed by debugger"),
         }
     }
 }
 
-fn run() -> Result<(), Error> {
+fn parse_timeout(text: &str) -> Result<Duration, String> {
+    let seconds = text.parse::<f64>().map_err(|e| e.to_string())?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
+}
+
+fn run() -> Result<u64, Error> {
     let opt = Opt::from_args();
-    let exe = executable::Exe::read_from_file(&opt.file)?;
+    match opt.command {
+        Some(Command::Compile { ref compiler, ref input, ref output }) => {
+            compile(compiler, input, output)
+        }
+        Some(Command::Disasm { ref file }) => disasm(file),
+        Some(Command::Debug { ref file, ref stdin, ref stdout }) => {
+            debug(file, stdin.as_deref(), stdout.as_deref())
+        }
+        Some(Command::ExportElf { ref file, ref output }) => export_elf(file, output),
+        Some(Command::ExportNasm { ref file, ref output }) => export_nasm(file, output),
+        Some(Command::Dap) => {
+            dap::serve(io::BufReader::new(io::stdin()), io::stdout())?;
+            Ok(0)
+        }
+        None => match opt.file {
+            Some(ref file) => run_program(&opt, file),
+            None => {
+                structopt::clap::Error::with_description(
+                    "a spark executable or a subcommand must be provided",
+                    structopt::clap::ErrorKind::MissingRequiredArgument,
+                ).exit()
+            }
+        },
+    }
+}
+
+fn compile(compiler: &Path, input: &Path, output: &Path) -> Result<u64, Error> {
+    let compiler = Exe::read_from_file(compiler)?;
+    let source = fs::read(input)?;
+    match shroom::compile(compiler, input, &source) {
+        Ok(exe) => {
+            fs::write(output, exe)?;
+            Ok(0)
+        }
+        Err(CompileError::Rejected(_, code)) => {
+            report_compile_error(code, Some(input))?;
+            Ok(1)
+        }
+        Err(e) => Err(Error::Compile(e)),
+    }
+}
+
+fn disasm(file: &Path) -> Result<u64, Error> {
+    let exe = Exe::read_from_file(file)?;
+    let disassembly = Disassembly::new(&exe.code);
+    let stdout = io::stdout();
+    let mut writer = io::BufWriter::new(stdout.lock());
+    disassembly.write(&mut writer, &exe.code)?;
+    writer.flush()?;
+    Ok(0)
+}
+
+fn export_elf(file: &Path, output: &Path) -> Result<u64, Error> {
+    let exe = Exe::read_from_file(file)?;
+    let elf = elf::export(&exe)?;
+    fs::write(output, elf)?;
+    make_executable(output)?;
+    Ok(0)
+}
+
+fn export_nasm(file: &Path, output: &Path) -> Result<u64, Error> {
+    let exe = Exe::read_from_file(file)?;
+    let mut writer = io::BufWriter::new(fs::File::create(output)?);
+    nasm::write_source(&mut writer, &exe)?;
+    writer.flush()?;
+    Ok(0)
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    // executable by everyone who can read it
+    let mode = permissions.mode();
+    permissions.set_mode(mode | (mode & 0o444) >> 2);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn debug(file: &Path, stdin: Option<&Path>, stdout: Option<&Path>) -> Result<u64, Error> {
+    let exe = Exe::read_from_file(file)?;
+    // program input is reopened every time the program is restarted, and
+    // debugger commands are read from the terminal, so program cannot
+    // share stdin with the debugger
+    let mut open_stdin = || -> io::Result<Box<dyn Read>> {
+        match stdin {
+            Some(path) => Ok(Box::new(fs::File::open(path)?)),
+            None => Ok(Box::new(io::empty())),
+        }
+    };
+    let mut output: Box<dyn Write> = match stdout {
+        Some(path) => Box::new(fs::File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let commands = io::stdin();
+    debugger::debug(&exe, &mut open_stdin, output.as_mut(), commands.lock(), io::stdout())?;
+    Ok(0)
+}
+
+fn run_program(opt: &Opt, file: &Path) -> Result<u64, Error> {
+    let exe = Exe::read_from_file(file)?;
 
     let (stdin, stdout);
-    let mut input: Box<Read> = if let Some(path) = opt.stdin {
+    let mut input: Box<dyn Read> = if let Some(ref path) = opt.stdin {
         Box::new(fs::File::open(path)?)
     } else {
         stdin = io::stdin();
         Box::new(stdin.lock())
     };
-    let mut output: Box<Write> = if let Some(path) = opt.stdout {
-        Box::new(fs::File::create(path)?)
+    // the program writes a byte at a time, and `Vm` flushes the output
+    // before reading input or exiting
+    let mut output: Box<dyn Write> = if let Some(ref path) = opt.stdout {
+        Box::new(io::BufWriter::new(fs::File::create(path)?))
     } else {
         stdout = io::stdout();
         Box::new(stdout.lock())
     };
 
-    let mut vm = vm::Vm::new(exe, input.as_mut(), output.as_mut(), opt.trace)?;
+    // only needed to name functions in watchpoint log and reports
+    let needs_symbols = !opt.watch.is_empty()
+        || opt.profile.is_some()
+        || opt.annotate.is_some()
+        || opt.coverage.is_some();
+    let symbols = if !needs_symbols {
+        None
+    } else {
+        Some(load_symbols(opt.symbols.as_deref(), &exe.code)?)
+    };
+
+    let mut vm = Vm::new(exe, input.as_mut(), output.as_mut(), opt.trace)?;
+    vm.set_history(opt.history);
+    vm.set_profiling(opt.profile.is_some());
+    vm.set_hit_counting(opt.annotate.is_some() || opt.coverage.is_some());
+    vm.set_stats(opt.stats || opt.stats_json.is_some());
+    vm.set_max_instructions(opt.max_instructions);
+    vm.set_native_execution(opt.native);
+    let start_time = Instant::now();
+    // a deadline too far in the future to be represented is never reached
+    vm.set_deadline(opt.timeout.and_then(|timeout| start_time.checked_add(timeout)));
+    for &watchpoint in &opt.watch {
+        vm.add_watchpoint(watchpoint);
+    }
+    let outcome = match opt.gdb {
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("waiting for gdb connection on 127.0.0.1:{}", port);
+            let (stream, _) = listener.accept()?;
+            match gdb::serve(&mut vm, stream)? {
+                SessionEnd::Finished(outcome) => outcome,
+                SessionEnd::Detached => run_watching(&mut vm, symbols.as_ref()),
+                SessionEnd::Killed => return Err(Error::Killed),
+            }
+        }
+        None => run_watching(&mut vm, symbols.as_ref()),
+    };
+    let elapsed = start_time.elapsed();
+    if let Some(e) = vm.native_error() {
+        eprintln!("warning: program was interpreted, as native execution failed: {}", e);
+    }
+
+    if let Some(stats) = vm.stats() {
+        if opt.stats {
+            let stderr = io::stderr();
+            stats.write(stderr.lock(), elapsed)?;
+        }
+        if let Some(ref path) = opt.stats_json {
+            stats.write_json(fs::File::create(path)?, elapsed)?;
+        }
+    }
+    if let (Some(path), Some(profile), Some(symbols)) = (opt.profile.as_ref(), vm.profile(), symbols.as_ref()) {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        profile.write_folded(&mut out, symbols)?;
+        out.flush()?;
+        let stderr = io::stderr();
+        profile.write_summary(stderr.lock(), symbols, 20)?;
+    }
+    if let (Some(path), Some(hit_counts), Some(symbols)) = (opt.annotate.as_ref(), vm.hit_counts(), symbols.as_ref()) {
+        let disassembly = Disassembly::new(vm.code());
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        annotate::write_report(&mut out, &disassembly, vm.code(), hit_counts, symbols, 10)?;
+        out.flush()?;
+    }
+    if let (Some(path), Some(hit_counts), Some(taken_counts), Some(symbols)) =
+        (opt.coverage.as_ref(), vm.hit_counts(), vm.taken_counts(), symbols.as_ref())
+    {
+        let disassembly = Disassembly::new(vm.code());
+        let coverage = Coverage::from_counts(&disassembly, hit_counts, taken_counts);
+        let merged = coverage.update_lcov_file(path, &file.display().to_string(), &disassembly)?;
+        let stderr = io::stderr();
+        merged.write_summary(stderr.lock(), &disassembly, symbols)?;
+    }
+
+    if let Some(ref path) = opt.exit_report_file {
+        let file = fs::File::create(path)?;
+        write_exit_report(file, &outcome, opt.exit_report_json)?;
+    } else if opt.exit_report || opt.exit_report_json {
+        let stderr = io::stderr();
+        write_exit_report(stderr.lock(), &outcome, opt.exit_report_json)?;
+    }
+
+    if opt.shroom_diagnostics {
+        if let RunOutcome::Exited(code) = outcome {
+            report_compile_error(code, opt.stdin.as_deref())?;
+        }
+    }
+
+    // symbols for error reports are only loaded when needed
+    let symbols = || match symbols {
+        Some(symbols) => Ok(symbols),
+        None => load_symbols(opt.symbols.as_deref(), vm.code()),
+    };
+    match outcome {
+        RunOutcome::Exited(code) => Ok(code),
+        RunOutcome::Faulted(fault) => Err(Error::Fault(Box::new(fault), symbols()?)),
+        RunOutcome::OutOfFuel(backtrace) => {
+            let limit = opt.max_instructions.unwrap_or(0);
+            Err(Error::OutOfFuel(limit, Box::new(backtrace), symbols()?))
+        }
+        RunOutcome::TimedOut(backtrace) => {
+            let timeout = opt.timeout.unwrap_or_default();
+            Err(Error::TimedOut(timeout, Box::new(backtrace), symbols()?))
+        }
+        RunOutcome::Paused(_) | RunOutcome::Watchpoint(_) => {
+            unreachable!("no breakpoints were set and watchpoints were handled")
+        }
+    }
+}
+
+/// Runs the program to completion, logging watchpoint hits to stderr.
+fn run_watching(vm: &mut Vm, symbols: Option<&Symbols>) -> RunOutcome {
     loop {
-        vm.cycle()?;
+        match vm.run() {
+            RunOutcome::Watchpoint(hits) => {
+                for hit in hits {
+                    match symbols.and_then(|symbols| symbols.lookup(hit.rip)) {
+                        Some((start, name)) => {
+                            eprintln!("watchpoint: {} <{}+{}>", hit, name, hit.rip - start)
+                        }
+                        None => eprintln!("watchpoint: {}", hit),
+                    }
+                }
+            }
+            outcome => return outcome,
+        }
+    }
+}
+
+/// Reads symbol file if there is one, or otherwise names functions found
+/// in the code.
+fn load_symbols(path: Option<&Path>, code: &[u8]) -> Result<Symbols, Error> {
+    match path {
+        Some(path) => Ok(Symbols::read_from_file(path)?),
+        None => Ok(Symbols::from_disassembly(&Disassembly::new(code))),
+    }
+}
+
+fn write_exit_report<W: Write>(mut out: W, outcome: &RunOutcome, json: bool) -> io::Result<()> {
+    if json {
+        let report = match *outcome {
+            RunOutcome::Exited(code) => json!({
+                "status": "exited",
+                "exit_value": code,
+                "exit_value_hex": format!("{:#x}", code),
+            }),
+            RunOutcome::Faulted(ref fault) => json!({
+                "status": "faulted",
+                "rip": format!("{:#x}", fault.backtrace.registers.rip),
+                "error": fault.error.to_string(),
+            }),
+            RunOutcome::OutOfFuel(ref backtrace) => json!({
+                "status": "out_of_fuel",
+                "rip": format!("{:#x}", backtrace.registers.rip),
+            }),
+            RunOutcome::TimedOut(ref backtrace) => json!({
+                "status": "timed_out",
+                "rip": format!("{:#x}", backtrace.registers.rip),
+            }),
+            RunOutcome::Paused(_) | RunOutcome::Watchpoint(_) => {
+                unreachable!("no breakpoints were set and watchpoints were handled")
+            }
+        };
+        serde_json::to_writer(&mut out, &report)?;
+        return writeln!(out);
+    }
+    match *outcome {
+        RunOutcome::Exited(code) => {
+            writeln!(out, "exit value: {} ({:#x})", code, code)
+        }
+        RunOutcome::Faulted(ref e) => {
+            writeln!(out, "program did not exit: {}", e)
+        }
+        RunOutcome::OutOfFuel(ref backtrace) => {
+            writeln!(out, "program did not exit: instruction limit reached (rip = {:#x})", backtrace.registers.rip)
+        }
+        RunOutcome::TimedOut(ref backtrace) => {
+            writeln!(out, "program did not exit: time limit reached (rip = {:#x})", backtrace.registers.rip)
+        }
+        RunOutcome::Paused(_) | RunOutcome::Watchpoint(_) => {
+            unreachable!("no breakpoints were set and watchpoints were handled")
+        }
+    }
+}
+
+fn report_compile_error(code: u64, source_path: Option<&Path>) -> Result<(), Error> {
+    if code == 0 {
+        return Ok(());
+    }
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    let diagnostic = match Diagnostic::from_exit_value(code) {
+        Some(diagnostic) => diagnostic,
+        None => {
+            writeln!(stderr, "error: compiler exited with unknown code {}", code)?;
+            return Ok(());
+        }
+    };
+    if let Some(path) = source_path {
+        let source = fs::read_to_string(path)?;
+        let file_name = path.display().to_string();
+        diagnostic.render(&mut stderr, &file_name, Some(&source))?;
+    } else {
+        diagnostic.render(&mut stderr, "<stdin>", None)?;
+    }
+    Ok(())
+}
+
+/// Maps exit value of a spark program to exit code of the emulator process.
+///
+/// Operating systems keep only the lowest 8 bits of the exit code, so larger
+/// values are reported as 255 instead of letting them wrap around, possibly
+/// to 0.
+fn process_exit_code(code: u64) -> i32 {
+    if code <= 255 {
+        code as i32
+    } else {
+        255
     }
 }
 
 fn main() {
     match run() {
-        Ok(()) => {}
+        Ok(code) => std::process::exit(process_exit_code(code)),
         Err(e) => {
             eprintln!("Error: {}", e);
             std::process::exit(1);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use spark_emu::{RunOutcome, Vm};
+    use spark_emu::asm::assemble;
+    use structopt::StructOpt;
+    use super::{process_exit_code, write_exit_report, Opt};
+
+    fn run(source: &str, max_instructions: Option<u64>) -> RunOutcome {
+        let exe = assemble(source).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.set_max_instructions(max_instructions);
+        vm.run()
+    }
+
+    fn report(outcome: &RunOutcome, json: bool) -> String {
+        let mut out = Vec::new();
+        write_exit_report(&mut out, outcome, json).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn invalid_timeouts_are_rejected() {
+        let timeout = |value: &str| {
+            Opt::from_iter_safe(&["spark-emu", &format!("--timeout={}", value), "program.exe"])
+                .map(|opt| opt.timeout)
+                .map_err(|e| e.message)
+        };
+        assert_eq!(timeout("0.5").unwrap(), Some(Duration::from_millis(500)));
+        assert_eq!(timeout("0").unwrap(), Some(Duration::from_secs(0)));
+        for &value in &["-1", "NaN", "inf", "1e30", "soon"] {
+            let message = timeout(value).unwrap_err();
+            assert!(message.contains("--timeout"), "{}: {}", value, message);
+        }
+    }
+
+    #[test]
+    fn large_exit_values_saturate() {
+        assert_eq!(process_exit_code(0), 0);
+        assert_eq!(process_exit_code(255), 255);
+        assert_eq!(process_exit_code(256), 255);
+        assert_eq!(process_exit_code(u64::MAX), 255);
+    }
+
+    #[test]
+    fn exit_value_is_reported_in_decimal_and_hex() {
+        let outcome = RunOutcome::Exited(0x1_0000_002a);
+        assert_eq!(report(&outcome, false), "exit value: 4294967338 (0x10000002a)\n");
+        assert_eq!(
+            report(&outcome, true),
+            "{\"exit_value\":4294967338,\"exit_value_hex\":\"0x10000002a\",\"status\":\"exited\"}\n",
+        );
+    }
+
+    #[test]
+    fn unfinished_runs_are_reported_with_rip() {
+        let faulted = run("
+            mov rax, 0x30000000
+            push qword [rax]
+        ", None);
+        assert_eq!(
+            report(&faulted, false),
+            "program did not exit: out of range data access at 0x30000000 (rip = 0x1000000a)\n",
+        );
+        assert_eq!(
+            report(&faulted, true),
+            "{\"error\":\"out of range data access at 0x30000000\",\"rip\":\"0x1000000a\",\"status\":\"faulted\"}\n",
+        );
+        let out_of_fuel = run("
+        loop:
+            jmp loop
+        ", Some(3));
+        assert_eq!(
+            report(&out_of_fuel, false),
+            "program did not exit: instruction limit reached (rip = 0x10000000)\n",
+        );
+        assert_eq!(report(&out_of_fuel, true), "{\"rip\":\"0x10000000\",\"status\":\"out_of_fuel\"}\n");
+    }
+}
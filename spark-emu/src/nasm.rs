@@ -0,0 +1,275 @@
+//! Export of programs as NASM source.
+//!
+//! The code section is disassembled linearly like in `disasm`, with a label
+//! at every branch target that starts an instruction. Assembling the source
+//! with `nasm -f bin` gives the code section followed by the data section.
+//!
+//! NASM picks the shortest encoding by default, so operands are written
+//! with `strict` and `dword` wherever spark uses a longer one than needed,
+//! which keeps the assembled code byte-identical to the original.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use disasm::{DecodedInstr, Disassembly, LabelKind};
+use executable::{Exe, CODE_START, DATA_START};
+use instruction::Instr;
+
+/// Shortest run of zero quad words written with `times`.
+const MIN_ZERO_RUN: usize = 8;
+
+/// Labels that can be placed in the source, by address.
+struct Labels<'a> {
+    disassembly: &'a Disassembly,
+    /// Function that every local label belongs to, which is the closest
+    /// preceding non-local label, like in NASM.
+    scopes: HashMap<u64, &'a str>,
+}
+
+impl<'a> Labels<'a> {
+    fn new(disassembly: &'a Disassembly) -> Labels<'a> {
+        let mut scopes = HashMap::new();
+        let mut scope = "";
+        for decoded in &disassembly.instrs {
+            if let Some(label) = disassembly.labels.get(&decoded.addr) {
+                if label.kind == LabelKind::Local {
+                    scopes.insert(decoded.addr, scope);
+                } else {
+                    scope = &label.name;
+                }
+            }
+        }
+        Labels { disassembly, scopes }
+    }
+
+    /// Name of the label at `addr` if it is placed at an instruction, as
+    /// seen from code in `scope`.
+    fn name(&self, addr: u64, scope: &str) -> Option<String> {
+        self.disassembly.index_of(addr)?;
+        let label = self.disassembly.labels.get(&addr)?;
+        match self.scopes.get(&addr) {
+            Some(&label_scope) if label_scope != scope => Some(format!("{}{}", label_scope, label.name)),
+            _ => Some(label.name.clone()),
+        }
+    }
+}
+
+/// Writes NASM source that assembles to the code and data of `exe`.
+pub fn write_source<W: Write>(mut out: W, exe: &Exe) -> io::Result<()> {
+    let disassembly = Disassembly::new(&exe.code);
+    let labels = Labels::new(&disassembly);
+    writeln!(out, "; assemble with `nasm -f bin` to get the code section followed by")?;
+    writeln!(out, "; the data section")?;
+    writeln!(out, "bits 64")?;
+    writeln!(out, "org {:#x}", CODE_START)?;
+    writeln!(out)?;
+    writeln!(out, "section .text")?;
+    let mut scope = "";
+    for decoded in &disassembly.instrs {
+        if let Some(label) = disassembly.labels.get(&decoded.addr) {
+            if label.kind != LabelKind::Local {
+                writeln!(out)?;
+                scope = &label.name;
+            }
+            writeln!(out, "{}:", label.name)?;
+        }
+        writeln!(out, "    {}", format_instr(decoded, &exe.code, &labels, scope))?;
+    }
+
+    if exe.data.is_empty() {
+        return Ok(());
+    }
+    writeln!(out)?;
+    writeln!(out, "section .data follows=.text vstart={:#x} align=1", DATA_START)?;
+    let quads = exe.data
+        .chunks_exact(8)
+        .map(|quad| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(quad);
+            u64::from_le_bytes(bytes)
+        })
+        .collect::<Vec<_>>();
+    let mut index = 0;
+    while index < quads.len() {
+        let zeros = zero_run(&quads[index..]);
+        if zeros >= MIN_ZERO_RUN {
+            writeln!(out, "    times {} dq 0", zeros)?;
+            index += zeros;
+            continue;
+        }
+        let mut line = Vec::new();
+        while index < quads.len() && line.len() < 4 && zero_run(&quads[index..]) < MIN_ZERO_RUN {
+            line.push(format!("{:#x}", quads[index]));
+            index += 1;
+        }
+        writeln!(out, "    dq {}", line.join(", "))?;
+    }
+    // data sections that do not end at a quad word boundary cannot be
+    // loaded, but they are still preserved
+    let tail = exe.data.chunks_exact(8).remainder();
+    if !tail.is_empty() {
+        writeln!(out, "    db {}", format_bytes(tail))?;
+    }
+    Ok(())
+}
+
+fn format_instr(decoded: &DecodedInstr, code: &[u8], labels: &Labels, scope: &str) -> String {
+    let instr = match decoded.instr {
+        Some(instr) => instr,
+        None => {
+            let offset = (decoded.addr - CODE_START) as usize;
+            return format!("db {}", format_bytes(&code[offset..offset + 1]));
+        }
+    };
+    let mnemonic = match instr {
+        Instr::Call(_) => "call",
+        Instr::Jmp(_) => "jmp strict near",
+        Instr::Jnz(_) => "jnz strict near",
+        Instr::Jz(_) => "jz strict near",
+        Instr::PushQwordRaxOffset(o) => return format!("push qword [dword rax {}]", displacement(o)),
+        Instr::MovRaxRspOffset(o) => return format!("mov rax, [dword rsp {}]", displacement(o)),
+        Instr::MovRaxOffsetRbx(o) => return format!("mov [dword rax {}], rbx", displacement(o)),
+        Instr::AddRsp(x) => return format!("add rsp, strict dword {}", x as i64),
+        Instr::SubRsp(x) => return format!("sub rsp, strict dword {}", x as i64),
+        Instr::MovRax(x) => return format!("mov rax, strict qword {:#x}", x),
+        Instr::LeaRaxRbpOffset(o) => return format!("lea rax, [dword rbp {}]", displacement(o)),
+        Instr::MovRbxRspRaxOffset(o) => return format!("mov rbx, [dword rsp + rax {}]", displacement(o)),
+        Instr::MovRspOffsetRbx(o) => return format!("mov [dword rsp {}], rbx", displacement(o)),
+        _ => return instr.to_string(),
+    };
+    let target = instr.branch_target(decoded.addr).unwrap();
+    match labels.name(target, scope) {
+        Some(label) => format!("{} {}", mnemonic, label),
+        // targets outside the code or inside another instruction are given
+        // relative to the branch
+        None => format!("{} $ {}", mnemonic, displacement(target.wrapping_sub(decoded.addr))),
+    }
+}
+
+fn zero_run(quads: &[u64]) -> usize {
+    quads.iter().take_while(|&&quad| quad == 0).count()
+}
+
+/// Formats a sign-extended offset as `+ n` or `- n`.
+fn displacement(offset: u64) -> String {
+    let offset = offset as i64;
+    if offset < 0 {
+        format!("- {}", offset.unsigned_abs())
+    } else {
+        format!("+ {}", offset)
+    }
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:#04x}", byte)).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process::{self, Command};
+    use asm::assemble;
+    use super::write_source;
+
+    /// Has labels of every kind, a branch into the middle of an
+    /// instruction, code that is not reached and data with a run of zeros.
+    const PROGRAM: &str = "
+            jz loop
+            jmp main
+        helper:
+            mov rax, [rsp + 8]
+            ret
+        main:
+            push rbp
+            mov rbp, rsp
+            lea rax, [rbp - 8]
+        loop:
+            call helper
+            jz loop
+            jnz main + 3
+            sub rsp, 16
+            jmp done
+            .quad 0xff
+        done:
+            jmp loop
+        .data
+            .quad 1, 0, 0, 0, 0, 0, 0, 0, 0, 2
+    ";
+
+    #[test]
+    fn labels_and_forced_encodings() {
+        let exe = assemble(PROGRAM).unwrap();
+        let mut source = Vec::new();
+        write_source(&mut source, &exe).unwrap();
+        let expected = "\
+; assemble with `nasm -f bin` to get the code section followed by
+; the data section
+bits 64
+org 0x10000000
+
+section .text
+
+_start:
+    jz strict near fn_0x1000000b.L2
+    jmp strict near fn_0x1000000b.L0
+
+fn_0x1000000b:
+    mov rax, [dword rsp + 8]
+    ret
+.L0:
+    push rbp
+    mov rbp, rsp
+    lea rax, [dword rbp - 8]
+.L2:
+    call fn_0x1000000b
+    jz strict near .L2
+    jnz strict near $ - 19
+    sub rsp, strict dword 16
+    jmp strict near .L3
+    db 0xff
+    db 0x00
+    db 0x00
+    db 0x00
+    db 0x00
+    db 0x00
+    db 0x00
+    db 0x00
+.L3:
+    jmp strict near .L2
+
+section .data follows=.text vstart=0x20000000 align=1
+    dq 0x1
+    times 8 dq 0
+    dq 0x2
+";
+        assert_eq!(String::from_utf8(source).unwrap(), expected);
+    }
+
+    // needs nasm, run with `cargo test -- --ignored` where it is installed
+    #[test]
+    #[ignore]
+    fn nasm_output_is_byte_identical() {
+        let exe = assemble(PROGRAM).unwrap();
+        let dir = env::temp_dir();
+        let source_path = dir.join(format!("spark-emu-nasm-{}.asm", process::id()));
+        let output_path = dir.join(format!("spark-emu-nasm-{}.bin", process::id()));
+        let mut source = Vec::new();
+        write_source(&mut source, &exe).unwrap();
+        fs::write(&source_path, source).unwrap();
+        let status = Command::new("nasm")
+            .arg("-f")
+            .arg("bin")
+            .arg("-o")
+            .arg(&output_path)
+            .arg(&source_path)
+            .status()
+            .unwrap();
+        let output = fs::read(&output_path);
+        fs::remove_file(&source_path).unwrap();
+        let _ = fs::remove_file(&output_path);
+        assert!(status.success());
+        let mut expected = exe.code.clone();
+        expected.extend_from_slice(&exe.data);
+        assert_eq!(output.unwrap(), expected);
+    }
+}
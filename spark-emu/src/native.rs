@@ -0,0 +1,1227 @@
+//! Native execution on x86-64 Linux hosts.
+//!
+//! Every spark instruction is a real x86-64 instruction, so the code
+//! section can run directly on the host CPU. Code is mapped at
+//! `CODE_START` and the stack and data at `STACK_START`, so that all
+//! addresses stay the same as in the interpreter.
+//!
+//! Before mapping, the code is explored from the entry point, following
+//! branches and calls. Bytes that were not reached this way, or that cannot
+//! be decoded, are replaced with `int3`, which hands the program back to
+//! the interpreter if it ever gets there. `syscall` instructions are
+//! replaced with a jump to a stub that repeats the instructions overwritten
+//! by the jump and then leaves native code, so that the syscall is handled
+//! in Rust. Syscalls that do not have enough room before them for a jump
+//! are replaced with `ud2`, and take the slower path through a signal
+//! handler. Faults are caught by signal handlers too, and are also handed
+//! back to the interpreter, which executes the faulting instruction again
+//! and reports the error exactly like it would have without native
+//! execution.
+//!
+//! Native execution differs from the interpreter in a few ways:
+//!
+//! - flags and `rdx` are updated by all instructions that change them on
+//!   real hardware, for example `add` and `mul`;
+//! - misaligned stack and memory accesses do not fault;
+//! - `div` with non-zero `rdx` divides the 128-bit value in `rdx:rax`, and
+//!   only faults if the quotient does not fit in 64 bits;
+//! - accesses to the code section and to the rest of the last page of the
+//!   data section do not fault;
+//! - a `ret` to an address in the middle of an instruction runs whatever
+//!   that decodes to on the host CPU.
+//!
+//! The program has access to the whole emulator process, so only trusted
+//! programs should be executed natively.
+
+use std::fmt;
+use std::io;
+use executable::CODE_START;
+use instruction::Instr;
+
+pub use self::host::{is_supported, NativeMachine};
+
+#[derive(Debug)]
+pub enum NativeError {
+    UnsupportedHost,
+    OverlappingInstructions(u64),
+    Map(u64, io::Error),
+}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NativeError::UnsupportedHost => write!(f, "native execution needs an x86-64 Linux host"),
+            NativeError::OverlappingInstructions(addr) => {
+                write!(f, "code jumps into the middle of the instruction at {:#x}", addr)
+            }
+            NativeError::Map(addr, ref e) => write!(f, "cannot map memory at {:#x}: {}", addr, e),
+        }
+    }
+}
+
+/// The reason why `NativeMachine::run` returned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// Program reached a `syscall` instruction, which is not yet executed.
+    Syscall,
+    /// Program reached an instruction that has to be executed by the
+    /// interpreter, because it faulted or was not prepared for native
+    /// execution.
+    Trap,
+    /// Program was still running at the deadline.
+    TimedOut,
+}
+
+pub const PAGE_SIZE: u64 = 4096;
+const INT3: u8 = 0xCC;
+const UD2: [u8; 2] = [0x0F, 0x0B];
+/// Length of `jmp rel32`.
+const JMP_LEN: usize = 5;
+
+/// What the exploration found at an offset in the code section.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Slot {
+    Unreached,
+    /// Start of an instruction, `None` if it cannot be decoded.
+    Start(Option<Instr>),
+    Inside,
+}
+
+/// Code section prepared for native execution.
+pub struct NativeProgram {
+    code: Vec<u8>,
+    /// Syscall stubs, placed right before `CODE_START`.
+    stubs: Vec<u8>,
+    /// Address of every instruction in `stubs` together with the address of
+    /// the instruction it stands for, sorted by the former. Addresses in
+    /// stubs are offsets until all stubs are added.
+    stub_addrs: Vec<(u64, u64)>,
+    /// Addresses of syscalls that were replaced with `ud2`.
+    trapped_syscalls: Vec<u64>,
+}
+
+impl NativeProgram {
+    /// Prepares `code`. Syscall stubs leave native code by jumping to
+    /// `syscall_entry` with `r11` set to the address of the syscall.
+    pub fn new(code: &[u8], syscall_entry: u64) -> Result<NativeProgram, NativeError> {
+        let (slots, targets) = explore(code)?;
+        let mut program = NativeProgram {
+            code: code.to_vec(),
+            stubs: Vec::new(),
+            stub_addrs: Vec::new(),
+            trapped_syscalls: Vec::new(),
+        };
+        let mut syscalls = Vec::new();
+        for (offset, &slot) in slots.iter().enumerate() {
+            match slot {
+                Slot::Unreached | Slot::Start(None) => program.code[offset] = INT3,
+                Slot::Start(Some(Instr::Syscall)) => syscalls.push(offset),
+                Slot::Start(Some(_)) | Slot::Inside => {}
+            }
+        }
+
+        // stubs are laid out first, and the jumps to them written once
+        // their final address is known
+        let mut jumps = Vec::new();
+        for &offset in &syscalls {
+            match stub_window(&slots, &targets, offset) {
+                Some(start) => {
+                    jumps.push((start, program.stubs.len()));
+                    program.add_stub(code, start, offset, syscall_entry);
+                }
+                None => {
+                    program.code[offset..offset + 2].copy_from_slice(&UD2);
+                    program.trapped_syscalls.push(CODE_START + offset as u64);
+                }
+            }
+        }
+        let stubs_start = program.stubs_start();
+        for entry in &mut program.stub_addrs {
+            entry.0 += stubs_start;
+        }
+        for (start, stub) in jumps {
+            let end = start + JMP_LEN;
+            let target = stubs_start + stub as u64;
+            let offset = target.wrapping_sub(CODE_START + end as u64) as u32;
+            program.code[start] = 0xE9;
+            program.code[start + 1..end].copy_from_slice(&offset.to_le_bytes());
+        }
+        Ok(program)
+    }
+
+    /// Copies instructions from `start` to the syscall at `syscall` into a
+    /// new stub, and fills their place in the code with `int3` after the
+    /// jump to the stub.
+    fn add_stub(&mut self, code: &[u8], start: usize, syscall: usize, syscall_entry: u64) {
+        let mut offset = start;
+        while offset < syscall {
+            let instr = Instr::decode(&code[offset..]).expect("stub window is decoded");
+            self.stub_addrs.push((self.stubs.len() as u64, CODE_START + offset as u64));
+            offset += instr.len() as usize;
+        }
+        self.stubs.extend_from_slice(&code[start..syscall]);
+        let syscall_addr = CODE_START + syscall as u64;
+        self.stub_addrs.push((self.stubs.len() as u64, syscall_addr));
+        // mov r11, syscall_addr
+        self.stubs.extend_from_slice(&[0x49, 0xBB]);
+        self.stubs.extend_from_slice(&syscall_addr.to_le_bytes());
+        // mov r10, syscall_entry
+        self.stubs.extend_from_slice(&[0x49, 0xBA]);
+        self.stubs.extend_from_slice(&syscall_entry.to_le_bytes());
+        // jmp r10
+        self.stubs.extend_from_slice(&[0x41, 0xFF, 0xE2]);
+        for byte in &mut self.code[start + JMP_LEN..syscall + 2] {
+            *byte = INT3;
+        }
+    }
+
+    /// Code section with syscalls and unreachable code replaced.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn stubs(&self) -> &[u8] {
+        &self.stubs
+    }
+
+    /// Address of the first stub. Stubs are mapped in whole pages right
+    /// before `CODE_START`.
+    pub fn stubs_start(&self) -> u64 {
+        CODE_START - round_to_pages(self.stubs.len() as u64)
+    }
+
+    /// Syscalls that had no room for a jump to a stub, and were replaced
+    /// with `ud2` instead.
+    pub fn trapped_syscalls(&self) -> &[u64] {
+        &self.trapped_syscalls
+    }
+
+    /// Maps an address where native execution stopped to the address of
+    /// the same instruction in the original code.
+    pub fn original_addr(&self, addr: u64) -> u64 {
+        if addr < self.stubs_start() || addr >= CODE_START {
+            return addr;
+        }
+        match self.stub_addrs.binary_search_by_key(&addr, |&(stub, _)| stub) {
+            Ok(index) => self.stub_addrs[index].1,
+            Err(0) => addr,
+            Err(index) => self.stub_addrs[index - 1].1,
+        }
+    }
+}
+
+/// Rounds `len` up to a multiple of `PAGE_SIZE`.
+pub fn round_to_pages(len: u64) -> u64 {
+    len.div_ceil(PAGE_SIZE) * PAGE_SIZE
+}
+
+/// Finds instructions reachable from the start of `code`. Also returns the
+/// offsets that execution can reach other than by falling through from the
+/// previous instruction: the entry point, branch targets and return
+/// addresses.
+fn explore(code: &[u8]) -> Result<(Vec<Slot>, Vec<bool>), NativeError> {
+    let mut slots = vec![Slot::Unreached; code.len()];
+    let mut targets = vec![false; code.len()];
+    let mut pending = Vec::new();
+    if !code.is_empty() {
+        targets[0] = true;
+        pending.push(0);
+    }
+    while let Some(mut offset) = pending.pop() {
+        while offset < code.len() {
+            match slots[offset] {
+                Slot::Unreached => {}
+                Slot::Start(_) => break,
+                Slot::Inside => return Err(overlapping(&slots, offset)),
+            }
+            let instr = Instr::decode(&code[offset..]);
+            slots[offset] = Slot::Start(instr);
+            let instr = match instr {
+                Some(instr) => instr,
+                None => break,
+            };
+            let addr = CODE_START + offset as u64;
+            let next = offset + instr.len() as usize;
+            for slot in &mut slots[offset + 1..next] {
+                if *slot != Slot::Unreached {
+                    return Err(NativeError::OverlappingInstructions(addr));
+                }
+                *slot = Slot::Inside;
+            }
+            if let Some(target) = instr.branch_target(addr) {
+                let target = target.wrapping_sub(CODE_START) as usize;
+                if target < code.len() {
+                    targets[target] = true;
+                    pending.push(target);
+                }
+            }
+            match instr {
+                // return address
+                Instr::Call(_) if next < code.len() => targets[next] = true,
+                Instr::Jmp(_) | Instr::Ret => break,
+                _ => {}
+            }
+            offset = next;
+        }
+    }
+    Ok((slots, targets))
+}
+
+/// Error for a jump to `offset`, which is inside of an instruction.
+fn overlapping(slots: &[Slot], offset: usize) -> NativeError {
+    let start = (0..offset).rev().find(|&start| slots[start] != Slot::Inside).unwrap_or(0);
+    NativeError::OverlappingInstructions(CODE_START + start as u64)
+}
+
+/// Finds where a jump to the stub for the syscall at `syscall` can start.
+/// The jump replaces the syscall and the instructions right before it, so
+/// none of them except the first one can be a target of other jumps, and
+/// all of them have to be safe to move to a stub.
+fn stub_window(slots: &[Slot], targets: &[bool], syscall: usize) -> Option<usize> {
+    let mut start = syscall;
+    while syscall + 2 - start < JMP_LEN {
+        if targets[start] {
+            return None;
+        }
+        let previous = (0..start).rev().find(|&offset| slots[offset] != Slot::Inside)?;
+        match slots[previous] {
+            Slot::Start(Some(instr)) if is_movable(instr) && previous + instr.len() as usize == start => {
+                start = previous;
+            }
+            _ => return None,
+        }
+    }
+    Some(start)
+}
+
+/// Whether the instruction does the same thing at any address.
+fn is_movable(instr: Instr) -> bool {
+    !matches!(
+        instr,
+        Instr::Call(_) | Instr::Jmp(_) | Instr::Jnz(_) | Instr::Jz(_) | Instr::Ret | Instr::Syscall
+    )
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod host {
+    use std::cell::UnsafeCell;
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::{self, RecvTimeoutError};
+    use std::sync::{Mutex, MutexGuard};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+    use std::arch::global_asm;
+    use libc::{self, c_int, c_void};
+    use executable::{CODE_START, STACK_START};
+    use vm::Registers;
+    use super::{round_to_pages, NativeError, NativeProgram, Stop, INT3};
+
+    pub fn is_supported() -> bool {
+        true
+    }
+
+    /// Guest registers and the host state needed to switch between the
+    /// two. Shared with the assembly below and the signal handler.
+    #[repr(C)]
+    struct State {
+        rax: u64,
+        rbx: u64,
+        rdx: u64,
+        rsp: u64,
+        rbp: u64,
+        rip: u64,
+        /// Flags as stored by `lahf`, in bits 8 to 15.
+        flags: u64,
+        host_rsp: u64,
+        reason: u64,
+        /// Set while the guest is running.
+        running: u64,
+        thread: libc::pthread_t,
+        /// Range of addresses of guest code, including stubs.
+        guest_start: u64,
+        guest_end: u64,
+    }
+
+    const REASON_SYSCALL: u64 = 0;
+    const REASON_TRAP: u64 = 1;
+    const REASON_TIMED_OUT: u64 = 2;
+
+    const CARRY_FLAG: u64 = 1 << 8;
+    const ZERO_FLAG: u64 = 1 << 14;
+
+    struct SharedState(UnsafeCell<State>);
+
+    unsafe impl Sync for SharedState {}
+
+    static STATE: SharedState = SharedState(UnsafeCell::new(State {
+        rax: 0,
+        rbx: 0,
+        rdx: 0,
+        rsp: 0,
+        rbp: 0,
+        rip: 0,
+        flags: 0,
+        host_rsp: 0,
+        reason: 0,
+        running: 0,
+        thread: 0,
+        guest_start: 0,
+        guest_end: 0,
+    }));
+
+    /// Set by the timer signal if it arrives while the guest is not
+    /// running.
+    static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+    /// Guest memory is at fixed addresses, so only one machine can exist at
+    /// a time.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    // `spark_native_enter` saves host registers and jumps to the guest.
+    // Guest code returns to the host by jumping to `spark_native_syscall`
+    // from a syscall stub, or by a signal handler redirecting it to
+    // `spark_native_leave`.
+    global_asm!(
+        ".p2align 4",
+        "spark_native_enter:",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rip + {state} + {host_rsp}], rsp",
+        "mov rax, [rip + {state} + {flags}]",
+        "sahf",
+        "mov rax, [rip + {state} + {rax}]",
+        "mov rbx, [rip + {state} + {rbx}]",
+        "mov rdx, [rip + {state} + {rdx}]",
+        "mov rbp, [rip + {state} + {rbp}]",
+        "mov rsp, [rip + {state} + {rsp}]",
+        "mov qword ptr [rip + {state} + {running}], 1",
+        "jmp qword ptr [rip + {state} + {rip}]",
+        "",
+        ".p2align 4",
+        "spark_native_syscall:",
+        "mov [rip + {state} + {rax}], rax",
+        "lahf",
+        "mov [rip + {state} + {flags}], rax",
+        "mov [rip + {state} + {rbx}], rbx",
+        "mov [rip + {state} + {rdx}], rdx",
+        "mov [rip + {state} + {rbp}], rbp",
+        "mov [rip + {state} + {rsp}], rsp",
+        "mov [rip + {state} + {rip}], r11",
+        "mov qword ptr [rip + {state} + {reason}], {syscall}",
+        "spark_native_leave:",
+        "mov qword ptr [rip + {state} + {running}], 0",
+        "mov rsp, [rip + {state} + {host_rsp}]",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+        state = sym STATE,
+        rax = const mem::offset_of!(State, rax),
+        rbx = const mem::offset_of!(State, rbx),
+        rdx = const mem::offset_of!(State, rdx),
+        rsp = const mem::offset_of!(State, rsp),
+        rbp = const mem::offset_of!(State, rbp),
+        rip = const mem::offset_of!(State, rip),
+        flags = const mem::offset_of!(State, flags),
+        host_rsp = const mem::offset_of!(State, host_rsp),
+        reason = const mem::offset_of!(State, reason),
+        running = const mem::offset_of!(State, running),
+        syscall = const REASON_SYSCALL,
+    );
+
+    extern "C" {
+        fn spark_native_enter();
+        fn spark_native_syscall();
+        fn spark_native_leave();
+    }
+
+    const FAULT_SIGNALS: [c_int; 5] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGFPE, libc::SIGILL, libc::SIGTRAP];
+    const TIMER_SIGNAL: c_int = libc::SIGALRM;
+    /// How often the timer signal is repeated after the deadline, in case
+    /// it arrived while the guest was not running.
+    const TIMER_REPEAT: Duration = Duration::from_millis(10);
+    const SIGNAL_STACK_SIZE: usize = 64 * 1024;
+
+    extern "C" fn handle_signal(signal: c_int, _info: *mut libc::siginfo_t, context: *mut c_void) {
+        unsafe {
+            let state = &mut *STATE.0.get();
+            let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+            let rip = gregs[libc::REG_RIP as usize] as u64;
+            let in_guest = state.running != 0 && libc::pthread_equal(libc::pthread_self(), state.thread) != 0;
+            if signal == TIMER_SIGNAL {
+                if !in_guest || rip < state.guest_start || rip >= state.guest_end {
+                    TIMED_OUT.store(true, Ordering::SeqCst);
+                    return;
+                }
+            } else if !in_guest {
+                // a fault in the emulator itself, crash as usual
+                libc::signal(signal, libc::SIG_DFL);
+                return;
+            }
+            let (reason, rip) = match signal {
+                libc::SIGILL => (REASON_SYSCALL, rip),
+                // `int3` reports the address after it
+                libc::SIGTRAP => (REASON_TRAP, rip - 1),
+                TIMER_SIGNAL => (REASON_TIMED_OUT, rip),
+                _ => (REASON_TRAP, rip),
+            };
+            state.rax = gregs[libc::REG_RAX as usize] as u64;
+            state.rbx = gregs[libc::REG_RBX as usize] as u64;
+            state.rdx = gregs[libc::REG_RDX as usize] as u64;
+            state.rsp = gregs[libc::REG_RSP as usize] as u64;
+            state.rbp = gregs[libc::REG_RBP as usize] as u64;
+            state.flags = (gregs[libc::REG_EFL as usize] as u64 & 0xFF) << 8;
+            state.rip = rip;
+            state.reason = reason;
+            gregs[libc::REG_RIP as usize] = spark_native_leave as *const () as i64;
+        }
+    }
+
+    /// Anonymous memory mapped at a fixed address.
+    struct Mapping {
+        addr: u64,
+        len: u64,
+    }
+
+    impl Mapping {
+        /// Maps `len` bytes rounded up to whole pages at `addr`, fills them
+        /// with `fill` and copies `contents` to the start.
+        fn new(addr: u64, len: u64, contents: &[u8], fill: u8, prot: c_int) -> Result<Mapping, NativeError> {
+            let len = round_to_pages(len.max(1));
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE;
+            let result = unsafe {
+                libc::mmap(addr as *mut c_void, len as usize, libc::PROT_READ | libc::PROT_WRITE, flags, -1, 0)
+            };
+            if result == libc::MAP_FAILED {
+                return Err(NativeError::Map(addr, io::Error::last_os_error()));
+            }
+            let mapping = Mapping { addr: result as u64, len };
+            if mapping.addr != addr {
+                // kernels before 4.17 treat the address as a hint
+                return Err(NativeError::Map(addr, io::Error::from_raw_os_error(libc::EEXIST)));
+            }
+            unsafe {
+                if fill != 0 {
+                    ptr::write_bytes(addr as *mut u8, fill, len as usize);
+                }
+                ptr::copy_nonoverlapping(contents.as_ptr(), addr as *mut u8, contents.len());
+                if libc::mprotect(addr as *mut c_void, len as usize, prot) != 0 {
+                    return Err(NativeError::Map(addr, io::Error::last_os_error()));
+                }
+            }
+            Ok(mapping)
+        }
+    }
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.addr as *mut c_void, self.len as usize);
+            }
+        }
+    }
+
+    /// Signal handlers and signal stack, restored to previous ones when
+    /// dropped.
+    struct Signals {
+        old_actions: Vec<(c_int, libc::sigaction)>,
+        old_stack: libc::stack_t,
+        _stack: Vec<u8>,
+    }
+
+    impl Signals {
+        fn install() -> Signals {
+            unsafe {
+                // the guest stack cannot be used, as the handler would
+                // overwrite memory below `rsp`
+                let mut stack = vec![0u8; SIGNAL_STACK_SIZE];
+                let new_stack = libc::stack_t {
+                    ss_sp: stack.as_mut_ptr() as *mut c_void,
+                    ss_flags: 0,
+                    ss_size: stack.len(),
+                };
+                let mut old_stack = mem::zeroed();
+                libc::sigaltstack(&new_stack, &mut old_stack);
+
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handle_signal as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESTART;
+                libc::sigfillset(&mut action.sa_mask);
+                let mut old_actions = Vec::new();
+                for &signal in FAULT_SIGNALS.iter().chain(&[TIMER_SIGNAL]) {
+                    let mut old_action = mem::zeroed();
+                    libc::sigaction(signal, &action, &mut old_action);
+                    old_actions.push((signal, old_action));
+                }
+                Signals { old_actions, old_stack, _stack: stack }
+            }
+        }
+    }
+
+    impl Drop for Signals {
+        fn drop(&mut self) {
+            unsafe {
+                for &(signal, ref old_action) in &self.old_actions {
+                    libc::sigaction(signal, old_action, ptr::null_mut());
+                }
+                libc::sigaltstack(&self.old_stack, ptr::null_mut());
+            }
+        }
+    }
+
+    /// Thread that interrupts the guest with the timer signal once the
+    /// deadline passes.
+    struct Timer {
+        stop: Option<mpsc::Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Timer {
+        fn start(deadline: Instant) -> Timer {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let target = unsafe { libc::pthread_self() };
+            let thread = thread::spawn(move || {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let mut wait = remaining;
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                    unsafe {
+                        libc::pthread_kill(target, TIMER_SIGNAL);
+                    }
+                    wait = TIMER_REPEAT;
+                }
+            });
+            Timer {
+                stop: Some(stop),
+                thread: Some(thread),
+            }
+        }
+    }
+
+    impl Drop for Timer {
+        fn drop(&mut self) {
+            self.stop.take();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// Program mapped into memory and ready to run natively.
+    pub struct NativeMachine {
+        program: NativeProgram,
+        memory_words: usize,
+        // fields are dropped in order: the timer has to stop before signal
+        // handlers are removed, and the lock can only be released after
+        // the memory is unmapped
+        _timer: Option<Timer>,
+        _signals: Signals,
+        _mappings: Vec<Mapping>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl NativeMachine {
+        /// Maps `code` and `memory`, which holds the stack and the data
+        /// section starting at `STACK_START`, into the host's memory.
+        pub fn new(code: &[u8], memory: &[u64], deadline: Option<Instant>) -> Result<NativeMachine, NativeError> {
+            let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let program = NativeProgram::new(code, spark_native_syscall as *const () as u64)?;
+            let executable = libc::PROT_READ | libc::PROT_EXEC;
+            let mut mappings = vec![
+                Mapping::new(CODE_START, program.code().len() as u64, program.code(), INT3, executable)?,
+            ];
+            if !program.stubs().is_empty() {
+                let stubs = program.stubs();
+                let start = program.stubs_start();
+                mappings.push(Mapping::new(start, stubs.len() as u64, stubs, INT3, executable)?);
+            }
+            let bytes = unsafe { ::std::slice::from_raw_parts(memory.as_ptr() as *const u8, memory.len() * 8) };
+            let writable = libc::PROT_READ | libc::PROT_WRITE;
+            mappings.push(Mapping::new(STACK_START, bytes.len() as u64, bytes, 0, writable)?);
+
+            TIMED_OUT.store(false, Ordering::SeqCst);
+            let signals = Signals::install();
+            Ok(NativeMachine {
+                memory_words: memory.len(),
+                _timer: deadline.map(Timer::start),
+                _signals: signals,
+                _mappings: mappings,
+                _lock: lock,
+                program,
+            })
+        }
+
+        /// Runs the program from `regs` until it stops, and updates `regs`
+        /// with the state at that point. `rip` points at the syscall or the
+        /// trapping instruction, which is not yet executed.
+        pub fn run(&mut self, regs: &mut Registers) -> Stop {
+            if TIMED_OUT.load(Ordering::SeqCst) {
+                return Stop::TimedOut;
+            }
+            let state = STATE.0.get();
+            let mut flags = 0;
+            if regs.below_flag {
+                flags |= CARRY_FLAG;
+            }
+            if regs.zero_flag {
+                flags |= ZERO_FLAG;
+            }
+            unsafe {
+                *state = State {
+                    rax: regs.rax,
+                    rbx: regs.rbx,
+                    rdx: regs.rdx,
+                    rsp: regs.rsp,
+                    rbp: regs.rbp,
+                    rip: regs.rip,
+                    flags,
+                    host_rsp: 0,
+                    reason: REASON_TRAP,
+                    running: 0,
+                    thread: libc::pthread_self(),
+                    guest_start: self.program.stubs_start(),
+                    guest_end: CODE_START + round_to_pages(self.program.code().len() as u64),
+                };
+                spark_native_enter();
+                let state = &*state;
+                *regs = Registers {
+                    rip: self.program.original_addr(state.rip),
+                    rax: state.rax,
+                    rbx: state.rbx,
+                    rdx: state.rdx,
+                    rsp: state.rsp,
+                    rbp: state.rbp,
+                    below_flag: state.flags & CARRY_FLAG != 0,
+                    zero_flag: state.flags & ZERO_FLAG != 0,
+                };
+                match state.reason {
+                    REASON_SYSCALL => Stop::Syscall,
+                    REASON_TIMED_OUT => Stop::TimedOut,
+                    _ => Stop::Trap,
+                }
+            }
+        }
+
+        /// Copies the stack and data section back to `memory`.
+        pub fn copy_memory_to(&self, memory: &mut [u64]) {
+            let len = memory.len().min(self.memory_words);
+            unsafe {
+                ptr::copy_nonoverlapping(STACK_START as *const u64, memory.as_mut_ptr(), len);
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+mod host {
+    use std::time::Instant;
+    use vm::Registers;
+    use super::{NativeError, Stop};
+
+    pub fn is_supported() -> bool {
+        false
+    }
+
+    /// Program mapped into memory and ready to run natively. Cannot be
+    /// created on this host.
+    pub struct NativeMachine {
+        _private: (),
+    }
+
+    impl NativeMachine {
+        pub fn new(_code: &[u8], _memory: &[u64], _deadline: Option<Instant>) -> Result<NativeMachine, NativeError> {
+            Err(NativeError::UnsupportedHost)
+        }
+
+        pub fn run(&mut self, _regs: &mut Registers) -> Stop {
+            unreachable!("native machine cannot be created on this host")
+        }
+
+        pub fn copy_memory_to(&self, _memory: &mut [u64]) {
+            unreachable!("native machine cannot be created on this host")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use asm::assemble;
+    use executable::CODE_START;
+    use super::{NativeProgram, INT3, UD2};
+
+    #[test]
+    fn syscalls_are_replaced_with_jumps_to_stubs() {
+        let exe = assemble("
+            mov rax, 2
+            syscall
+            jmp exit
+            push rax
+        exit:
+            xor rax, rax
+            syscall
+        ").unwrap();
+        let program = NativeProgram::new(&exe.code, 0x1234).unwrap();
+        let code = program.code();
+        let stubs_start = program.stubs_start();
+        assert_eq!(stubs_start, CODE_START - 4096);
+
+        // `mov rax, 2; syscall` is replaced with a jump to the first stub
+        let jump = (stubs_start.wrapping_sub(CODE_START + 5) as u32).to_le_bytes();
+        assert_eq!(code[0], 0xE9);
+        assert_eq!(code[1..5], jump);
+        assert_eq!(code[5..12], [INT3; 7]);
+        assert_eq!(code[12..17], exe.code[12..17]);
+        // `push rax` is never reached
+        assert_eq!(code[17], INT3);
+        // `xor rax, rax; syscall` jumps to the second stub, 33 bytes later
+        let jump = ((stubs_start + 33).wrapping_sub(CODE_START + 23) as u32).to_le_bytes();
+        assert_eq!(code[18], 0xE9);
+        assert_eq!(code[19..23], jump);
+
+        let stubs = program.stubs();
+        assert_eq!(stubs.len(), 33 + 26);
+        assert_eq!(stubs[..10], exe.code[..10]);
+        assert_eq!(stubs[10..12], [0x49, 0xBB]);
+        assert_eq!(stubs[12..20], (CODE_START + 10).to_le_bytes());
+        assert_eq!(stubs[20..22], [0x49, 0xBA]);
+        assert_eq!(stubs[22..30], 0x1234u64.to_le_bytes());
+        assert_eq!(stubs[33..36], exe.code[18..21]);
+
+        assert_eq!(program.original_addr(stubs_start), CODE_START);
+        assert_eq!(program.original_addr(stubs_start + 12), CODE_START + 10);
+        assert_eq!(program.original_addr(stubs_start + 33), CODE_START + 18);
+        assert_eq!(program.original_addr(stubs_start + 40), CODE_START + 21);
+        assert_eq!(program.original_addr(CODE_START + 12), CODE_START + 12);
+    }
+
+    #[test]
+    fn syscalls_at_jump_targets_trap() {
+        let exe = assemble("
+            xor rax, rax
+            jmp exit
+        exit:
+            syscall
+        ").unwrap();
+        let program = NativeProgram::new(&exe.code, 0).unwrap();
+        assert!(program.stubs().is_empty());
+        assert_eq!(program.code()[..8], exe.code[..8]);
+        assert_eq!(program.code()[8..], UD2);
+        assert_eq!(program.trapped_syscalls(), [CODE_START + 8]);
+    }
+
+    #[test]
+    fn jumps_into_instructions_are_rejected() {
+        let exe = assemble("
+            jz start + 1
+        start:
+            mov rax, 2
+        ").unwrap();
+        assert!(NativeProgram::new(&exe.code, 0).is_err());
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    mod host {
+        use std::io;
+        use std::time::{Duration, Instant};
+        use asm::assemble;
+        use executable::{Exe, CODE_START, STACK_START};
+        use vm::{Registers, RunOutcome, Vm};
+
+        /// Runs `exe` natively or in the interpreter, returning the outcome,
+        /// final registers without flags, memory and output.
+        fn run(exe: &Exe, input: &[u8], native: bool) -> (String, Registers, Vec<u64>, Vec<u8>) {
+            let mut input = input;
+            let mut output = Vec::new();
+            let (outcome, registers, memory) = {
+                let mut vm = Vm::new(exe.clone(), &mut input, &mut output, false).unwrap();
+                vm.set_native_execution(native);
+                let outcome = match vm.run() {
+                    RunOutcome::Exited(code) => format!("exited {}", code),
+                    RunOutcome::Faulted(fault) => format!("faulted {} with {:?}", fault, fault.backtrace.call_stack),
+                    other => panic!("unexpected outcome: {:?}", other),
+                };
+                if let Some(e) = vm.native_error() {
+                    panic!("native execution failed: {}", e);
+                }
+                let memory = (0..)
+                    .map(|index| vm.read_data(STACK_START + index * 8))
+                    .take_while(|word| word.is_ok())
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap();
+                (outcome, vm.registers(), memory)
+            };
+            // flags are changed by more instructions natively
+            let registers = Registers {
+                below_flag: false,
+                zero_flag: false,
+                ..registers
+            };
+            (outcome, registers, memory, output)
+        }
+
+        /// Checks that native execution gives the same results as the
+        /// interpreter, and returns the outcome and output.
+        fn run_both(source: &str, input: &[u8]) -> (String, Vec<u8>) {
+            let exe = assemble(source).unwrap();
+            let native = run(&exe, input, true);
+            let interpreted = run(&exe, input, false);
+            assert_eq!(native.0, interpreted.0);
+            assert_eq!(native.1, interpreted.1);
+            assert!(native.2 == interpreted.2, "memory differs");
+            assert_eq!(native.3, interpreted.3);
+            (native.0, native.3)
+        }
+
+        #[test]
+        fn loops_and_calls() {
+            let (outcome, output) = run_both("
+                call sum
+                push rax
+                pop rbx
+                mov rax, 2
+                syscall
+                xor rax, rax
+                syscall
+            sum:
+                push rbp
+                mov rbp, rsp
+                xor rdx, rdx
+                mov rax, 10
+            loop:
+                push rax
+                push rdx
+                pop rbx
+                add rax, rbx
+                push rax
+                pop rdx
+                mov rax, 1
+                push rax
+                pop rbx
+                pop rax
+                sub rax, rbx
+                test rax, rax
+                jnz loop
+                push rdx
+                pop rax
+                pop rbp
+                ret
+            ", &[]);
+            assert_eq!(outcome, "exited 55");
+            assert_eq!(output, [55]);
+        }
+
+        #[test]
+        fn input_and_output() {
+            let input = b"hello, world";
+            let (outcome, output) = run_both("
+                xor rdx, rdx
+            next:
+                mov rax, 1
+                syscall
+                push rbx
+                mov rax, 256
+                push rax
+                pop rbx
+                pop rax
+                cmp rax, rbx
+                jz done
+                push rax
+                pop rbx
+                mov rax, 2
+                syscall
+                mov rax, 1
+                push rax
+                pop rbx
+                push rdx
+                pop rax
+                add rax, rbx
+                push rax
+                pop rdx
+                jmp next
+            done:
+                push rdx
+                pop rbx
+                xor rax, rax
+                syscall
+            ", input);
+            assert_eq!(outcome, "exited 12");
+            assert_eq!(output, input);
+        }
+
+        #[test]
+        fn data_section() {
+            let (outcome, _) = run_both("
+                mov rax, values
+                push qword [rax + 8]
+                pop rbx
+                mov [rax], rbx
+                mov rax, 8
+                mov rbx, [rsp + rax - 8]
+                mul rbx
+                push rax
+                mov rax, 0
+                mov rbx, [rsp + rax]
+                mov rax, values
+                mov [rax + 16], rbx
+                xor rax, rax
+                syscall
+            .data
+            values:
+                .quad 1, 6, 0
+            ", &[]);
+            assert_eq!(outcome, "exited 48");
+        }
+
+        #[test]
+        fn syscall_at_jump_target() {
+            let (outcome, output) = run_both("
+                mov rax, 2
+                mov rax, 66
+                push rax
+                pop rbx
+                mov rax, 2
+                jmp write
+            write:
+                syscall
+                xor rax, rax
+                syscall
+            ", &[]);
+            assert_eq!(outcome, "exited 66");
+            assert_eq!(output, b"B");
+        }
+
+        #[test]
+        fn code_reached_only_through_ret() {
+            let (outcome, _) = run_both("
+                mov rax, hidden
+                push rax
+                ret
+            hidden:
+                mov rax, 3
+                push rax
+                pop rbx
+                xor rax, rax
+                syscall
+            ", &[]);
+            assert_eq!(outcome, "exited 3");
+        }
+
+        #[test]
+        fn faults() {
+            let programs = [
+                // division by zero
+                "mov rax, 0\n push rax\n pop rbx\n xor rdx, rdx\n div rbx",
+                // store outside of memory
+                "xor rax, rax\n mov [rax + 8], rbx",
+                // stack overflow
+                "f: push rax\n call f",
+                // unknown syscall
+                "call f\n f: mov rax, 7\n syscall",
+                // fault in instructions moved to a syscall stub
+                "xor rax, rax\n pop rbx\n syscall",
+                // return outside of code
+                "mov rax, 0x1234\n push rax\n ret",
+                // undecodable instruction
+                "jmp bad\n bad: .quad -1",
+            ];
+            let expected = [
+                "faulted attempted to divide by 0 (rip = 0x1000000f)",
+                "faulted out of range data access at 0x8 (rip = 0x10000003)",
+                "faulted out of range data access at 0x1feffff8 (rip = 0x10000000)",
+                "faulted unknown syscall id: 7 (rip = 0x1000000f)",
+                "faulted out of range data access at 0x20000000 (rip = 0x10000003)",
+                "faulted out of range code access at 0x1234 (rip = 0x1234)",
+                "faulted cannot decode instruction",
+            ];
+            for (source, expected) in programs.iter().zip(&expected) {
+                let (outcome, _) = run_both(source, &[]);
+                assert!(outcome.starts_with(expected), "{}", outcome);
+            }
+        }
+
+        /// Returns native and interpreted outcomes of a program for which
+        /// they differ.
+        fn run_diverging(source: &str) -> (String, String) {
+            let exe = assemble(source).unwrap();
+            let native = run(&exe, &[], true).0;
+            let interpreted = run(&exe, &[], false).0;
+            assert_ne!(native, interpreted);
+            (native, interpreted)
+        }
+
+        #[test]
+        fn arithmetic_changes_flags_and_rdx() {
+            // `add` clears the zero flag set by `test`
+            let (native, interpreted) = run_diverging("
+                xor rax, rax
+                test rax, rax
+                mov rax, 1
+                push rax
+                pop rbx
+                add rax, rbx
+                jz taken
+                xor rax, rax
+                syscall
+            taken:
+                push rax
+                pop rbx
+                xor rax, rax
+                syscall
+            ");
+            assert_eq!((native.as_str(), interpreted.as_str()), ("exited 1", "exited 2"));
+            // `mul` writes the high half of the product to `rdx`
+            let (native, interpreted) = run_diverging("
+                xor rdx, rdx
+                mov rax, 0x4000000000000000
+                push rax
+                mov rax, 4
+                push rax
+                pop rbx
+                pop rax
+                mul rbx
+                push rdx
+                pop rbx
+                xor rax, rax
+                syscall
+            ");
+            assert_eq!((native.as_str(), interpreted.as_str()), ("exited 1", "exited 0"));
+        }
+
+        #[test]
+        fn misaligned_stack_does_not_fault() {
+            let (native, interpreted) = run_diverging("
+                sub rsp, 4
+                add rsp, 4
+                xor rax, rax
+                syscall
+            ");
+            assert_eq!(native, "exited 0");
+            assert!(interpreted.starts_with("faulted misaligned stack with rsp = 0x1ffffffc"), "{}", interpreted);
+        }
+
+        #[test]
+        fn div_uses_rdx_as_high_half() {
+            let (native, interpreted) = run_diverging("
+                mov rax, 1
+                push rax
+                pop rdx
+                mov rax, 2
+                push rax
+                pop rbx
+                xor rax, rax
+                div rbx
+                push rax
+                pop rbx
+                xor rax, rax
+                syscall
+            ");
+            assert_eq!(native, "exited 9223372036854775808");
+            assert!(interpreted.starts_with("faulted attempted to divide with rdx != 0"), "{}", interpreted);
+        }
+
+        #[test]
+        fn code_and_rest_of_data_page_are_accessible() {
+            let (native, interpreted) = run_diverging("
+                mov rax, 0x10000000
+                push qword [rax]
+                pop rbx
+                xor rax, rax
+                syscall
+            ");
+            assert!(native.starts_with("exited "), "{}", native);
+            assert!(interpreted.starts_with("faulted out of range data access at 0x10000000"), "{}", interpreted);
+            let (native, interpreted) = run_diverging("
+                mov rax, values
+                push qword [rax + 8]
+                pop rbx
+                xor rax, rax
+                syscall
+            .data
+            values:
+                .quad 1
+            ");
+            assert_eq!(native, "exited 0");
+            assert!(interpreted.starts_with("faulted out of range data access at 0x20000008"), "{}", interpreted);
+        }
+
+        #[test]
+        fn ret_into_instruction_runs_host_instructions() {
+            // the immediate of the first `mov` at `hidden` is eight `nop`s,
+            // which only the host CPU can execute
+            let (native, interpreted) = run_diverging("
+                xor rax, rax
+                test rax, rax
+                jnz hidden
+                mov rax, 2
+                push rax
+                pop rbx
+                mov rax, hidden
+                add rax, rbx
+                push rax
+                ret
+            hidden:
+                mov rax, 0x9090909090909090
+                mov rax, 5
+                push rax
+                pop rbx
+                xor rax, rax
+                syscall
+            ");
+            assert_eq!(native, "exited 5");
+            assert!(interpreted.starts_with("faulted cannot decode instruction"), "{}", interpreted);
+        }
+
+        #[test]
+        fn history_is_recorded_in_interpreter() {
+            let exe = assemble("
+                mov rax, 2
+                push rax
+                pop rbx
+                xor rax, rax
+                syscall
+            ").unwrap();
+            let (mut input, mut output) = (io::empty(), io::sink());
+            let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+            vm.set_native_execution(true);
+            vm.set_history(8);
+            match vm.run() {
+                RunOutcome::Exited(2) => {}
+                other => panic!("unexpected outcome: {:?}", other),
+            }
+            assert_eq!(vm.history().map(|history| history.len()), Some(5));
+            assert_eq!(vm.instruction_count(), 5);
+        }
+
+        #[test]
+        fn deadline() {
+            let programs = [
+                "loop: jmp loop",
+                "loop: mov rax, 2\n syscall\n jmp loop",
+            ];
+            for source in &programs {
+                let exe = assemble(source).unwrap();
+                let (mut input, mut output) = (io::empty(), io::sink());
+                let mut vm = Vm::new(exe.clone(), &mut input, &mut output, false).unwrap();
+                vm.set_native_execution(true);
+                vm.set_deadline(Some(Instant::now() + Duration::from_millis(50)));
+                match vm.run() {
+                    RunOutcome::TimedOut(backtrace) => {
+                        let rip = backtrace.registers.rip;
+                        assert!(rip >= CODE_START && rip < CODE_START + exe.code.len() as u64);
+                    }
+                    other => panic!("unexpected outcome: {:?}", other),
+                }
+                assert!(vm.native_error().is_none());
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Instruction count profiler.
+//!
+//! The profiler follows `call` and `ret` instructions to keep track of the
+//! call stack, and counts executed instructions for every distinct stack.
+//! Stacks are kept as a call tree, so counting an instruction does not need
+//! to look at the whole stack.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use symbols::Symbols;
+use executable::CODE_START;
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// Address of the function.
+    addr: u64,
+    parent: Option<usize>,
+    children: HashMap<u64, usize>,
+    /// Instructions executed in this function with this exact stack.
+    count: u64,
+}
+
+impl Node {
+    fn new(addr: u64, parent: Option<usize>) -> Node {
+        Node {
+            addr,
+            parent,
+            children: HashMap::new(),
+            count: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    /// Call tree, rooted at the entry point.
+    nodes: Vec<Node>,
+    current: usize,
+}
+
+/// Instruction counts of a single function.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FunctionCounts {
+    pub addr: u64,
+    /// Instructions executed in the function itself.
+    pub self_count: u64,
+    /// Instructions executed in the function and everything it called.
+    /// Recursive calls are counted once.
+    pub inclusive_count: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile {
+            nodes: vec![Node::new(CODE_START, None)],
+            current: 0,
+        }
+    }
+
+    /// Counts an instruction executed in the current function.
+    pub fn count(&mut self) {
+        self.nodes[self.current].count += 1;
+    }
+
+    /// Records a call to a function at `addr`.
+    pub fn enter(&mut self, addr: u64) {
+        let next_index = self.nodes.len();
+        let current = self.current;
+        let child = *self.nodes[current].children.entry(addr).or_insert(next_index);
+        if child == next_index {
+            self.nodes.push(Node::new(addr, Some(current)));
+        }
+        self.current = child;
+    }
+
+    /// Records a return from the current function. Returns from the entry
+    /// point are ignored, as there is nothing to return to.
+    pub fn leave(&mut self) {
+        if let Some(parent) = self.nodes[self.current].parent {
+            self.current = parent;
+        }
+    }
+
+    pub fn total_count(&self) -> u64 {
+        self.nodes.iter().map(|node| node.count).sum()
+    }
+
+    /// Writes counts in the folded stack format used by flame graph tools:
+    /// one line per stack, with function names separated by `;` followed by
+    /// a space and the instruction count.
+    pub fn write_folded<W: Write>(&self, mut out: W, symbols: &Symbols) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for index in 0..self.nodes.len() {
+            if self.nodes[index].count == 0 {
+                continue;
+            }
+            let mut names = Vec::new();
+            let mut node = Some(index);
+            while let Some(index) = node {
+                names.push(function_name(symbols, self.nodes[index].addr));
+                node = self.nodes[index].parent;
+            }
+            names.reverse();
+            lines.push((names.join(";"), self.nodes[index].count));
+        }
+        lines.sort();
+        for (stack, count) in lines {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+
+    /// Counts for every function that executed at least one instruction
+    /// or called one that did, sorted by self count, largest first.
+    pub fn functions(&self) -> Vec<FunctionCounts> {
+        // children are always created after their parents, so iterating
+        // backwards computes subtree totals before they are needed
+        let mut totals = vec![0; self.nodes.len()];
+        for index in (0..self.nodes.len()).rev() {
+            totals[index] += self.nodes[index].count;
+            if let Some(parent) = self.nodes[index].parent {
+                totals[parent] += totals[index];
+            }
+        }
+
+        let mut counts = HashMap::new();
+        let mut active = HashMap::new();
+        self.collect_counts(&totals, &mut counts, &mut active);
+
+        let mut functions = counts
+            .into_iter()
+            .filter(|&(_, (_, inclusive_count))| inclusive_count > 0)
+            .map(|(addr, (self_count, inclusive_count))| FunctionCounts {
+                addr,
+                self_count,
+                inclusive_count,
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by(|a, b| {
+            b.self_count
+                .cmp(&a.self_count)
+                .then(b.inclusive_count.cmp(&a.inclusive_count))
+                .then(a.addr.cmp(&b.addr))
+        });
+        functions
+    }
+
+    fn collect_counts(
+        &self,
+        totals: &[u64],
+        counts: &mut HashMap<u64, (u64, u64)>,
+        active: &mut HashMap<u64, usize>,
+    ) {
+        // call stacks can be deep, so the tree is walked with an explicit
+        // stack instead of recursion
+        enum Visit {
+            Enter(usize),
+            Exit(u64),
+        }
+        let mut stack = vec![Visit::Enter(0)];
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(index) => {
+                    let node = &self.nodes[index];
+                    let entry = counts.entry(node.addr).or_insert((0, 0));
+                    entry.0 += node.count;
+                    let depth = active.entry(node.addr).or_insert(0);
+                    if *depth == 0 {
+                        entry.1 += totals[index];
+                    }
+                    *depth += 1;
+                    stack.push(Visit::Exit(node.addr));
+                    stack.extend(node.children.values().map(|&child| Visit::Enter(child)));
+                }
+                Visit::Exit(addr) => {
+                    *active.get_mut(&addr).unwrap() -= 1;
+                }
+            }
+        }
+    }
+
+    /// Writes a table of functions with the largest self counts.
+    pub fn write_summary<W: Write>(&self, mut out: W, symbols: &Symbols, limit: usize) -> io::Result<()> {
+        let total = self.total_count();
+        let percent = |count: u64| {
+            if total == 0 {
+                0.0
+            } else {
+                count as f64 * 100.0 / total as f64
+            }
+        };
+        writeln!(out, "executed {} instructions", total)?;
+        writeln!(out, "{:>14} {:>7} {:>14} {:>7}  function", "self", "", "inclusive", "")?;
+        for function in self.functions().iter().take(limit) {
+            writeln!(
+                out,
+                "{:>14} {:>6.2}% {:>14} {:>6.2}%  {}",
+                function.self_count,
+                percent(function.self_count),
+                function.inclusive_count,
+                percent(function.inclusive_count),
+                function_name(symbols, function.addr),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn function_name(symbols: &Symbols, addr: u64) -> String {
+    match symbols.lookup(addr) {
+        Some((start, name)) if start == addr => name.to_string(),
+        _ => format!("{:#x}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use asm::assemble_with_symbols;
+    use executable::CODE_START;
+    use vm::{RunOutcome, Vm};
+    use super::{FunctionCounts, Profile};
+
+    #[test]
+    fn stacks_are_written_in_folded_format() {
+        let (exe, symbols) = assemble_with_symbols("
+            _start:
+                call f
+                call f
+                xor rax, rax
+                syscall
+            f:
+                call g
+                ret
+            g:
+                ret
+        ").unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.set_profiling(true);
+        match vm.run() {
+            RunOutcome::Exited(0) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.total_count(), vm.instruction_count());
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded, &symbols).unwrap();
+        // `call` is counted in the caller and `ret` in the callee
+        assert_eq!(String::from_utf8(folded).unwrap(), "_start 4\n_start;f 4\n_start;f;g 2\n");
+    }
+
+    #[test]
+    fn recursive_calls_are_counted_once_in_inclusive_count() {
+        let f = CODE_START + 0x10;
+        let mut profile = Profile::new();
+        profile.count();
+        profile.enter(f);
+        profile.count();
+        profile.enter(f);
+        profile.count();
+        profile.count();
+        profile.leave();
+        profile.leave();
+        // returning from the entry point is ignored
+        profile.leave();
+        profile.count();
+        assert_eq!(profile.functions(), [
+            FunctionCounts { addr: f, self_count: 3, inclusive_count: 3 },
+            FunctionCounts { addr: CODE_START, self_count: 2, inclusive_count: 5 },
+        ]);
+    }
+}
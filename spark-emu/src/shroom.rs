@@ -0,0 +1,293 @@
+//! Support for running the shroom compiler.
+//!
+//! When the compiler finds an error it calls `abort` which exits with value
+//! `(line * 1000 + col) * 1000 + error`, where `line` and `col` are the
+//! position of the current token and `error` is one of `ERR_*` constants.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use executable::{Exe, ReadError};
+use backtrace::Fault;
+use vm::{LoadError, RunOutcome, Vm};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    MultipleDefs,
+    UnexpectedToken,
+    TooMany,
+    BadType,
+    StaticInitializer,
+    TypeTooLarge,
+    NoSuchStruct,
+    NoSuchConstant,
+    NoSuchFunction,
+    NoSuchVar,
+    NoField,
+    NoIndexing,
+    CantAssign,
+    BadParamCount,
+    UnexpectedChar,
+    NameTooLong,
+    CodeTooLong,
+    NoMain,
+    FieldNotFound,
+}
+
+impl ErrorKind {
+    pub fn from_code(code: u64) -> Option<ErrorKind> {
+        match code {
+            1 => Some(ErrorKind::MultipleDefs),
+            2 => Some(ErrorKind::UnexpectedToken),
+            3 => Some(ErrorKind::TooMany),
+            4 => Some(ErrorKind::BadType),
+            5 => Some(ErrorKind::StaticInitializer),
+            6 => Some(ErrorKind::TypeTooLarge),
+            7 => Some(ErrorKind::NoSuchStruct),
+            8 => Some(ErrorKind::NoSuchConstant),
+            9 => Some(ErrorKind::NoSuchFunction),
+            10 => Some(ErrorKind::NoSuchVar),
+            11 => Some(ErrorKind::NoField),
+            12 => Some(ErrorKind::NoIndexing),
+            13 => Some(ErrorKind::CantAssign),
+            14 => Some(ErrorKind::BadParamCount),
+            15 => Some(ErrorKind::UnexpectedChar),
+            16 => Some(ErrorKind::NameTooLong),
+            17 => Some(ErrorKind::CodeTooLong),
+            18 => Some(ErrorKind::NoMain),
+            19 => Some(ErrorKind::FieldNotFound),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::MultipleDefs => write!(f, "name is defined multiple times"),
+            ErrorKind::UnexpectedToken => write!(f, "unexpected token"),
+            ErrorKind::TooMany => write!(f, "too many definitions"),
+            ErrorKind::BadType => write!(f, "mismatched types"),
+            ErrorKind::StaticInitializer => write!(f, "statics cannot have initializers"),
+            ErrorKind::TypeTooLarge => write!(f, "type is nested too deeply"),
+            ErrorKind::NoSuchStruct => write!(f, "no such struct"),
+            ErrorKind::NoSuchConstant => write!(f, "no such constant"),
+            ErrorKind::NoSuchFunction => write!(f, "no such function"),
+            ErrorKind::NoSuchVar => write!(f, "no such variable"),
+            ErrorKind::NoField => write!(f, "type does not have fields"),
+            ErrorKind::NoIndexing => write!(f, "type cannot be indexed"),
+            ErrorKind::CantAssign => write!(f, "cannot assign to this expression"),
+            ErrorKind::BadParamCount => write!(f, "wrong number of arguments"),
+            ErrorKind::UnexpectedChar => write!(f, "unexpected character"),
+            ErrorKind::NameTooLong => write!(f, "name is too long"),
+            ErrorKind::CodeTooLong => write!(f, "generated code is too long"),
+            ErrorKind::NoMain => write!(f, "no main function"),
+            ErrorKind::FieldNotFound => write!(f, "no such field"),
+        }
+    }
+}
+
+/// Compile error decoded from the exit value of the compiler.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: u64,
+    pub col: u64,
+    pub kind: ErrorKind,
+}
+
+impl Diagnostic {
+    /// Decodes an exit value produced by `abort`. Returns `None` for 0 (which
+    /// means successful compilation) and for values with unknown error kind.
+    pub fn from_exit_value(value: u64) -> Option<Diagnostic> {
+        let kind = ErrorKind::from_code(value % 1000)?;
+        Some(Diagnostic {
+            line: value / 1_000_000,
+            col: value / 1000 % 1000,
+            kind,
+        })
+    }
+
+    /// Writes the diagnostic as `file:line:col: error: message`, followed
+    /// by the offending source line with a caret under the error position.
+    ///
+    /// Line is 0 if compiler failed before reading the first token, and then
+    /// only the file name is shown.
+    pub fn render<W: Write>(&self, mut out: W, file_name: &str, source: Option<&str>) -> io::Result<()> {
+        if self.line == 0 {
+            return writeln!(out, "{}: error: {}", file_name, self.kind);
+        }
+        writeln!(out, "{}:{}:{}: error: {}", file_name, self.line, self.col, self.kind)?;
+        let line = source.and_then(|source| source.lines().nth((self.line - 1) as usize));
+        if let Some(line) = line {
+            let line = line.trim_end_matches('\r');
+            // keep tabs so that caret lines up with the source line
+            let padding = line
+                .chars()
+                .take(self.col.saturating_sub(1) as usize)
+                .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+            writeln!(out, "{}", line)?;
+            writeln!(out, "{}^", padding)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CompileError {
+    /// Compiler executable could not be loaded.
+    Load(LoadError),
+    /// Compiler crashed while running.
+    Exec(Box<Fault>),
+    /// Compiler rejected the source code at the given path and exited with
+    /// the given value.
+    Rejected(PathBuf, u64),
+    /// Compiler exited successfully, but did not produce a valid executable.
+    BadOutput(ReadError),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompileError::Load(ref e) => write!(f, "failed to load compiler: {}", e),
+            CompileError::Exec(ref e) => write!(f, "compiler crashed: {}", e),
+            CompileError::Rejected(ref path, value) => match Diagnostic::from_exit_value(value) {
+                Some(d) if d.line == 0 => write!(f, "{}: error: {}", path.display(), d.kind),
+                Some(d) => write!(f, "{}:{}:{}: error: {}", path.display(), d.line, d.col, d.kind),
+                None => write!(f, "{}: compiler exited with unknown code {}", path.display(), value),
+            },
+            CompileError::BadOutput(ref e) => write!(f, "compiler produced invalid executable: {}", e),
+        }
+    }
+}
+
+/// Runs shroom compiler on `source` and returns contents of the produced
+/// executable file. `path` is only used in error messages.
+pub fn compile(compiler: Exe, path: &Path, source: &[u8]) -> Result<Vec<u8>, CompileError> {
+    let mut input = source;
+    let mut output = Vec::new();
+    let outcome = {
+        let mut vm = Vm::new(compiler, &mut input, &mut output, false).map_err(CompileError::Load)?;
+        vm.run()
+    };
+    match outcome {
+        RunOutcome::Exited(0) => {}
+        RunOutcome::Exited(value) => return Err(CompileError::Rejected(path.to_owned(), value)),
+        RunOutcome::Faulted(e) => return Err(CompileError::Exec(Box::new(e))),
+        RunOutcome::Paused(_)
+        | RunOutcome::Watchpoint(_)
+        | RunOutcome::OutOfFuel(_)
+        | RunOutcome::TimedOut(_) => {
+            unreachable!("no breakpoints, watchpoints or limits were set")
+        }
+    }
+    Exe::read(&output[..]).map_err(CompileError::BadOutput)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{CompileError, Diagnostic, ErrorKind};
+
+    /// Exit value of `abort` at the given position.
+    fn exit_value(line: u64, col: u64, error: u64) -> u64 {
+        (line * 1000 + col) * 1000 + error
+    }
+
+    fn render(value: u64, source: &str) -> String {
+        let mut out = Vec::new();
+        let diagnostic = Diagnostic::from_exit_value(value).unwrap();
+        diagnostic.render(&mut out, "test.shr", Some(source)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn exit_value_is_split_into_position_and_kind() {
+        let diagnostic = Diagnostic::from_exit_value(exit_value(12, 34, 15)).unwrap();
+        assert_eq!(diagnostic, Diagnostic { line: 12, col: 34, kind: ErrorKind::UnexpectedChar });
+        // columns are 1-based, so the first character of a line is column 1
+        let diagnostic = Diagnostic::from_exit_value(exit_value(1, 1, 2)).unwrap();
+        assert_eq!((diagnostic.line, diagnostic.col), (1, 1));
+        // line numbers are not limited to three digits
+        let diagnostic = Diagnostic::from_exit_value(exit_value(1234, 999, 19)).unwrap();
+        assert_eq!(diagnostic, Diagnostic { line: 1234, col: 999, kind: ErrorKind::FieldNotFound });
+    }
+
+    #[test]
+    fn every_error_code_is_known() {
+        let mut messages = Vec::new();
+        for code in 1..20 {
+            let kind = ErrorKind::from_code(code).unwrap_or_else(|| panic!("code {}", code));
+            let diagnostic = Diagnostic::from_exit_value(exit_value(1, 1, code)).unwrap();
+            assert_eq!(diagnostic.kind, kind);
+            messages.push(kind.to_string());
+        }
+        messages.sort();
+        messages.dedup();
+        assert_eq!(messages.len(), 19);
+    }
+
+    #[test]
+    fn unknown_codes_are_rejected() {
+        assert_eq!(ErrorKind::from_code(0), None);
+        assert_eq!(ErrorKind::from_code(20), None);
+        assert_eq!(Diagnostic::from_exit_value(0), None);
+        assert_eq!(Diagnostic::from_exit_value(exit_value(3, 4, 0)), None);
+        assert_eq!(Diagnostic::from_exit_value(exit_value(3, 4, 999)), None);
+    }
+
+    #[test]
+    fn caret_points_at_column() {
+        let source = "fn main() -> int {\n    retrun 0;\n}\n";
+        let expected = "\
+test.shr:2:5: error: unexpected token
+    retrun 0;
+    ^
+";
+        assert_eq!(render(exit_value(2, 5, 2), source), expected);
+        // column 1 is the first character
+        assert!(render(exit_value(3, 1, 2), source).ends_with("\n}\n^\n"));
+    }
+
+    #[test]
+    fn caret_keeps_tabs_and_ignores_carriage_returns() {
+        let source = "fn main() -> int {\r\n\treturn x;\r\n}\r\n";
+        let expected = "\
+test.shr:2:9: error: no such variable
+\treturn x;
+\t       ^
+";
+        assert_eq!(render(exit_value(2, 9, 10), source), expected);
+    }
+
+    #[test]
+    fn caret_past_end_of_line_is_placed_after_it() {
+        let source = "fn main() -> int {\n";
+        let expected = "\
+test.shr:1:40: error: unexpected token
+fn main() -> int {
+                  ^
+";
+        assert_eq!(render(exit_value(1, 40, 2), source), expected);
+    }
+
+    #[test]
+    fn missing_lines_are_not_shown() {
+        // line past the end of the source
+        assert_eq!(render(exit_value(7, 1, 18), "\n"), "test.shr:7:1: error: no main function\n");
+        // error before the first token
+        assert_eq!(render(17, ""), "test.shr: error: generated code is too long\n");
+    }
+
+    #[test]
+    fn rejection_message_includes_path() {
+        let path = PathBuf::from("src/main.shr");
+        let error = CompileError::Rejected(path.clone(), exit_value(3, 14, 9));
+        assert_eq!(error.to_string(), "src/main.shr:3:14: error: no such function");
+        let error = CompileError::Rejected(path.clone(), 18);
+        assert_eq!(error.to_string(), "src/main.shr: error: no main function");
+        let error = CompileError::Rejected(path, 20);
+        assert_eq!(error.to_string(), "src/main.shr: compiler exited with unknown code 20");
+    }
+}
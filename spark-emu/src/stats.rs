@@ -0,0 +1,259 @@
+//! Execution statistics.
+
+use std::io::{self, Write};
+use std::time::Duration;
+use serde_json::Map;
+use executable::{STACK_SIZE, STACK_START};
+use instruction::{Instr, INSTR_NAMES};
+
+/// Names of syscalls, indexed by syscall number.
+const SYSCALL_NAMES: [&str; 3] = ["exit", "read_byte", "write_byte"];
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub instructions: u64,
+    /// Executed instructions by variant, indexed by `Instr::kind`.
+    pub instr_counts: [u64; INSTR_NAMES.len()],
+    /// Executed syscalls, indexed by syscall number.
+    pub syscalls: [u64; SYSCALL_NAMES.len()],
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub call_depth: u64,
+    pub max_call_depth: u64,
+    /// Lowest value of `rsp` after any instruction.
+    pub lowest_rsp: u64,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            instructions: 0,
+            instr_counts: [0; INSTR_NAMES.len()],
+            syscalls: [0; SYSCALL_NAMES.len()],
+            bytes_read: 0,
+            bytes_written: 0,
+            call_depth: 0,
+            max_call_depth: 0,
+            lowest_rsp: STACK_START + STACK_SIZE,
+        }
+    }
+
+    /// Counts an instruction that is about to be executed.
+    pub fn record(&mut self, instr: Instr) {
+        self.instructions += 1;
+        self.instr_counts[instr.kind()] += 1;
+        match instr {
+            Instr::Call(_) => {
+                self.call_depth += 1;
+                self.max_call_depth = self.max_call_depth.max(self.call_depth);
+            }
+            Instr::Ret => {
+                self.call_depth = self.call_depth.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn record_syscall(&mut self, number: u64) {
+        if let Some(count) = self.syscalls.get_mut(number as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn record_rsp(&mut self, rsp: u64) {
+        self.lowest_rsp = self.lowest_rsp.min(rsp);
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.instr_counts[Instr::Call(0).kind()]
+    }
+
+    pub fn returns(&self) -> u64 {
+        self.instr_counts[Instr::Ret.kind()]
+    }
+
+    /// Bytes of stack used at the deepest point.
+    pub fn stack_used(&self) -> u64 {
+        (STACK_START + STACK_SIZE).saturating_sub(self.lowest_rsp)
+    }
+
+    fn instructions_per_second(&self, elapsed: Duration) -> f64 {
+        let seconds = elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            0.0
+        } else {
+            self.instructions as f64 / seconds
+        }
+    }
+
+    /// Writes human readable statistics. `elapsed` is the wall time it
+    /// took to run the program.
+    pub fn write<W: Write>(&self, mut out: W, elapsed: Duration) -> io::Result<()> {
+        writeln!(out, "instructions executed: {}", self.instructions)?;
+        writeln!(out, "wall time: {:.3}s", elapsed.as_secs_f64())?;
+        writeln!(out, "instructions per second: {:.0}", self.instructions_per_second(elapsed))?;
+        writeln!(out, "calls: {}, returns: {}", self.calls(), self.returns())?;
+        writeln!(out, "maximum call depth: {}", self.max_call_depth)?;
+        writeln!(
+            out,
+            "lowest rsp: {:#x} (STACK_START + {:#x}, {} bytes of stack used)",
+            self.lowest_rsp,
+            self.lowest_rsp.wrapping_sub(STACK_START),
+            self.stack_used(),
+        )?;
+        writeln!(out, "syscalls:")?;
+        for (name, &count) in SYSCALL_NAMES.iter().zip(&self.syscalls) {
+            writeln!(out, "  {:<12} {}", name, count)?;
+        }
+        writeln!(out, "bytes read: {}, bytes written: {}", self.bytes_read, self.bytes_written)?;
+        writeln!(out, "instructions by kind:")?;
+        let mut counts = INSTR_NAMES.iter().zip(&self.instr_counts).collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (name, &count) in counts {
+            if count == 0 {
+                continue;
+            }
+            let percent = count as f64 * 100.0 / self.instructions as f64;
+            writeln!(out, "  {:<20} {:>14} {:>6.2}%", name, count, percent)?;
+        }
+        Ok(())
+    }
+
+    /// Writes statistics as a single JSON object.
+    pub fn write_json<W: Write>(&self, mut out: W, elapsed: Duration) -> io::Result<()> {
+        let instr_counts = INSTR_NAMES
+            .iter()
+            .zip(&self.instr_counts)
+            .map(|(&name, &count)| (name.to_string(), json!(count)))
+            .collect::<Map<_, _>>();
+        let syscalls = SYSCALL_NAMES
+            .iter()
+            .zip(&self.syscalls)
+            .map(|(&name, &count)| (name.to_string(), json!(count)))
+            .collect::<Map<_, _>>();
+        let value = json!({
+            "instructions": self.instructions,
+            "instructions_by_kind": instr_counts,
+            "calls": self.calls(),
+            "returns": self.returns(),
+            "max_call_depth": self.max_call_depth,
+            "lowest_rsp": self.lowest_rsp,
+            "lowest_rsp_offset": self.lowest_rsp.wrapping_sub(STACK_START),
+            "stack_used": self.stack_used(),
+            "syscalls": syscalls,
+            "bytes_read": self.bytes_read,
+            "bytes_written": self.bytes_written,
+            "wall_time_seconds": elapsed.as_secs_f64(),
+            "instructions_per_second": self.instructions_per_second(elapsed),
+        });
+        writeln!(out, "{}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+    use serde_json::{self, Value};
+    use asm::assemble;
+    use instruction::Instr;
+    use vm::{RunOutcome, Vm};
+    use super::Stats;
+
+    fn run(source: &str, input: &[u8]) -> Stats {
+        let exe = assemble(source).unwrap();
+        let mut input = input;
+        let mut output = io::sink();
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.set_stats(true);
+        match vm.run() {
+            RunOutcome::Exited(_) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        vm.stats().unwrap().clone()
+    }
+
+    const PROGRAM: &str = "
+        _start:
+            call f
+            mov rax, 1
+            syscall
+            mov rax, 2
+            syscall
+            mov rax, 1
+            syscall
+            xor rax, rax
+            syscall
+        f:
+            call g
+            ret
+        g:
+            push rax
+            pop rax
+            ret
+    ";
+
+    #[test]
+    fn calls_stack_and_syscalls_are_counted() {
+        let stats = run(PROGRAM, b"x");
+        assert_eq!(stats.instructions, 14);
+        assert_eq!(stats.instr_counts[Instr::Syscall.kind()], 4);
+        assert_eq!((stats.calls(), stats.returns(), stats.max_call_depth), (2, 2, 2));
+        assert_eq!(stats.call_depth, 0);
+        // two return addresses and `rax` pushed in `g`
+        assert_eq!(stats.stack_used(), 24);
+        assert_eq!(stats.syscalls, [1, 2, 1]);
+        // reaching end of input is not a byte read
+        assert_eq!((stats.bytes_read, stats.bytes_written), (1, 1));
+    }
+
+    #[test]
+    fn report_lists_instruction_kinds_by_count() {
+        let stats = run(PROGRAM, b"x");
+        let mut out = Vec::new();
+        stats.write(&mut out, Duration::from_secs(2)).unwrap();
+        let expected = "\
+instructions executed: 14
+wall time: 2.000s
+instructions per second: 7
+calls: 2, returns: 2
+maximum call depth: 2
+lowest rsp: 0x1fffffe8 (STACK_START + 0xfffe8, 24 bytes of stack used)
+syscalls:
+  exit         1
+  read_byte    2
+  write_byte   1
+bytes read: 1, bytes written: 1
+instructions by kind:
+  Syscall                           4  28.57%
+  MovRax                            3  21.43%
+  Call                              2  14.29%
+  Ret                               2  14.29%
+  PopRax                            1   7.14%
+  PushRax                           1   7.14%
+  XorRaxRax                         1   7.14%
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn json_report_has_the_same_counts() {
+        let stats = run(PROGRAM, b"x");
+        let mut out = Vec::new();
+        stats.write_json(&mut out, Duration::from_secs(2)).unwrap();
+        let value: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["instructions"], 14);
+        assert_eq!(value["instructions_by_kind"]["Call"], 2);
+        assert_eq!(value["instructions_by_kind"]["DivRbx"], 0);
+        assert_eq!(value["syscalls"]["read_byte"], 2);
+        assert_eq!(value["stack_used"], 24);
+        assert_eq!(value["max_call_depth"], 2);
+        assert_eq!(value["instructions_per_second"], 7.0);
+    }
+}
@@ -0,0 +1,160 @@
+//! Symbol tables mapping addresses to names.
+//!
+//! Symbol files have one symbol per line, written as a hex address and a
+//! name separated by whitespace, for example `0x10000041 main`. Empty lines
+//! and lines starting with `#` are ignored. `spark-as` writes such files
+//! with `--symbols`.
+//!
+//! Executables do not contain symbols, so when there is no symbol file,
+//! names of functions found by the disassembler are used instead.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use disasm::{Disassembly, LabelKind};
+
+#[derive(Debug)]
+pub enum SymbolsError {
+    /// Line could not be parsed, with one-based line number.
+    BadLine(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for SymbolsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymbolsError::BadLine(line) => write!(f, "invalid symbol on line {}", line),
+            SymbolsError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for SymbolsError {
+    fn from(err: io::Error) -> SymbolsError {
+        SymbolsError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    names: BTreeMap<u64, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Uses names of the entry point and functions found by the
+    /// disassembler.
+    pub fn from_disassembly(disassembly: &Disassembly) -> Symbols {
+        let names = disassembly
+            .labels
+            .iter()
+            .filter(|&(_, label)| label.kind != LabelKind::Local)
+            .map(|(&addr, label)| (addr, label.name.clone()))
+            .collect();
+        Symbols { names }
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, SymbolsError> {
+        let mut symbols = Symbols::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let addr = parts.next().and_then(|addr| {
+                let addr = addr.trim_start_matches("0x").trim_start_matches("0X");
+                u64::from_str_radix(addr, 16).ok()
+            });
+            match (addr, parts.next(), parts.next()) {
+                (Some(addr), Some(name), None) => symbols.insert(addr, name),
+                _ => return Err(SymbolsError::BadLine(index + 1)),
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Symbols, SymbolsError> {
+        Symbols::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for (&addr, name) in &self.names {
+            writeln!(out, "{:#010x} {}", addr, name)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        self.write(&mut out)?;
+        out.flush()
+    }
+
+    pub fn insert(&mut self, addr: u64, name: &str) {
+        self.names.insert(addr, name.to_string());
+    }
+
+    /// Finds the closest symbol at or before `addr`.
+    pub fn lookup(&self, addr: u64) -> Option<(u64, &str)> {
+        self.names
+            .range(..=addr)
+            .next_back()
+            .map(|(&start, name)| (start, name.as_str()))
+    }
+
+    /// Formats address as `0x10000045 <main+4>`, or just the address if
+    /// there is no symbol before it.
+    pub fn format_address(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((start, name)) if start == addr => format!("{:#x} <{}>", addr, name),
+            Some((start, name)) => format!("{:#x} <{}+{}>", addr, name, addr - start),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Symbols, SymbolsError};
+
+    #[test]
+    fn written_symbols_are_parsed_back() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x1000_0041, "main");
+        symbols.insert(0x1000_0000, "_start");
+        let mut text = Vec::new();
+        symbols.write(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text, "0x10000000 _start\n0x10000041 main\n");
+        let parsed = Symbols::parse(&format!("# comment\n\n{}  1000000a   helper  \n", text)).unwrap();
+        assert_eq!(parsed.lookup(0x1000_000a), Some((0x1000_000a, "helper")));
+        assert_eq!(parsed.lookup(0x1000_0041), Some((0x1000_0041, "main")));
+    }
+
+    #[test]
+    fn bad_lines_are_reported() {
+        for text in &["0x10000000", "main 0x10000000", "0x10000000 main extra"] {
+            match Symbols::parse(&format!("0x0 zero\n{}", text)) {
+                Err(SymbolsError::BadLine(2)) => {}
+                other => panic!("{:?}: {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn addresses_are_formatted_relative_to_closest_symbol() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x1000_0000, "_start");
+        symbols.insert(0x1000_0041, "main");
+        assert_eq!(symbols.format_address(0xfff_ffff), "0xfffffff");
+        assert_eq!(symbols.format_address(0x1000_0000), "0x10000000 <_start>");
+        assert_eq!(symbols.format_address(0x1000_0040), "0x10000040 <_start+64>");
+        assert_eq!(symbols.format_address(0x1000_0045), "0x10000045 <main+4>");
+    }
+}
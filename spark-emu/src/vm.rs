@@ -1,8 +1,17 @@
+use std::collections::HashSet;
 use std::fmt;
 use std::io::{self, Read, Write};
 use std::iter::FromIterator;
 use std::num::Wrapping;
+use std::time::Instant;
+use backtrace::{Backtrace, Fault};
+use blocks::{BlockCache, Exit};
+use history::History;
 use instruction::Instr;
+use native::{NativeError, NativeMachine, Stop};
+use profile::Profile;
+use stats::Stats;
+use watch::{Access, WatchHit, Watchpoint};
 
 use executable::{Exe, CODE_START, DATA_START, STACK_START, STACK_SIZE};
 
@@ -84,6 +93,15 @@ impl From<io::Error> for ExecError {
 
 pub type ExecResult<T> = Result<T, ExecError>;
 
+/// State of the machine after a cycle.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    /// Program invoked the exit syscall with the given value in `rbx`. A
+    /// halted machine does not execute any more instructions.
+    Halted(u64),
+}
+
 #[derive(Clone)]
 struct DataSection {
     start_address: u64,
@@ -94,11 +112,8 @@ impl DataSection {
     fn new(data: Vec<u8>) -> Result<Self, LoadError> {
         assert_eq!(STACK_START + STACK_SIZE, DATA_START);
         assert_eq!(STACK_SIZE % 8, 0);
-        let mut converted_data = Vec::new();
         // zero initialize stack
-        for _ in 0..(STACK_SIZE / 8) {
-            converted_data.push(0u64);
-        }
+        let mut converted_data = vec![0u64; (STACK_SIZE / 8) as usize];
         if data.len() % 8 != 0 {
             return Err(LoadError::BadDataLength(data.len()));
         }
@@ -110,7 +125,7 @@ impl DataSection {
             curr += byte << (taken * 8);
             taken += 1;
             pos += 1;
-            if taken % 8 == 0 {
+            if taken == 8 {
                 converted_data.push(curr);
                 taken = 0;
                 curr = 0;
@@ -123,7 +138,16 @@ impl DataSection {
     }
 
     fn access(&mut self, addr: u64) -> ExecResult<&mut u64> {
-        if self.data.len() == 0 {
+        let index = self.index(addr)?;
+        Ok(&mut self.data[index])
+    }
+
+    fn read(&self, addr: u64) -> ExecResult<u64> {
+        self.index(addr).map(|index| self.data[index])
+    }
+
+    fn index(&self, addr: u64) -> ExecResult<usize> {
+        if self.data.is_empty() {
             return Err(ExecError::BadDataAccess(addr));
         }
         let last_address = self.start_address + (self.data.len() - 1) as u64 * 8;
@@ -134,24 +158,56 @@ impl DataSection {
         if addr2 % 8 != 0 {
             return Err(ExecError::MisalignedDataAccess(addr));
         }
-        Ok(&mut self.data[(addr2 / 8) as usize])
+        Ok((addr2 / 8) as usize)
     }
 }
 
+/// Decoded instruction together with its length in bytes.
+#[derive(Debug, Copy, Clone)]
+struct CachedInstr {
+    instr: Instr,
+    len: u64,
+}
+
 #[derive(Clone)]
 struct CodeSection {
     start_address: u64,
     data: Vec<u8>,
+    /// Instructions decoded so far, indexed by offset in `data`. Code
+    /// section cannot be modified, so they never need to be decoded again.
+    decoded: Vec<Option<CachedInstr>>,
 }
 
 impl CodeSection {
     fn new(data: Vec<u8>) -> Self {
         CodeSection {
             start_address: CODE_START,
+            decoded: vec![None; data.len()],
             data,
         }
     }
 
+    fn decode(&self, addr: u64) -> ExecResult<Instr> {
+        let code_view = self.load_slice(addr)?;
+        if let Some(instr) = Instr::decode(code_view) {
+            Ok(instr)
+        } else {
+            let code = code_view.iter().cloned().take(10).collect();
+            Err(ExecError::InvalidInstruction(code))
+        }
+    }
+
+    fn decode_cached(&mut self, addr: u64) -> ExecResult<CachedInstr> {
+        let offset = addr.wrapping_sub(self.start_address) as usize;
+        if let Some(&Some(cached)) = self.decoded.get(offset) {
+            return Ok(cached);
+        }
+        let instr = self.decode(addr)?;
+        let cached = CachedInstr { instr, len: instr.len() };
+        self.decoded[offset] = Some(cached);
+        Ok(cached)
+    }
+
     fn load_slice(&self, addr: u64) -> ExecResult<&[u8]> {
         if addr < self.start_address || addr >= self.start_address + self.data.len() as u64 {
             return Err(ExecError::BadCodeRead(addr));
@@ -161,6 +217,9 @@ impl CodeSection {
     }
 }
 
+/// How often `Vm::run` checks the deadline, in instructions.
+pub const DEADLINE_CHECK_INTERVAL: u64 = 0x10000;
+
 pub struct Vm<'a> {
     rip: Wrapping<u64>,
     rax: Wrapping<u64>,
@@ -172,19 +231,86 @@ pub struct Vm<'a> {
     zero_flag: bool,
     code: CodeSection,
     data: DataSection,
-    stdin: &'a mut (Read + 'a),
-    stdout: &'a mut (Write + 'a),
+    stdin: &'a mut (dyn Read + 'a),
+    stdout: &'a mut (dyn Write + 'a),
     have_pending_writes: bool,
     trace_instructions: bool,
+    /// Where traced instructions are written, stderr if `None`.
+    trace_output: Option<&'a mut (dyn Write + 'a)>,
+    exit_code: Option<u64>,
+    breakpoints: HashSet<u64>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hits: Vec<WatchHit>,
+    /// Address of the instruction being executed.
+    instr_addr: u64,
+    history: Option<History>,
+    /// Whether `execute_instr` has to call `collect_before` and
+    /// `collect_after`, see `is_collecting`.
+    collecting: bool,
+    profile: Option<Profile>,
+    /// Execution counts of instructions, indexed by offset in the code.
+    hit_counts: Option<Vec<u64>>,
+    /// Counts of taken conditional jumps, indexed like `hit_counts`.
+    taken_counts: Option<Vec<u64>>,
+    stats: Option<Stats>,
+    instruction_count: u64,
+    max_instructions: Option<u64>,
+    deadline: Option<Instant>,
+    use_decode_cache: bool,
+    blocks: BlockCache,
+    use_blocks: bool,
+    /// Instruction count at which `run` should next check the deadline.
+    next_deadline_check: u64,
+    /// Cleared once native execution hands the program over to the
+    /// interpreter.
+    use_native: bool,
+    /// Set when the machine was paused at the current instruction or
+    /// stepped onto it, in which case `run` does not stop at a breakpoint
+    /// on the instruction it starts from.
+    resuming: bool,
+    native_error: Option<NativeError>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Registers {
+    pub rip: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub below_flag: bool,
+    pub zero_flag: bool,
+}
+
+/// The reason why `Vm::run` returned.
+#[derive(Debug)]
+pub enum RunOutcome {
+    /// Program invoked the exit syscall with the given code.
+    Exited(u64),
+    /// Program could not continue because of an execution error.
+    Faulted(Fault),
+    /// Execution reached a breakpoint at the given address. The instruction
+    /// at that address is not yet executed.
+    Paused(u64),
+    /// Last executed instruction triggered these watchpoints.
+    Watchpoint(Vec<WatchHit>),
+    /// Program executed as many instructions as allowed by
+    /// `Vm::set_max_instructions`.
+    OutOfFuel(Backtrace),
+    /// Program was still running at the deadline set by
+    /// `Vm::set_deadline`.
+    TimedOut(Backtrace),
 }
 
 impl<'a> Vm<'a> {
     pub fn new(
         exe: Exe,
-        stdin: &'a mut (Read + 'a),
-        stdout: &'a mut (Write + 'a),
+        stdin: &'a mut (dyn Read + 'a),
+        stdout: &'a mut (dyn Write + 'a),
         trace_instructions: bool,
     ) -> Result<Self, LoadError> {
+        let blocks = BlockCache::new(exe.code.len());
         let code = CodeSection::new(exe.code);
         let data = DataSection::new(exe.data)?;
         Ok(Vm {
@@ -202,27 +328,659 @@ impl<'a> Vm<'a> {
             stdout,
             have_pending_writes: false,
             trace_instructions,
+            trace_output: None,
+            exit_code: None,
+            breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            instr_addr: CODE_START,
+            history: None,
+            collecting: trace_instructions,
+            profile: None,
+            hit_counts: None,
+            taken_counts: None,
+            stats: None,
+            instruction_count: 0,
+            max_instructions: None,
+            deadline: None,
+            use_decode_cache: true,
+            blocks,
+            use_blocks: true,
+            next_deadline_check: 0,
+            use_native: false,
+            native_error: None,
+            resuming: false,
         })
     }
 
-    pub fn cycle(&mut self) -> ExecResult<()> {
-        let instr = {
-            let code_view = self.code.load_slice(self.rip.0)?;
-            if let Some(instr) = Instr::decode(code_view) {
-                instr
+    /// Address of the next instruction to be executed.
+    pub fn rip(&self) -> u64 {
+        self.rip.0
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            rip: self.rip.0,
+            rax: self.rax.0,
+            rbx: self.rbx.0,
+            rdx: self.rdx.0,
+            rsp: self.rsp.0,
+            rbp: self.rbp.0,
+            below_flag: self.below_flag,
+            zero_flag: self.zero_flag,
+        }
+    }
+
+    pub fn set_registers(&mut self, regs: Registers) {
+        self.rip = Wrapping(regs.rip);
+        self.rax = Wrapping(regs.rax);
+        self.rbx = Wrapping(regs.rbx);
+        self.rdx = Wrapping(regs.rdx);
+        self.rsp = Wrapping(regs.rsp);
+        self.rbp = Wrapping(regs.rbp);
+        self.below_flag = regs.below_flag;
+        self.zero_flag = regs.zero_flag;
+    }
+
+    /// Reads a single byte from the code, stack or data section.
+    pub fn load_byte(&self, addr: u64) -> Option<u8> {
+        if let Ok(code) = self.code.load_slice(addr) {
+            return Some(code[0]);
+        }
+        let word = self.data.read(addr & !7).ok()?;
+        Some((word >> ((addr & 7) * 8)) as u8)
+    }
+
+    /// Reads a quad word from the stack or data section.
+    pub fn read_data(&self, addr: u64) -> ExecResult<u64> {
+        self.data.read(addr)
+    }
+
+    /// Decodes the instruction at `rip`.
+    pub fn current_instr(&self) -> ExecResult<Instr> {
+        self.instr_at(self.rip.0)
+    }
+
+    pub fn instr_at(&self, addr: u64) -> ExecResult<Instr> {
+        self.code.decode(addr)
+    }
+
+    /// Contents of the code section.
+    pub fn code(&self) -> &[u8] {
+        &self.code.data
+    }
+
+    pub fn status(&self) -> Status {
+        match self.exit_code {
+            Some(code) => Status::Halted(code),
+            None => Status::Running,
+        }
+    }
+
+    /// Starts recording last `capacity` executed instructions, or stops
+    /// recording if `capacity` is 0.
+    ///
+    /// Registers are copied after every instruction, which makes execution
+    /// noticeably slower even with small `capacity`.
+    pub fn set_history(&mut self, capacity: usize) {
+        self.history = if capacity == 0 {
+            None
+        } else {
+            Some(History::new(capacity))
+        };
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Starts counting executed instructions per call stack, discarding
+    /// previously collected counts, or stops counting.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profile = if enabled { Some(Profile::new()) } else { None };
+        self.collecting = self.is_collecting();
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Starts counting how many times each instruction is executed and
+    /// each conditional jump is taken, discarding previous counts, or stops
+    /// counting.
+    pub fn set_hit_counting(&mut self, enabled: bool) {
+        if enabled {
+            self.hit_counts = Some(vec![0; self.code.data.len()]);
+            self.taken_counts = Some(vec![0; self.code.data.len()]);
+        } else {
+            self.hit_counts = None;
+            self.taken_counts = None;
+        }
+        self.collecting = self.is_collecting();
+    }
+
+    /// Execution counts indexed by offset of the instruction from
+    /// `CODE_START`.
+    pub fn hit_counts(&self) -> Option<&[u64]> {
+        self.hit_counts.as_deref()
+    }
+
+    /// Counts of taken `jz` and `jnz` instructions, indexed like
+    /// `hit_counts`.
+    pub fn taken_counts(&self) -> Option<&[u64]> {
+        self.taken_counts.as_deref()
+    }
+
+    /// Starts collecting execution statistics, discarding previously
+    /// collected ones, or stops collecting.
+    pub fn set_stats(&mut self, enabled: bool) {
+        self.stats = if enabled { Some(Stats::new()) } else { None };
+        self.collecting = self.is_collecting();
+    }
+
+    pub fn stats(&self) -> Option<&Stats> {
+        self.stats.as_ref()
+    }
+
+    /// Writes traced instructions to `output` instead of stderr.
+    pub fn set_trace_output(&mut self, output: &'a mut (dyn Write + 'a)) {
+        self.trace_output = Some(output);
+    }
+
+    /// Enables or disables caching of decoded instructions. The cache is
+    /// enabled by default, and disabling it is only useful to measure how
+    /// much it helps.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.use_decode_cache = enabled;
+    }
+
+    /// Enables or disables running whole basic blocks at a time in `run`.
+    /// This is enabled by default, but only used while nothing needs to
+    /// observe individual instructions: there must be no breakpoints,
+    /// watchpoints, tracing, profiling, hit counting or statistics.
+    /// Results are the same either way.
+    pub fn set_block_execution(&mut self, enabled: bool) {
+        self.use_blocks = enabled;
+    }
+
+    /// Enables or disables running the program directly on the host CPU
+    /// in `run`, see the `native` module. Like block execution, it is only
+    /// used when nothing needs to observe individual instructions, and
+    /// also not with an instruction limit or history. Instructions executed
+    /// natively are not counted.
+    ///
+    /// If native execution is not possible, `run` uses the interpreter
+    /// and the reason is available from `native_error`.
+    pub fn set_native_execution(&mut self, enabled: bool) {
+        self.use_native = enabled;
+    }
+
+    /// Why native execution enabled with `set_native_execution` could not
+    /// be used.
+    pub fn native_error(&self) -> Option<&NativeError> {
+        self.native_error.as_ref()
+    }
+
+    /// Number of instructions executed so far.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    pub fn max_instructions(&self) -> Option<u64> {
+        self.max_instructions
+    }
+
+    /// Makes `run` stop with `RunOutcome::OutOfFuel` once `limit`
+    /// instructions were executed in total, or removes the limit.
+    pub fn set_max_instructions(&mut self, limit: Option<u64>) {
+        self.max_instructions = limit;
+    }
+
+    /// Makes `run` stop with `RunOutcome::TimedOut` if the program is still
+    /// running at `deadline`. The clock is checked only every
+    /// `DEADLINE_CHECK_INTERVAL` instructions.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.next_deadline_check = self.instruction_count;
+    }
+
+    /// Makes `run` stop before executing the instruction at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Makes `run` stop after an instruction accesses memory watched by
+    /// `watchpoint`.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Removes all watchpoints on `addr`.
+    pub fn remove_watchpoint(&mut self, addr: u64) {
+        self.watchpoints.retain(|watchpoint| watchpoint.addr != addr);
+    }
+
+    /// Returns watchpoints triggered since the last call. `cycle` does not
+    /// stop on watchpoints, so callers that step the machine themselves
+    /// should check this after each cycle.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        ::std::mem::take(&mut self.watch_hits)
+    }
+
+    /// Runs the program until it exits, faults, or hits a breakpoint or a
+    /// watchpoint.
+    ///
+    /// A breakpoint on the first instruction of the program stops it
+    /// before anything is executed. Otherwise the instruction at `rip` is
+    /// executed even if there is a breakpoint on it, so that calling `run`
+    /// again after `RunOutcome::Paused` or after stepping onto a breakpoint
+    /// continues execution instead of pausing at the same place. After
+    /// `RunOutcome::Watchpoint` a breakpoint on `rip` is reported.
+    pub fn run(&mut self) -> RunOutcome {
+        if self.use_native && self.can_run_native() {
+            self.use_native = false;
+            match self.run_native() {
+                Ok(Some(outcome)) => return outcome,
+                Ok(None) => {}
+                Err(e) => self.native_error = Some(e),
+            }
+        }
+        let mut first = self.resuming;
+        self.resuming = true;
+        loop {
+            if !first && !self.breakpoints.is_empty() && self.breakpoints.contains(&self.rip.0) {
+                return RunOutcome::Paused(self.rip.0);
+            }
+            first = false;
+            // number of instructions that can run before the next check
+            let mut budget = u64::MAX;
+            if self.exit_code.is_none() {
+                if let Some(limit) = self.max_instructions {
+                    if self.instruction_count >= limit {
+                        return RunOutcome::OutOfFuel(Backtrace::new(self));
+                    }
+                    budget = limit - self.instruction_count;
+                }
+                if let Some(deadline) = self.deadline {
+                    if self.instruction_count >= self.next_deadline_check {
+                        if Instant::now() >= deadline {
+                            return RunOutcome::TimedOut(Backtrace::new(self));
+                        }
+                        self.next_deadline_check = self.instruction_count + DEADLINE_CHECK_INTERVAL;
+                    }
+                    budget = budget.min(self.next_deadline_check - self.instruction_count);
+                }
+            }
+            let result = if self.can_run_blocks() {
+                self.run_blocks(budget)
             } else {
-                let code = code_view.iter().cloned().take(10).collect();
-                return Err(ExecError::InvalidInstruction(code));
+                self.run_cycles(budget)
+            };
+            match result {
+                Ok(Status::Running) if !self.watch_hits.is_empty() => {
+                    // a breakpoint on the next instruction is still reported
+                    // by the next `run`
+                    self.resuming = false;
+                    return RunOutcome::Watchpoint(self.take_watch_hits());
+                }
+                Ok(Status::Running) => {}
+                Ok(Status::Halted(code)) => return RunOutcome::Exited(code),
+                Err(e) => return RunOutcome::Faulted(Fault::new(self, e)),
+            }
+        }
+    }
+
+    /// Like `run`, but returns `None` if the program is still running
+    /// after executing `count` more instructions, so that callers can do
+    /// something else in between.
+    pub fn run_for(&mut self, count: u64) -> Option<RunOutcome> {
+        let limit = self.max_instructions;
+        let slice_end = self.instruction_count.saturating_add(count);
+        self.max_instructions = Some(limit.map_or(slice_end, |limit| limit.min(slice_end)));
+        let outcome = self.run();
+        self.max_instructions = limit;
+        match outcome {
+            RunOutcome::OutOfFuel(_) if limit.is_none_or(|limit| self.instruction_count < limit) => None,
+            outcome => Some(outcome),
+        }
+    }
+
+    /// Whether anything needs to see every executed instruction.
+    fn is_instrumented(&self) -> bool {
+        !self.breakpoints.is_empty()
+            || !self.watchpoints.is_empty()
+            || self.trace_instructions
+            || self.profile.is_some()
+            || self.hit_counts.is_some()
+            || self.stats.is_some()
+    }
+
+    fn can_run_blocks(&self) -> bool {
+        self.use_blocks && !self.is_instrumented()
+    }
+
+    fn can_run_native(&self) -> bool {
+        self.exit_code.is_none()
+            && self.max_instructions.is_none()
+            && self.history.is_none()
+            && !self.is_instrumented()
+    }
+
+    /// Runs the program natively until it halts, faults, times out or
+    /// reaches an instruction that the interpreter has to execute. Returns
+    /// `None` in the last case, and leaves the machine in the state where
+    /// the interpreter can continue.
+    fn run_native(&mut self) -> Result<Option<RunOutcome>, NativeError> {
+        let mut machine = NativeMachine::new(&self.code.data, &self.data.data, self.deadline)?;
+        let mut regs = self.registers();
+        let stop = loop {
+            let stop = machine.run(&mut regs);
+            self.set_registers(regs);
+            if stop != Stop::Syscall {
+                break Ok(stop);
+            }
+            self.rip += Wrapping(Instr::Syscall.len());
+            if let Err(e) = self.syscall() {
+                self.rip = Wrapping(regs.rip);
+                break Err(e);
+            }
+            if self.exit_code.is_some() {
+                break Ok(stop);
             }
+            regs = self.registers();
         };
-        self.execute_instr(instr)
+        machine.copy_memory_to(&mut self.data.data);
+        Ok(match stop {
+            Ok(Stop::Syscall) => self.exit_code.map(RunOutcome::Exited),
+            Ok(Stop::Trap) => None,
+            Ok(Stop::TimedOut) => Some(RunOutcome::TimedOut(Backtrace::new(self))),
+            Err(e) => Some(RunOutcome::Faulted(Fault::new(self, e))),
+        })
+    }
+
+    /// Executes single instructions until the program halts, `budget`
+    /// instructions were executed, a watchpoint is triggered or the next
+    /// instruction has a breakpoint.
+    fn run_cycles(&mut self, budget: u64) -> ExecResult<Status> {
+        for _ in 0..budget {
+            let status = self.cycle()?;
+            if status != Status::Running || !self.watch_hits.is_empty() {
+                return Ok(status);
+            }
+            if !self.breakpoints.is_empty() && self.breakpoints.contains(&self.rip.0) {
+                break;
+            }
+        }
+        Ok(Status::Running)
     }
 
-    fn execute_instr(&mut self, instr: Instr) -> ExecResult<()> {
+    /// Runs whole basic blocks until the program halts or `budget`
+    /// instructions were executed. Has the same effect as calling `cycle`
+    /// the same number of times.
+    fn run_blocks(&mut self, budget: u64) -> ExecResult<Status> {
+        let mut blocks = ::std::mem::replace(&mut self.blocks, BlockCache::new(0));
+        let result = self.run_blocks_in(&mut blocks, budget);
+        self.blocks = blocks;
+        result
+    }
+
+    fn run_blocks_in(&mut self, blocks: &mut BlockCache, mut budget: u64) -> ExecResult<Status> {
+        // block to run next if it is already known, and the branch of the
+        // previous block that led to it
+        let mut next = None;
+        let mut came_from = None;
+        loop {
+            if let Some(code) = self.exit_code {
+                return Ok(Status::Halted(code));
+            }
+            let index = match next {
+                Some(index) => index,
+                None => {
+                    let code = &self.code;
+                    let index = blocks.find(self.rip.0, |addr| code.decode(addr))?;
+                    if let Some((from, slot)) = came_from {
+                        blocks.link(from, slot, index);
+                    }
+                    index
+                }
+            };
+            let block = blocks.block(index);
+            if block.len() > budget {
+                // finish the budget one instruction at a time
+                for _ in 0..budget {
+                    if let Status::Halted(code) = self.cycle()? {
+                        return Ok(Status::Halted(code));
+                    }
+                }
+                return Ok(Status::Running);
+            }
+            budget -= block.len();
+
+            for &(addr, instr) in &block.ops {
+                self.instruction_count += 1;
+                if let Err(e) = self.execute_op(instr).and_then(|()| self.check_stack()) {
+                    self.rip = Wrapping(addr);
+                    return Err(e);
+                }
+                if self.history.is_some() {
+                    // history includes `rip` after each instruction
+                    self.rip = Wrapping(addr + instr.len());
+                    self.record_history(addr, instr);
+                }
+            }
+            if let Exit::Fallthrough { next: addr } = block.exit {
+                self.rip = Wrapping(addr);
+                next = None;
+                came_from = None;
+                continue;
+            }
+
+            self.instruction_count += 1;
+            // which successor of the block is taken, if it has a slot
+            let slot = match self.run_exit(block.exit) {
+                Ok(slot) => slot,
+                Err(e) => {
+                    self.rip = Wrapping(block.exit_addr);
+                    return Err(e);
+                }
+            };
+            self.record_history(block.exit_addr, block.exit_instr);
+            next = slot.and_then(|slot| block.successor(slot));
+            came_from = slot.map(|slot| (index, slot));
+        }
+    }
+
+    /// Executes the instruction ending a block, setting `rip` to the next
+    /// instruction. Returns the successor slot that execution continues to.
+    fn run_exit(&mut self, exit: Exit) -> ExecResult<Option<usize>> {
+        let slot = match exit {
+            Exit::Call { target, return_addr } => {
+                self.push(return_addr)?;
+                self.rip = Wrapping(target);
+                Some(0)
+            }
+            Exit::Jmp { target } => {
+                self.rip = Wrapping(target);
+                Some(0)
+            }
+            Exit::Jz { target, next } => {
+                self.jump_if(self.zero_flag, target, next)
+            }
+            Exit::Jnz { target, next } => {
+                self.jump_if(!self.zero_flag, target, next)
+            }
+            Exit::Ret => {
+                self.rip = Wrapping(self.pop()?);
+                None
+            }
+            Exit::Syscall { next } => {
+                self.syscall()?;
+                self.rip = Wrapping(next);
+                Some(1)
+            }
+            Exit::Fallthrough { next } => {
+                self.rip = Wrapping(next);
+                Some(1)
+            }
+        };
+        self.check_stack()?;
+        Ok(slot)
+    }
+
+    fn jump_if(&mut self, condition: bool, target: u64, next: u64) -> Option<usize> {
+        if condition {
+            self.rip = Wrapping(target);
+            Some(0)
+        } else {
+            self.rip = Wrapping(next);
+            Some(1)
+        }
+    }
+
+    /// Executes a single instruction. If it fails, `rip` is left pointing
+    /// at the faulting instruction.
+    pub fn cycle(&mut self) -> ExecResult<Status> {
+        if let Some(code) = self.exit_code {
+            return Ok(Status::Halted(code));
+        }
+        let rip = self.rip;
+        self.instr_addr = rip.0;
+        self.resuming = true;
+        let CachedInstr { instr, len } = if self.use_decode_cache {
+            self.code.decode_cached(rip.0)?
+        } else {
+            let instr = self.current_instr()?;
+            CachedInstr { instr, len: instr.len() }
+        };
+        self.instruction_count += 1;
+        if let Err(e) = self.execute_instr(instr, len) {
+            self.rip = rip;
+            return Err(e);
+        }
+        self.record_history(rip.0, instr);
+        Ok(self.status())
+    }
+
+    fn record_history(&mut self, addr: u64, instr: Instr) {
+        if self.history.is_some() {
+            self.record_history_entry(addr, instr);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn record_history_entry(&mut self, addr: u64, instr: Instr) {
+        let registers = self.registers();
+        if let Some(ref mut history) = self.history {
+            history.record(addr, instr, registers);
+        }
+    }
+
+    fn execute_instr(&mut self, instr: Instr, len: u64) -> ExecResult<()> {
+        let collecting = self.collecting;
+        if collecting {
+            self.collect_before(instr);
+        }
+        self.rip += Wrapping(len);
+        match instr {
+            Instr::Call(offset) => {
+                let return_addr = self.rip.0;
+                self.push(return_addr)?;
+                self.rip += Wrapping(offset);
+            }
+            Instr::Jmp(offset) => {
+                self.rip += Wrapping(offset);
+            }
+            Instr::Jnz(offset) => {
+                if !self.zero_flag {
+                    self.rip += Wrapping(offset);
+                }
+            }
+            Instr::Jz(offset) => {
+                if self.zero_flag {
+                    self.rip += Wrapping(offset);
+                }
+            }
+            Instr::Ret => {
+                self.rip = Wrapping(self.pop()?);
+            }
+            Instr::Syscall => self.syscall()?,
+            other => self.execute_op(other)?,
+        }
+        if collecting {
+            self.collect_after(instr);
+        }
+        self.check_stack()
+    }
+
+    /// Whether tracing, profiling, hit counting or statistics need to see
+    /// every executed instruction. Collection is kept out of line so that
+    /// it does not slow down `execute_instr` when nothing is collected, and
+    /// the result is cached in `collecting` by the setters.
+    fn is_collecting(&self) -> bool {
+        self.trace_instructions
+            || self.profile.is_some()
+            || self.hit_counts.is_some()
+            || self.stats.is_some()
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn collect_before(&mut self, instr: Instr) {
         if self.trace_instructions {
-            eprintln!("rip = {:#x}, instruction: {}", self.rip.0, instr);
+            let rip = self.rip.0;
+            match self.trace_output {
+                // the trace is only diagnostic output, like on stderr
+                Some(ref mut output) => {
+                    let _ = writeln!(output, "rip = {:#x}, instruction: {}", rip, instr);
+                }
+                None => eprintln!("rip = {:#x}, instruction: {}", rip, instr),
+            }
+        }
+        if let Some(ref mut profile) = self.profile {
+            profile.count();
+        }
+        if let Some(ref mut hit_counts) = self.hit_counts {
+            hit_counts[(self.instr_addr - CODE_START) as usize] += 1;
+        }
+        if let Some(ref mut stats) = self.stats {
+            stats.record(instr);
+        }
+    }
+
+    /// Called after `instr` was executed successfully.
+    #[cold]
+    #[inline(never)]
+    fn collect_after(&mut self, instr: Instr) {
+        if let Some(ref mut profile) = self.profile {
+            match instr {
+                Instr::Call(_) => profile.enter(self.rip.0),
+                Instr::Ret => profile.leave(),
+                _ => {}
+            }
+        }
+        if let Some(ref mut taken_counts) = self.taken_counts {
+            // flags are not changed by jumps
+            let taken = match instr {
+                Instr::Jz(_) => self.zero_flag,
+                Instr::Jnz(_) => !self.zero_flag,
+                _ => false,
+            };
+            if taken {
+                taken_counts[(self.instr_addr - CODE_START) as usize] += 1;
+            }
         }
-        self.rip += Wrapping(instr.len());
+        if let Some(ref mut stats) = self.stats {
+            stats.record_rsp(self.rsp.0);
+        }
+    }
+
+    /// Executes an instruction that neither reads nor changes `rip`.
+    fn execute_op(&mut self, instr: Instr) -> ExecResult<()> {
         match instr {
             Instr::AddRaxRbx => {
                 self.rax += self.rbx;
@@ -230,11 +988,6 @@ impl<'a> Vm<'a> {
             Instr::AddRsp(value) => {
                 self.rsp += Wrapping(value);
             }
-            Instr::Call(offset) => {
-                let return_addr = self.rip.0;
-                self.push(return_addr)?;
-                self.rip += Wrapping(offset);
-            }
             Instr::CmpRaxRbx => {
                 self.below_flag = self.rax < self.rbx;
                 self.zero_flag = self.rax == self.rbx;
@@ -247,20 +1000,7 @@ impl<'a> Vm<'a> {
                     return Err(ExecError::DivByZero);
                 }
                 self.rdx = self.rax % self.rbx;
-                self.rax = self.rax / self.rbx;
-            }
-            Instr::Jmp(offset) => {
-                self.rip += Wrapping(offset);
-            }
-            Instr::Jnz(offset) => {
-                if !self.zero_flag {
-                    self.rip += Wrapping(offset);
-                }
-            }
-            Instr::Jz(offset) => {
-                if self.zero_flag {
-                    self.rip += Wrapping(offset);
-                }
+                self.rax /= self.rbx;
             }
             Instr::LeaRaxRbpOffset(offset) => {
                 self.rax = self.rbp + Wrapping(offset);
@@ -270,26 +1010,26 @@ impl<'a> Vm<'a> {
             }
             Instr::MovRaxOffsetRbx(offset) => {
                 let addr = (self.rax + Wrapping(offset)).0;
-                *self.data.access(addr)? = self.rbx.0;
+                self.store(addr, self.rbx.0)?;
             }
             Instr::MovRaxQwordRsp => {
-                let value = *self.data.access(self.rsp.0)?;
+                let value = self.load(self.rsp.0)?;
                 self.rax = Wrapping(value);
             }
             Instr::MovRaxRspOffset(offset) => {
                 let addr = (self.rsp + Wrapping(offset)).0;
-                self.rax = Wrapping(*self.data.access(addr)?);
+                self.rax = Wrapping(self.load(addr)?);
             }
             Instr::MovRbpRsp => {
                 self.rbp = self.rsp;
             }
             Instr::MovRbxRspRaxOffset(offset) => {
                 let addr = (self.rsp + self.rax + Wrapping(offset)).0;
-                self.rbp = Wrapping(*self.data.access(addr)?);
+                self.rbx = Wrapping(self.load(addr)?);
             }
             Instr::MovRspOffsetRbx(offset) => {
                 let addr = (self.rsp + Wrapping(offset)).0;
-                *self.data.access(addr)? = self.rbx.0;
+                self.store(addr, self.rbx.0)?;
             }
             Instr::MulRbx => {
                 self.rax *= self.rbx;
@@ -307,12 +1047,12 @@ impl<'a> Vm<'a> {
                 self.rdx = Wrapping(self.pop()?);
             }
             Instr::PushQwordRax => {
-                let value = *self.data.access(self.rax.0)?;
+                let value = self.load(self.rax.0)?;
                 self.push(value)?;
             }
             Instr::PushQwordRaxOffset(offset) => {
                 let addr = (self.rax + Wrapping(offset)).0;
-                let value = *self.data.access(addr)?;
+                let value = self.load(addr)?;
                 self.push(value)?;
             }
             Instr::PushRax => {
@@ -331,9 +1071,6 @@ impl<'a> Vm<'a> {
                 let rdx = self.rdx.0;
                 self.push(rdx)?;
             }
-            Instr::Ret => {
-                self.rip = Wrapping(self.pop()?);
-            }
             Instr::SetbDl => {
                 self.rdx &= Wrapping(!0xFF);
                 if self.below_flag {
@@ -367,30 +1104,57 @@ impl<'a> Vm<'a> {
             Instr::XorRdxRdx => {
                 self.rdx = Wrapping(0);
             }
-            Instr::Syscall => {
-                match self.rax.0 {
-                    0 => { // exit
-                        let arg = self.rbx.0;
-                        ::std::process::exit(arg as i32);
-                    }
-                    1 => { // read_byte
-                        if self.have_pending_writes {
-                            self.stdout.flush()?;
-                        }
-                        let value = self.read_byte()?;
-                        self.rbx = Wrapping(value);
-                    }
-                    2 => { // write_byte
-                        let value = (self.rbx.0 & 0xFF) as u8;
-                        self.stdout.write(&[value])?;
-                        self.have_pending_writes = true;
-                    }
-                    other => {
-                        return Err(ExecError::InvalidSyscall(other));
+            Instr::Call(_)
+            | Instr::Jmp(_)
+            | Instr::Jnz(_)
+            | Instr::Jz(_)
+            | Instr::Ret
+            | Instr::Syscall => unreachable!("{} changes rip", instr),
+        }
+        Ok(())
+    }
+
+    fn syscall(&mut self) -> ExecResult<()> {
+        if let Some(ref mut stats) = self.stats {
+            stats.record_syscall(self.rax.0);
+        }
+        match self.rax.0 {
+            0 => { // exit
+                if self.have_pending_writes {
+                    self.stdout.flush()?;
+                    self.have_pending_writes = false;
+                }
+                self.exit_code = Some(self.rbx.0);
+            }
+            1 => { // read_byte
+                if self.have_pending_writes {
+                    self.stdout.flush()?;
+                }
+                let value = self.read_byte()?;
+                self.rbx = Wrapping(value);
+                if let Some(ref mut stats) = self.stats {
+                    // 256 means end of input
+                    if value < 256 {
+                        stats.bytes_read += 1;
                     }
                 }
             }
+            2 => { // write_byte
+                let value = (self.rbx.0 & 0xFF) as u8;
+                self.stdout.write_all(&[value])?;
+                self.have_pending_writes = true;
+                if let Some(ref mut stats) = self.stats {
+                    stats.bytes_written += 1;
+                }
+            }
+            other => {
+                return Err(ExecError::InvalidSyscall(other));
+            }
         }
+        Ok(())
+    }
+
+    fn check_stack(&self) -> ExecResult<()> {
         if self.rsp.0 % 8 == 0 {
             Ok(())
         } else {
@@ -398,14 +1162,47 @@ impl<'a> Vm<'a> {
         }
     }
 
+    fn load(&mut self, addr: u64) -> ExecResult<u64> {
+        let value = self.data.read(addr)?;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, Access::Read, value, value);
+        }
+        Ok(value)
+    }
+
+    fn store(&mut self, addr: u64, value: u64) -> ExecResult<()> {
+        let old_value = {
+            let slot = self.data.access(addr)?;
+            ::std::mem::replace(slot, value)
+        };
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, Access::Write, old_value, value);
+        }
+        Ok(())
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn check_watchpoints(&mut self, addr: u64, access: Access, old_value: u64, new_value: u64) {
+        if self.watchpoints.iter().any(|w| w.matches(addr, access, new_value)) {
+            self.watch_hits.push(WatchHit {
+                rip: self.instr_addr,
+                addr,
+                access,
+                old_value,
+                new_value,
+            });
+        }
+    }
+
     fn push(&mut self, value: u64) -> ExecResult<()> {
         self.rsp -= Wrapping(8);
-        *self.data.access(self.rsp.0)? = value;
+        self.store(self.rsp.0, value)?;
         Ok(())
     }
 
     fn pop(&mut self) -> ExecResult<u64> {
-        let value = *self.data.access(self.rsp.0)?;
+        let value = self.load(self.rsp.0)?;
         self.rsp += Wrapping(8);
         Ok(value)
     }
@@ -420,3 +1217,276 @@ impl<'a> Vm<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::{Duration, Instant};
+    use asm::assemble;
+    use executable::{Exe, CODE_START};
+    use super::{RunOutcome, Status, Vm, DEADLINE_CHECK_INTERVAL};
+
+    /// Sums numbers from 10 to 1 in a loop, writes the sum as a byte and
+    /// exits with it.
+    const SUM_PROGRAM: &str = "
+        call sum
+        push rax
+        pop rbx
+        mov rax, 2
+        syscall
+        xor rax, rax
+        syscall
+    sum:
+        xor rdx, rdx
+        mov rax, 10
+    loop:
+        push rax
+        push rdx
+        pop rbx
+        add rax, rbx
+        push rax
+        pop rdx
+        mov rax, 1
+        push rax
+        pop rbx
+        pop rax
+        sub rax, rbx
+        test rax, rax
+        jnz loop
+        push rdx
+        pop rax
+        ret
+    ";
+
+    #[test]
+    fn decode_cache_does_not_change_execution() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        let (mut cached_input, mut cached_output) = (io::empty(), Vec::new());
+        let (mut plain_input, mut plain_output) = (io::empty(), Vec::new());
+        {
+            let mut cached = Vm::new(exe.clone(), &mut cached_input, &mut cached_output, false).unwrap();
+            let mut plain = Vm::new(exe, &mut plain_input, &mut plain_output, false).unwrap();
+            plain.set_decode_cache(false);
+            loop {
+                let status = cached.cycle().unwrap();
+                assert_eq!(plain.cycle().unwrap(), status);
+                assert_eq!(cached.registers(), plain.registers());
+                if status != Status::Running {
+                    assert_eq!(status, Status::Halted(55));
+                    break;
+                }
+            }
+            assert_eq!(cached.instruction_count(), plain.instruction_count());
+        }
+        assert_eq!(cached_output, [55]);
+        assert_eq!(plain_output, [55]);
+    }
+
+    #[test]
+    fn decode_cache_does_not_change_trace() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        let trace = |decode_cache: bool| {
+            let (mut input, mut output, mut trace) = (io::empty(), io::sink(), Vec::new());
+            {
+                let mut vm = Vm::new(exe.clone(), &mut input, &mut output, true).unwrap();
+                vm.set_trace_output(&mut trace);
+                vm.set_decode_cache(decode_cache);
+                match vm.run() {
+                    RunOutcome::Exited(55) => {}
+                    other => panic!("unexpected outcome: {:?}", other),
+                }
+            }
+            String::from_utf8(trace).unwrap()
+        };
+        let cached = trace(true);
+        assert!(cached.starts_with("rip = 0x10000000, instruction: "), "{}", cached);
+        assert_eq!(cached, trace(false));
+    }
+
+    /// Runs `exe` to the end with `run`, either with block execution or
+    /// without it, returning the outcome, final registers, instruction
+    /// count and output.
+    fn run_to_end(
+        exe: &Exe,
+        blocks: bool,
+        max_instructions: Option<u64>,
+    ) -> (String, super::Registers, u64, Vec<u8>) {
+        let mut input = io::empty();
+        let mut output = Vec::new();
+        let (outcome, registers, count) = {
+            let mut vm = Vm::new(exe.clone(), &mut input, &mut output, false).unwrap();
+            vm.set_block_execution(blocks);
+            vm.set_max_instructions(max_instructions);
+            let outcome = match vm.run() {
+                RunOutcome::Exited(code) => format!("exited {}", code),
+                RunOutcome::Faulted(fault) => format!("faulted {}", fault),
+                RunOutcome::OutOfFuel(backtrace) => format!("out of fuel at {:#x}", backtrace.registers.rip),
+                other => panic!("unexpected outcome: {:?}", other),
+            };
+            (outcome, vm.registers(), vm.instruction_count())
+        };
+        (outcome, registers, count, output)
+    }
+
+    #[test]
+    fn block_execution_matches_single_steps() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        let (outcome, registers, count, output) = run_to_end(&exe, true, None);
+        assert_eq!(outcome, "exited 55");
+        assert_eq!(output, [55]);
+        assert_eq!((outcome, registers, count, output), run_to_end(&exe, false, None));
+    }
+
+    #[test]
+    fn block_execution_stops_at_instruction_limit() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        for limit in 0..80 {
+            let with_blocks = run_to_end(&exe, true, Some(limit));
+            assert_eq!(with_blocks, run_to_end(&exe, false, Some(limit)), "limit {}", limit);
+        }
+    }
+
+    #[test]
+    fn block_execution_reports_faults_at_faulting_instruction() {
+        // misaligned stack in the middle of a block, after a call
+        let exe = assemble("
+            call f
+            xor rax, rax
+            syscall
+        f:
+            push rbp
+            mov rbp, rsp
+            sub rsp, 4
+            pop rbp
+            ret
+        ").unwrap();
+        let (outcome, registers, count, _) = run_to_end(&exe, true, None);
+        // rip points at `sub rsp, 4`
+        assert_eq!(registers.rip, 0x1000000e);
+        assert!(outcome.starts_with("faulted misaligned stack"), "{}", outcome);
+        let (plain_outcome, plain_registers, plain_count, _) = run_to_end(&exe, false, None);
+        assert_eq!((outcome, registers, count), (plain_outcome, plain_registers, plain_count));
+    }
+
+    #[test]
+    fn mov_rbx_loads_from_stack() {
+        let exe = assemble("
+            mov rax, 7
+            push rax
+            mov rax, 9
+            push rax
+            mov rax, 8
+            mov rbx, [rsp + rax]
+            xor rax, rax
+            syscall
+        ").unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        match vm.run() {
+            RunOutcome::Exited(code) => assert_eq!(code, 7),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert_eq!(vm.registers().rbp, 0);
+    }
+
+    #[test]
+    fn run_stops_at_breakpoint_on_first_instruction_once() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.add_breakpoint(CODE_START);
+        match vm.run() {
+            RunOutcome::Paused(addr) => assert_eq!(addr, CODE_START),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert_eq!(vm.instruction_count(), 0);
+        match vm.run() {
+            RunOutcome::Exited(code) => assert_eq!(code, 55),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn run_continues_after_running_out_of_fuel() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.set_max_instructions(Some(3));
+        match vm.run() {
+            // `call sum`, `xor rdx, rdx` and `mov rax, 10` were executed
+            RunOutcome::OutOfFuel(backtrace) => {
+                assert_eq!(backtrace.registers.rip, CODE_START + 37);
+                assert_eq!(backtrace.registers.rax, 10);
+            }
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert_eq!(vm.instruction_count(), 3);
+        // the limit counts all instructions, not only those of this run
+        match vm.run() {
+            RunOutcome::OutOfFuel(_) => assert_eq!(vm.instruction_count(), 3),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        vm.set_max_instructions(Some(100));
+        match vm.run() {
+            RunOutcome::OutOfFuel(_) => assert_eq!(vm.instruction_count(), 100),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        vm.set_max_instructions(None);
+        match vm.run() {
+            RunOutcome::Exited(code) => assert_eq!(code, 55),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn exit_is_not_reported_as_out_of_fuel() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        let (_, _, count, _) = run_to_end(&exe, true, None);
+        for &blocks in &[true, false] {
+            let (outcome, _, _, _) = run_to_end(&exe, blocks, Some(count));
+            assert_eq!(outcome, "exited 55");
+            let (outcome, _, _, _) = run_to_end(&exe, blocks, Some(count - 1));
+            assert!(outcome.starts_with("out of fuel"), "{}", outcome);
+        }
+    }
+
+    #[test]
+    fn endless_loop_times_out() {
+        let exe = assemble("
+        loop:
+            jmp loop
+        ").unwrap();
+        for &blocks in &[true, false] {
+            let (mut input, mut output) = (io::empty(), io::sink());
+            let mut vm = Vm::new(exe.clone(), &mut input, &mut output, false).unwrap();
+            vm.set_block_execution(blocks);
+            vm.set_deadline(Some(Instant::now() + Duration::from_millis(20)));
+            match vm.run() {
+                RunOutcome::TimedOut(backtrace) => assert_eq!(backtrace.registers.rip, CODE_START),
+                other => panic!("unexpected outcome: {:?}", other),
+            }
+            // the clock is only checked every `DEADLINE_CHECK_INTERVAL`
+            // instructions
+            assert!(vm.instruction_count() > 0);
+            assert_eq!(vm.instruction_count() % DEADLINE_CHECK_INTERVAL, 0);
+        }
+    }
+
+    #[test]
+    fn passed_deadline_stops_before_first_instruction() {
+        let exe = assemble(SUM_PROGRAM).unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.set_deadline(Some(Instant::now()));
+        match vm.run() {
+            RunOutcome::TimedOut(backtrace) => assert_eq!(backtrace.registers.rip, CODE_START),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert_eq!(vm.instruction_count(), 0);
+        vm.set_deadline(None);
+        match vm.run() {
+            RunOutcome::Exited(code) => assert_eq!(code, 55),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+    }
+}
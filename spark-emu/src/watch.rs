@@ -0,0 +1,288 @@
+//! Watchpoints on stack and data section addresses.
+//!
+//! Watchpoints are written as `<addr>[:r|:w|:rw][=<value>]`, for example
+//! `0x20000010:w` or `0x20000010:rw=0`. Without a kind only writes are
+//! watched, and with a value only accesses that read or write that value
+//! are reported.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Kind of memory access.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (WatchKind::ReadWrite, _) | (WatchKind::Read, Access::Read) | (WatchKind::Write, Access::Write)
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// Address of the watched quad word, must be 8 byte aligned.
+    pub addr: u64,
+    pub kind: WatchKind,
+    /// If set, only accesses of this value trigger the watchpoint.
+    pub value: Option<u64>,
+}
+
+impl Watchpoint {
+    /// Checks if access of `value` at `addr` should be reported. For writes
+    /// `value` is the newly written value.
+    pub fn matches(&self, addr: u64, access: Access, value: u64) -> bool {
+        self.addr == addr && self.kind.matches(access) && self.value.is_none_or(|v| v == value)
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "r",
+            WatchKind::Write => "w",
+            WatchKind::ReadWrite => "rw",
+        };
+        write!(f, "{:#x}:{}", self.addr, kind)?;
+        if let Some(value) = self.value {
+            write!(f, "={:#x}", value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseWatchpointError {
+    BadAddress(String),
+    MisalignedAddress(u64),
+    BadKind(String),
+    BadValue(String),
+}
+
+impl fmt::Display for ParseWatchpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseWatchpointError::BadAddress(ref addr) => write!(f, "invalid address `{}`", addr),
+            ParseWatchpointError::MisalignedAddress(addr) => {
+                write!(f, "address {:#x} is not aligned to 8 bytes", addr)
+            }
+            ParseWatchpointError::BadKind(ref kind) => {
+                write!(f, "invalid watchpoint kind `{}`, expected `r`, `w` or `rw`", kind)
+            }
+            ParseWatchpointError::BadValue(ref value) => write!(f, "invalid value `{}`", value),
+        }
+    }
+}
+
+impl FromStr for Watchpoint {
+    type Err = ParseWatchpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, value) = match s.find('=') {
+            Some(pos) => {
+                let value = &s[pos + 1..];
+                let parsed = parse_number(value).ok_or_else(|| ParseWatchpointError::BadValue(value.to_string()))?;
+                (&s[..pos], Some(parsed))
+            }
+            None => (s, None),
+        };
+        let (addr, kind) = match s.find(':') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => (s, "w"),
+        };
+        let addr = parse_number(addr).ok_or_else(|| ParseWatchpointError::BadAddress(addr.to_string()))?;
+        if addr % 8 != 0 {
+            return Err(ParseWatchpointError::MisalignedAddress(addr));
+        }
+        let kind = match kind {
+            "r" => WatchKind::Read,
+            "w" => WatchKind::Write,
+            "rw" => WatchKind::ReadWrite,
+            other => return Err(ParseWatchpointError::BadKind(other.to_string())),
+        };
+        Ok(Watchpoint { addr, kind, value })
+    }
+}
+
+/// Access that triggered a watchpoint.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Address of the instruction that made the access.
+    pub rip: u64,
+    pub addr: u64,
+    pub access: Access,
+    /// For reads both values are the value that was read.
+    pub old_value: u64,
+    pub new_value: u64,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Read => write!(f, "read of {:#x} at rip = {:#x}: value = {:#x}", self.addr, self.rip, self.new_value),
+            Access::Write => write!(
+                f,
+                "write to {:#x} at rip = {:#x}: {:#x} -> {:#x}",
+                self.addr,
+                self.rip,
+                self.old_value,
+                self.new_value,
+            ),
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u64::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use asm::assemble;
+    use executable::{CODE_START, DATA_START};
+    use vm::{RunOutcome, Vm};
+    use super::{Access, ParseWatchpointError, WatchHit, WatchKind, Watchpoint};
+
+    #[test]
+    fn parses_kinds_and_values() {
+        let parse = |s: &str| s.parse::<Watchpoint>();
+        assert_eq!(parse("0x20000010"), Ok(Watchpoint { addr: 0x2000_0010, kind: WatchKind::Write, value: None }));
+        assert_eq!(parse("536870928:r"), Ok(Watchpoint { addr: 0x2000_0010, kind: WatchKind::Read, value: None }));
+        assert_eq!(
+            parse("0x20000010:rw=0x1f"),
+            Ok(Watchpoint { addr: 0x2000_0010, kind: WatchKind::ReadWrite, value: Some(0x1f) }),
+        );
+        assert_eq!(parse("0x20000010=7"), Ok(Watchpoint { addr: 0x2000_0010, kind: WatchKind::Write, value: Some(7) }));
+    }
+
+    #[test]
+    fn display_is_parsed_back() {
+        for text in &["0x20000010:w", "0x1ffffff8:r=0x0", "0x20000000:rw=0xff"] {
+            let watchpoint = text.parse::<Watchpoint>().unwrap();
+            assert_eq!(watchpoint.to_string(), *text);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |s: &str| s.parse::<Watchpoint>();
+        assert_eq!(parse("data"), Err(ParseWatchpointError::BadAddress("data".to_string())));
+        assert_eq!(parse("0x20000004"), Err(ParseWatchpointError::MisalignedAddress(0x2000_0004)));
+        assert_eq!(parse("0x20000000:x"), Err(ParseWatchpointError::BadKind("x".to_string())));
+        assert_eq!(parse("0x20000000:w=zero"), Err(ParseWatchpointError::BadValue("zero".to_string())));
+    }
+
+    #[test]
+    fn matches_address_kind_and_value() {
+        let watchpoint = Watchpoint { addr: 0x2000_0000, kind: WatchKind::Read, value: Some(5) };
+        assert!(watchpoint.matches(0x2000_0000, Access::Read, 5));
+        assert!(!watchpoint.matches(0x2000_0000, Access::Read, 6));
+        assert!(!watchpoint.matches(0x2000_0000, Access::Write, 5));
+        assert!(!watchpoint.matches(0x2000_0008, Access::Read, 5));
+        let watchpoint = Watchpoint { addr: 0x2000_0000, kind: WatchKind::ReadWrite, value: None };
+        assert!(watchpoint.matches(0x2000_0000, Access::Read, 1));
+        assert!(watchpoint.matches(0x2000_0000, Access::Write, 2));
+    }
+
+    #[test]
+    fn run_stops_after_watched_access() {
+        let exe = assemble("
+            mov rax, counter
+            push qword [rax]      ; read 0
+            pop rbx
+            mov rax, 1
+            push rax
+            pop rbx
+            mov rax, counter
+            mov [rax], rbx        ; write 0 -> 1
+            mov [rax], rbx        ; write 1 -> 1
+            xor rax, rax
+            syscall
+        .data
+        counter:
+            .quad 0
+        ").unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.add_watchpoint("0x20000000:rw=1".parse().unwrap());
+        let first_write = CODE_START + 35;
+        match vm.run() {
+            RunOutcome::Watchpoint(hits) => assert_eq!(hits, [WatchHit {
+                rip: first_write,
+                addr: DATA_START,
+                access: Access::Write,
+                old_value: 0,
+                new_value: 1,
+            }]),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        // execution stops after the instruction that made the access
+        assert_eq!(vm.rip(), first_write + 7);
+        match vm.run() {
+            RunOutcome::Watchpoint(hits) => assert_eq!((hits[0].rip, hits[0].old_value), (first_write + 7, 1)),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        vm.remove_watchpoint(DATA_START);
+        match vm.run() {
+            RunOutcome::Exited(1) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn breakpoint_after_watched_access_is_reported() {
+        let exe = assemble("
+            mov rax, slot
+            mov [rax], rbx
+        after:
+            xor rax, rax
+            syscall
+        .data
+        slot:
+            .quad 0
+        ").unwrap();
+        let (mut input, mut output) = (io::empty(), io::sink());
+        let mut vm = Vm::new(exe, &mut input, &mut output, false).unwrap();
+        vm.add_watchpoint("0x20000000:w".parse().unwrap());
+        let after = CODE_START + 17;
+        vm.add_breakpoint(after);
+        match vm.run() {
+            RunOutcome::Watchpoint(hits) => assert_eq!(hits[0].rip, CODE_START + 10),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        match vm.run() {
+            RunOutcome::Paused(addr) => assert_eq!(addr, after),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        match vm.run() {
+            RunOutcome::Exited(0) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+    }
+}